@@ -0,0 +1,58 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use rox::ast::parser::Parser;
+use rox::chunk::Chunk;
+use rox::compiler::Compiler;
+use rox::scanner::Scanner;
+use rox::vm::VM;
+
+/// tight loop loading string and number constants on every iteration
+const CONSTANT_LOOP: &str = "
+let i = 0;
+let s = \"\";
+while (i < 10000) {
+  s = \"a constant string, long enough to make copying it noticeable\";
+  i = i + 1;
+}
+";
+
+/// function reading and assigning globals on every iteration
+const GLOBAL_LOOP: &str = "
+let total = 0;
+let step = 2;
+fun run() {
+  for (i in 0..10000) total = total + step;
+}
+run();
+";
+
+const FIBONACCI: &str = "
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+fib(20);
+";
+
+fn compile(source: &str) -> Chunk {
+    let tokens = Scanner::new(source.to_string()).scan_tokens().ok().unwrap();
+    let program = Parser::new(tokens).parse().ok().unwrap();
+    let mut chunk = Chunk::new();
+    Compiler::new(&mut chunk).run(program).unwrap();
+    chunk
+}
+
+fn run(source: &str) {
+    let mut vm = VM::new();
+    if vm.interpret(compile(source)).is_err() {
+        panic!("benchmark script failed");
+    }
+}
+
+fn benchmarks(c: &mut Criterion) {
+    c.bench_function("constant loop", |b| b.iter(|| run(CONSTANT_LOOP)));
+    c.bench_function("global loop", |b| b.iter(|| run(GLOBAL_LOOP)));
+    c.bench_function("fibonacci", |b| b.iter(|| run(FIBONACCI)));
+}
+
+criterion_group!(benches, benchmarks);
+criterion_main!(benches);
@@ -1,24 +1,31 @@
 use crate::token::{Token, TokenType, TokenType::*};
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Program {
     pub declarations: Vec<DeclarationWithLineNo>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct DeclarationWithLineNo {
     pub decl: Declaration,
     pub lineno: u16,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub enum Declaration {
     FunDecl(FunDecl),
     LetDecl(LetDecl),
+    // `let a = 1, b = 2;`, declaring the variables in order
+    LetMulti(Vec<LetDecl>),
+    StructDecl(StructDecl),
     Statement(Statement),
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct FunDecl {
     pub name: Token,
     pub params: Vec<Token>,
@@ -26,22 +33,53 @@ pub struct FunDecl {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct StructDecl {
+    pub name: Token,
+    // the struct it inherits fields and methods from
+    pub parent: Option<Token>,
+    pub fields: Vec<Token>,
+    pub methods: Vec<FunDecl>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct LetDecl {
     pub identifier: Token,
     pub initializer: Option<Expr>,
+    // declared with `const`, it can't be assigned to
+    pub is_const: bool,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub enum Statement {
     ExprStmt(Expr),
     IfStmt(IfStmt),
-    PrintStmt(Expr),
+    /// values are printed on a single line, separated by a space
+    PrintStmt(Vec<Expr>),
     ReturnStmt(ReturnStmt),
+    /// the token is kept for error reporting
+    ContinueStmt(Token),
     WhileStmt(WhileStmt),
-    Block(Vec<DeclarationWithLineNo>),
+    /// same as a while loop, but the body runs before the first check
+    DoWhileStmt(WhileStmt),
+    Block(Block),
+    TryStmt(TryStmt),
+    ThrowStmt(ThrowStmt),
+    ImportStmt(ImportStmt),
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct Block {
+    pub declarations: Vec<DeclarationWithLineNo>,
+    /// line of the closing brace, where the block's locals go out of scope
+    pub end_lineno: u16,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub enum Expr {
     Literal(Literal),
     Unary(Unary),
@@ -53,18 +91,54 @@ pub enum Expr {
     Logical(Logical),
     Get(Get),
     Set(Set),
+    List(List),
+    Map(Map),
+    Index(Index),
+    IndexSet(IndexSet),
+    // the instance a method is called on
+    Slf(Token),
+    // anonymous function, named "lambda"
+    Lambda(FunDecl),
+}
+
+impl Expr {
+    /// line of the token the expression originates from. Literals don't keep
+    /// their token, but they can't fail either.
+    pub fn line(&self) -> Option<u16> {
+        match self {
+            Expr::Literal(_) => None,
+            Expr::Unary(unary) => Some(unary.operator.line),
+            Expr::Binary(binary) => Some(binary.operator.line),
+            Expr::Call(call) => Some(call.paren.line),
+            Expr::Grouping(group) => group.expression.line(),
+            Expr::Variable(variable) => Some(variable.name.line),
+            Expr::Assignment(assignment) => Some(assignment.name.line),
+            Expr::Logical(logical) => Some(logical.operator.line),
+            Expr::Get(get) => Some(get.name.line),
+            Expr::Set(set) => Some(set.name.line),
+            Expr::List(list) => Some(list.bracket.line),
+            Expr::Map(map) => Some(map.brace.line),
+            Expr::Index(index) => Some(index.bracket.line),
+            Expr::IndexSet(index_set) => Some(index_set.bracket.line),
+            Expr::Slf(keyword) => Some(keyword.line),
+            Expr::Lambda(lambda) => Some(lambda.name.line),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub enum Literal {
     Number(f64),
     Str(String),
+    Char(char),
     True,
     False,
     Null,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Call {
     pub callee: Box<Expr>,
     pub paren: Token,
@@ -72,22 +146,26 @@ pub struct Call {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Grouping {
     pub expression: Box<Expr>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Variable {
     pub name: Token,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Unary {
     pub operator: Token,
     pub right: Box<Expr>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Binary {
     pub left: Box<Expr>,
     pub operator: Token,
@@ -95,6 +173,7 @@ pub struct Binary {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Logical {
     pub left: Box<Expr>,
     pub operator: Token,
@@ -102,12 +181,14 @@ pub struct Logical {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Get {
     pub object: Box<Expr>,
     pub name: Token,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Set {
     pub object: Box<Expr>,
     pub name: Token,
@@ -115,18 +196,52 @@ pub struct Set {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct List {
+    pub bracket: Token,
+    pub elements: Vec<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct Map {
+    pub brace: Token,
+    pub entries: Vec<(Expr, Expr)>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct Index {
+    pub object: Box<Expr>,
+    pub bracket: Token,
+    pub index: Box<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct IndexSet {
+    pub object: Box<Expr>,
+    pub bracket: Token,
+    pub index: Box<Expr>,
+    pub value: Box<Expr>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Assignment {
     pub name: Token,
     pub value: Box<Expr>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Super {
     pub keyword: Token,
     pub method: Token,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct IfStmt {
     pub condition: Expr,
     pub then_branch: Box<Statement>,
@@ -134,12 +249,44 @@ pub struct IfStmt {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct WhileStmt {
     pub condition: Expr,
     pub body: Box<Statement>,
+    /// run after the body, `continue` included. Only set by desugared `for` loops.
+    pub increment: Option<Box<DeclarationWithLineNo>>,
+}
+
+/// `try { ... } catch (e) { ... }`: a runtime error raised by the body, even from a
+/// function it calls, runs the handler instead of stopping the script
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct TryStmt {
+    pub body: Block,
+    /// variable the error is bound to in the handler
+    pub name: Token,
+    pub handler: Block,
+}
+
+/// `throw value;` raises an error that a `catch` clause gets `value` from
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct ThrowStmt {
+    pub token: Token,
+    pub expr: Expr,
+}
+
+/// `import "file";` runs the top-level declarations of another file, as if they were
+/// written in place of the import. The path is relative to the importing file.
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
+pub struct ImportStmt {
+    pub token: Token,
+    pub path: String,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct ReturnStmt {
     pub token: Token,
     pub expr: Option<Expr>,
@@ -160,6 +307,12 @@ pub mod printer {
             Expr::Call(call) => pretty_print_call(call),
             Expr::Get(get) => pretty_print_get(get),
             Expr::Set(set) => pretty_print_set(set),
+            Expr::List(list) => pretty_print_list(list),
+            Expr::Map(map) => pretty_print_map(map),
+            Expr::Index(index) => pretty_print_index(index),
+            Expr::IndexSet(index_set) => pretty_print_index_set(index_set),
+            Expr::Slf(keyword) => keyword.lexeme.clone(),
+            Expr::Lambda(lambda) => pretty_print_lambda(lambda),
         }
     }
 
@@ -167,12 +320,18 @@ pub mod printer {
         match literal {
             Literal::Number(n) => n.to_string(),
             Literal::Str(s) => s.clone(),
+            Literal::Char(c) => c.to_string(),
             Literal::True => "true".to_string(),
             Literal::False => "false".to_string(),
-            Literal::Null => "nil".to_string(),
+            Literal::Null => "null".to_string(),
         }
     }
 
+    fn pretty_print_lambda(lambda: &FunDecl) -> String {
+        let params: Vec<String> = lambda.params.iter().map(|p| p.lexeme.clone()).collect();
+        format!("(fun ({}))", params.join(" "))
+    }
+
     fn pretty_print_grouping(group: &Grouping) -> String {
         format!("(group {})", pretty_print(&group.expression))
     }
@@ -224,6 +383,37 @@ pub mod printer {
             pretty_print(&set.value)
         )
     }
+
+    fn pretty_print_list(list: &List) -> String {
+        let elements: Vec<String> = list.elements.iter().map(pretty_print).collect();
+        format!("(list {})", elements.join(" "))
+    }
+
+    fn pretty_print_map(map: &Map) -> String {
+        let entries: Vec<String> = map
+            .entries
+            .iter()
+            .map(|(key, value)| format!("({} {})", pretty_print(key), pretty_print(value)))
+            .collect();
+        format!("(map {})", entries.join(" "))
+    }
+
+    fn pretty_print_index(index: &Index) -> String {
+        format!(
+            "(index {} {})",
+            pretty_print(&index.object),
+            pretty_print(&index.index)
+        )
+    }
+
+    fn pretty_print_index_set(index_set: &IndexSet) -> String {
+        format!(
+            "(index-set {} {} {})",
+            pretty_print(&index_set.object),
+            pretty_print(&index_set.index),
+            pretty_print(&index_set.value)
+        )
+    }
 }
 
 #[test]
@@ -254,33 +444,41 @@ fn test_pretty_printer() {
 
 pub mod parser {
     use super::*;
+    use std::fmt;
 
     // FIXME update this
     /*
     program        → declaration* EOF ;
-    declaration    → classDecl | funDecl | varDecl | statement ;
-    classDecl      → "class" IDENTIFIER ( "<" IDENTIFIER )?
-                     "{" function* "}" ;
+    declaration    → structDecl | funDecl | varDecl | statement ;
+    structDecl     → "struct" IDENTIFIER ( "<" IDENTIFIER )?
+                     "{" parameters? ( "fun" function )* "}" ;
     funDecl        → "fun" function ;
     function       → IDENTIFIER "(" parameters? ")" block ;
-    varDecl        → "var" IDENTIFIER ( "=" expression )? ";" ;
+    varDecl        → ( "let" | "const" ) varBinding ( "," varBinding )* ";" ;
+    varBinding     → IDENTIFIER ( "=" expression )? ;
     parameters     → IDENTIFIER ( "," IDENTIFIER )* ;
     statement      → exprStmt
                    | ifStmt
                    | printStmt
                    | returnStmt
                    | whileStmt
+                   | doWhileStmt
+                   | continueStmt
                    | block
     block          → "{" declaration* "}"
     exprStmt       → expression ";" ;
     ifStmt         → "if" "(" expression ")" statement
                    ( "else" statement )? ;
+    forInStmt      → "for" "(" IDENTIFIER "in" expression ".." expression ")" statement ;
     whileStmt      → "while" "(" expression ")" statement;
-    printStmt      → "print" expression ";" ;
+    doWhileStmt    → "do" statement "while" "(" expression ")" ";" ;
+    continueStmt   → "continue" ";" ;
+    printStmt      → "print" expression ( "," expression )* ";" ;
     returnStmt      → "return" expression? ";" ;
 
     expression     → assignment ;
     assignment     → ( call "." )? IDENTIFIER "=" assignment
+                   | call "[" expression "]" "=" assignment
                    | logic_or ;
     logic_or       → logic_and ( "or" logic_and )* ;
     logic_and      → equality ( "and" equality )* ;
@@ -288,18 +486,23 @@ pub mod parser {
     comparison     → term ( ( ">" | ">=" | "<" | "<=" ) term )* ;
     term           → factor ( ( "-" | "+" ) factor )* ;
     factor         → unary ( ( "/" | "*" ) unary )* ;
-    unary          → ( "!" | "-" ) unary | call
-    call           → primary ( "(" arguments? ")" | "." IDENTIFIER )* ;
-    primary        → NUMBER | STRING | "true" | "false" | "nil" | "this"
+    unary          → ( "!" | "not" | "-" ) unary | call
+    call           → primary ( "(" arguments? ")" | "." IDENTIFIER | "[" expression "]" )* ;
+    primary        → NUMBER | STRING | CHAR | "true" | "false" | "null" | "this"
                    | "(" expression ")" | IDENTIFIER
+                   | "[" arguments? "]" | "{" entries? "}"
+                   | "fun" "(" parameters? ")" block
                    | "super" "." IDENTIFIER ;
 
     arguments      → expression ( "," expression )* ;
+    entries        → expression ":" expression ( "," expression ":" expression )* ;
 
     */
     pub struct Parser {
         tokens: Vec<Token>,
         current: usize,
+        // errors which didn't prevent the parser from going on
+        errors: Vec<ParseError>,
     }
 
     #[derive(Debug)]
@@ -308,13 +511,47 @@ pub mod parser {
         pub token: Token,
     }
 
+    impl ParseError {
+        /// whether the source ended too early, e.g. in the middle of a block
+        pub fn at_end(&self) -> bool {
+            self.token.typ == Eof
+        }
+    }
+
+    impl fmt::Display for ParseError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if self.at_end() {
+                write!(f, "[line {}] Error at end: {}", self.token.line, self.message)
+            } else {
+                write!(
+                    f,
+                    "[line {}] Error at '{}': {}",
+                    self.token.line, self.token.lexeme, self.message
+                )
+            }
+        }
+    }
+
     impl Parser {
         pub fn new(tokens: Vec<Token>) -> Self {
-            Self { tokens, current: 0 }
+            Self {
+                tokens,
+                current: 0,
+                errors: Vec::new(),
+            }
         }
 
-        pub fn parse(&mut self) -> Result<Program, ParseError> {
-            self.program()
+        /// Errors are listed in source order. Parsing stops at the first one
+        /// the parser can't recover from.
+        pub fn parse(&mut self) -> Result<Program, Vec<ParseError>> {
+            match self.program() {
+                Ok(program) if self.errors.is_empty() => Ok(program),
+                Ok(_) => Err(std::mem::take(&mut self.errors)),
+                Err(error) => {
+                    self.errors.push(error);
+                    Err(std::mem::take(&mut self.errors))
+                }
+            }
         }
 
         fn peek(&self) -> &Token {
@@ -331,10 +568,7 @@ pub mod parser {
             }
             match &self.peek().typ {
                 // discard content of identifier for check
-                Identifier(_) => match typ {
-                    Identifier(_) => true,
-                    _ => false,
-                },
+                Identifier(_) => matches!(typ, Identifier(_)),
                 t => t == typ,
             }
         }
@@ -384,15 +618,46 @@ pub mod parser {
             let token = self.peek();
             match &token.typ {
                 Fun => self.fun_decl("function").map(Declaration::FunDecl),
-                Let => self.let_decl().map(Declaration::LetDecl),
+                Let | Const => self.let_decl(),
+                Struct => self.struct_decl().map(Declaration::StructDecl),
                 _ => Ok(Declaration::Statement(self.statement()?)),
             }
         }
 
-        fn fun_decl(&mut self, kind: &str) -> Result<FunDecl, ParseError> {
-            if kind == "function" {
-                self.advance(); // discard fun token
+        fn struct_decl(&mut self) -> Result<StructDecl, ParseError> {
+            self.advance(); // discard struct token
+            let name = self.consume(&Identifier("".to_string()), "Expect struct name.")?;
+            let parent = if self.matches(&vec![Less]) {
+                Some(self.consume(&Identifier("".to_string()), "Expect parent struct name.")?)
+            } else {
+                None
+            };
+            self.consume(&LeftBrace, "Expect '{' after struct name.")?;
+            let mut fields = vec![];
+            if self.check(&Identifier("".to_string())) {
+                loop {
+                    let field = self.consume(&Identifier("".to_string()), "Expect field name.")?;
+                    fields.push(field);
+                    if !self.matches(&vec![Comma]) {
+                        break;
+                    }
+                }
+            }
+            let mut methods = vec![];
+            while self.check(&Fun) {
+                methods.push(self.fun_decl("method")?);
             }
+            self.consume(&RightBrace, "Expect '}' after struct body.")?;
+            Ok(StructDecl {
+                name,
+                parent,
+                fields,
+                methods,
+            })
+        }
+
+        fn fun_decl(&mut self, kind: &str) -> Result<FunDecl, ParseError> {
+            self.advance(); // discard fun token
             // FIXME: need to create empty string to consume identifier
             let name = self.consume(
                 &Identifier("".to_string()),
@@ -428,21 +693,38 @@ pub mod parser {
             Ok(params)
         }
 
-        fn let_decl(&mut self) -> Result<LetDecl, ParseError> {
-            self.advance(); // discard var token
-            let lexeme = self.peek().lexeme.clone();
-            // FIXME: need to copy lexeme to check Identifier type -> ugly
-            let identifier = self.consume(&Identifier(lexeme), "Expect variable name.")?;
-            let initializer = if self.matches(&vec![Equal]) {
-                Some(self.expression()?)
-            } else {
-                None
-            };
+        /// a `LetDecl`, or a `LetMulti` when several variables are declared at once
+        fn let_decl(&mut self) -> Result<Declaration, ParseError> {
+            let is_const = self.advance().typ == Const; // discard let or const token
+            let mut decls = vec![];
+            loop {
+                let lexeme = self.peek().lexeme.clone();
+                // FIXME: need to copy lexeme to check Identifier type -> ugly
+                let identifier = self.consume(&Identifier(lexeme), "Expect variable name.")?;
+                let initializer = if self.matches(&vec![Equal]) {
+                    Some(self.expression()?)
+                } else if is_const {
+                    return Err(ParseError {
+                        token: self.peek().clone(),
+                        message: "Expect '=' after constant name.".to_string(),
+                    });
+                } else {
+                    None
+                };
+                decls.push(LetDecl {
+                    identifier,
+                    initializer,
+                    is_const,
+                });
+                if !self.matches(&vec![Comma]) {
+                    break;
+                }
+            }
             self.consume(&Semicolon, "Expect ';' after declaration.")?;
-            Ok(LetDecl {
-                identifier,
-                initializer,
-            })
+            if decls.len() == 1 {
+                return Ok(Declaration::LetDecl(decls.remove(0)));
+            }
+            Ok(Declaration::LetMulti(decls))
         }
 
         fn statement(&mut self) -> Result<Statement, ParseError> {
@@ -450,6 +732,8 @@ pub mod parser {
             match &token.typ {
                 If => Ok(Statement::IfStmt(self.if_stmt()?)),
                 While => Ok(Statement::WhileStmt(self.while_stmt()?)),
+                Do => Ok(Statement::DoWhileStmt(self.do_while_stmt()?)),
+                Try => Ok(Statement::TryStmt(self.try_stmt()?)),
                 // desugaring a for statement into while
                 For => self.for_stmt(),
                 Return => {
@@ -462,15 +746,51 @@ pub mod parser {
                     self.consume(&Semicolon, "Expect ';' after return value.")?;
                     Ok(Statement::ReturnStmt(ReturnStmt { token, expr }))
                 }
+                Throw => {
+                    let token = self.advance(); // take throw token
+                    let expr = self.expression()?;
+                    self.consume(&Semicolon, "Expect ';' after thrown value.")?;
+                    Ok(Statement::ThrowStmt(ThrowStmt { token, expr }))
+                }
+                Import => {
+                    let token = self.advance(); // take import token
+                    let Str(path) = self.peek().typ.clone() else {
+                        return Err(ParseError {
+                            token: self.peek().clone(),
+                            message: "Expect file path after 'import'.".to_string(),
+                        });
+                    };
+                    self.advance(); // take path
+                    self.consume(&Semicolon, "Expect ';' after imported file.")?;
+                    Ok(Statement::ImportStmt(ImportStmt { token, path }))
+                }
+                Continue => {
+                    let token = self.advance(); // take continue token
+                    self.consume(&Semicolon, "Expect ';' after 'continue'.")?;
+                    Ok(Statement::ContinueStmt(token))
+                }
                 LeftBrace => {
                     self.advance(); // discard left brace
-                    Ok(Statement::Block(self.block()?))
+                    let declarations = self.block()?;
+                    Ok(Statement::Block(Block {
+                        declarations,
+                        end_lineno: self.previous().line,
+                    }))
                 }
                 Print => {
                     self.advance(); // discard print token
-                    let expr = self.expression()?;
+                    let mut values = vec![self.expression()?];
+                    while self.matches(&vec![Comma]) {
+                        values.push(self.expression()?);
+                    }
+                    if values.len() > 255 {
+                        return Err(ParseError {
+                            token: self.peek().clone(),
+                            message: "Can't print more than 255 values.".to_string(),
+                        });
+                    }
                     self.consume(&Semicolon, "Expect ';' after value.")?;
-                    Ok(Statement::PrintStmt(expr))
+                    Ok(Statement::PrintStmt(values))
                 }
                 _ => self.expr_statement(),
             }
@@ -512,68 +832,228 @@ pub mod parser {
             Ok(WhileStmt {
                 condition,
                 body: Box::new(body),
+                increment: None,
+            })
+        }
+
+        fn do_while_stmt(&mut self) -> Result<WhileStmt, ParseError> {
+            self.advance(); // discard do token
+            let body = self.statement()?;
+            self.consume(&While, "Expect 'while' after do body.")?;
+            self.consume(&LeftParen, "Expect '(' after while.")?;
+            let condition = self.expression()?;
+            self.consume(&RightParen, "Expect ')' after while condition.")?;
+            self.consume(&Semicolon, "Expect ';' after do while loop.")?;
+            Ok(WhileStmt {
+                condition,
+                body: Box::new(body),
+                increment: None,
+            })
+        }
+
+        fn try_stmt(&mut self) -> Result<TryStmt, ParseError> {
+            self.advance(); // discard try token
+            self.consume(&LeftBrace, "Expect '{' after try.")?;
+            let body = Block {
+                declarations: self.block()?,
+                end_lineno: self.previous().line,
+            };
+            self.consume(&Catch, "Expect 'catch' after try block.")?;
+            self.consume(&LeftParen, "Expect '(' after catch.")?;
+            let lexeme = self.peek().lexeme.clone();
+            let name = self.consume(&Identifier(lexeme), "Expect error variable name.")?;
+            self.consume(&RightParen, "Expect ')' after error variable.")?;
+            self.consume(&LeftBrace, "Expect '{' after catch clause.")?;
+            let handler = Block {
+                declarations: self.block()?,
+                end_lineno: self.previous().line,
+            };
+            Ok(TryStmt {
+                body,
+                name,
+                handler,
             })
         }
 
         fn for_stmt(&mut self) -> Result<Statement, ParseError> {
             self.advance(); // discard for token
             self.consume(&LeftParen, "Expect '(' after for.")?;
+            if matches!(self.peek().typ, Identifier(_)) && self.tokens[self.current + 1].typ == In {
+                return self.for_in_stmt();
+            }
             let token = self.peek().clone();
+            let initializer_lineno = token.line;
             let initializer = match &token.typ {
                 Semicolon => {
                     self.advance(); // discard semi colon
                     None
                 }
-                Let => Some(self.let_decl()?).map(Declaration::LetDecl),
+                Let => Some(self.let_decl()?),
                 _ => Some(Declaration::Statement(self.expr_statement()?)),
             };
+            let condition_lineno = self.peek().line;
             let condition = match self.peek().typ {
                 Semicolon => None,
                 _ => Some(self.expression()?),
             };
             self.consume(&Semicolon, "Expect ';' after loop condition.")?;
+            let increment_lineno = self.peek().line;
             let increment = match self.peek().typ {
                 RightParen => None,
                 _ => Some(self.expression()?),
             };
             self.consume(&RightParen, "Expect ')' after for clauses.")?;
+            let body_lineno = self.peek().line;
+            let body = self.statement()?;
+            let end_lineno = self.previous().line;
+
+            // the body is wrapped in a block, so that it keeps its own line
+            let full_body = vec![DeclarationWithLineNo {
+                decl: Declaration::Statement(body),
+                lineno: body_lineno,
+            }];
+            // kept apart from the body, so that `continue` doesn't skip it
+            let increment = increment.map(|incr| {
+                Box::new(DeclarationWithLineNo {
+                    decl: Declaration::Statement(Statement::ExprStmt(incr)),
+                    lineno: increment_lineno,
+                })
+            });
+
+            let while_stmt = WhileStmt {
+                condition: match condition {
+                    Some(cond) => cond,
+                    None => Expr::Literal(Literal::True),
+                },
+                body: Box::new(Statement::Block(Block {
+                    declarations: full_body,
+                    end_lineno,
+                })),
+                increment,
+            };
+            let mut desugared = vec![];
+            if let Some(var_decl) = initializer {
+                desugared.push(DeclarationWithLineNo {
+                    decl: var_decl,
+                    lineno: initializer_lineno,
+                });
+            }
+            desugared.push(DeclarationWithLineNo {
+                decl: Declaration::Statement(Statement::WhileStmt(while_stmt)),
+                lineno: condition_lineno,
+            });
+            Ok(Statement::Block(Block {
+                declarations: desugared,
+                end_lineno,
+            }))
+        }
+
+        /// `for (x in start..end) body` is desugared into
+        /// ```text
+        /// {
+        ///   let <index> = start;
+        ///   let <end> = end;
+        ///   while (<index> < <end>) {
+        ///     let x = <index>;
+        ///     body
+        ///   } <index> = <index> + 1 // as the loop's increment
+        /// }
+        /// ```
+        /// The loop variable is a copy, so that assigning to it doesn't affect the iteration.
+        fn for_in_stmt(&mut self) -> Result<Statement, ParseError> {
+            let variable = self.advance();
+            self.advance(); // discard in token
+            let start_lineno = self.peek().line;
+            let start = self.expression()?;
+            let range = self.consume(&DotDot, "Expect '..' in range.")?;
+            let end = self.expression()?;
+            self.consume(&RightParen, "Expect ')' after for clauses.")?;
+            let body_lineno = self.peek().line;
             let body = self.statement()?;
+            let end_lineno = self.previous().line;
 
-            let lineno = self.peek().line;
-            let full_body = match increment {
-                None => body,
-                Some(incr) => Statement::Block(vec![
+            // the names of hidden variables can't be written in a script
+            let hidden_variable = |name: &str| Token {
+                typ: Identifier(name.to_string()),
+                lexeme: name.to_string(),
+                line: range.line,
+            };
+            let index = hidden_variable("for index");
+            let index_value = || {
+                Expr::Variable(Variable {
+                    name: index.clone(),
+                })
+            };
+            let range_operator = |typ: TokenType, lexeme: &str| Token {
+                typ,
+                lexeme: lexeme.to_string(),
+                line: range.line,
+            };
+
+            let body = Block {
+                declarations: vec![
                     DeclarationWithLineNo {
-                        decl: Declaration::Statement(body),
-                        lineno,
+                        decl: Declaration::LetDecl(LetDecl {
+                            identifier: variable,
+                            initializer: Some(index_value()),
+                            is_const: false,
+                        }),
+                        lineno: body_lineno,
                     },
                     DeclarationWithLineNo {
-                        decl: Declaration::Statement(Statement::ExprStmt(incr)),
-                        lineno,
+                        decl: Declaration::Statement(body),
+                        lineno: body_lineno,
                     },
-                ]),
+                ],
+                end_lineno,
             };
-
+            let increment = Expr::Assignment(Assignment {
+                name: index.clone(),
+                value: Box::new(Expr::Binary(Binary {
+                    left: Box::new(index_value()),
+                    operator: range_operator(Plus, "+"),
+                    right: Box::new(Expr::Literal(Literal::Number(1.0))),
+                })),
+            });
             let while_stmt = WhileStmt {
-                condition: match condition {
-                    Some(cond) => cond,
-                    None => Expr::Literal(Literal::True),
-                },
-                body: Box::new(full_body),
+                condition: Expr::Binary(Binary {
+                    left: Box::new(index_value()),
+                    operator: range_operator(Less, "<"),
+                    right: Box::new(Expr::Variable(Variable {
+                        name: hidden_variable("for end"),
+                    })),
+                }),
+                body: Box::new(Statement::Block(body)),
+                increment: Some(Box::new(DeclarationWithLineNo {
+                    decl: Declaration::Statement(Statement::ExprStmt(increment)),
+                    lineno: range.line,
+                })),
             };
-            Ok(match initializer {
-                None => Statement::WhileStmt(while_stmt),
-                Some(var_decl) => Statement::Block(vec![
+            Ok(Statement::Block(Block {
+                declarations: vec![
                     DeclarationWithLineNo {
-                        decl: var_decl,
-                        lineno,
+                        decl: Declaration::LetDecl(LetDecl {
+                            identifier: index.clone(),
+                            initializer: Some(start),
+                            is_const: false,
+                        }),
+                        lineno: start_lineno,
+                    },
+                    DeclarationWithLineNo {
+                        decl: Declaration::LetDecl(LetDecl {
+                            identifier: hidden_variable("for end"),
+                            initializer: Some(end),
+                            is_const: false,
+                        }),
+                        lineno: range.line,
                     },
                     DeclarationWithLineNo {
                         decl: Declaration::Statement(Statement::WhileStmt(while_stmt)),
-                        lineno,
+                        lineno: start_lineno,
                     },
-                ]),
-            })
+                ],
+                end_lineno,
+            }))
         }
 
         // FIXME disagreeing with the book here - it seems we want to return Declaration
@@ -610,11 +1090,24 @@ pub mod parser {
                         name,
                         value: Box::new(value),
                     })),
-                    // FIXME: should keep parsing here
-                    _ => Err(ParseError {
-                        token: equals,
-                        message: "Invalid assignment target.".to_string(),
-                    }),
+                    Expr::Index(Index {
+                        object,
+                        bracket,
+                        index,
+                    }) => Ok(Expr::IndexSet(IndexSet {
+                        object,
+                        bracket,
+                        index,
+                        value: Box::new(value),
+                    })),
+                    // the parser isn't confused, no need to stop here
+                    _ => {
+                        self.errors.push(ParseError {
+                            token: equals,
+                            message: "Invalid assignment target.".to_string(),
+                        });
+                        Ok(expr)
+                    }
                 };
             }
             Ok(expr)
@@ -685,11 +1178,11 @@ pub mod parser {
         }
 
         fn factor(&mut self) -> Result<Expr, ParseError> {
-            self.parse_left_associative_binary_op(&Parser::unary, &vec![Slash, Star])
+            self.parse_left_associative_binary_op(&Parser::unary, &vec![Slash, TildeSlash, Star])
         }
 
         fn unary(&mut self) -> Result<Expr, ParseError> {
-            if self.matches(&vec![Minus, Not]) {
+            if self.matches(&vec![Minus, Bang, Not]) {
                 let operator = self.previous();
                 let right = self.unary()?;
                 return Ok(Expr::Unary(Unary {
@@ -705,7 +1198,7 @@ pub mod parser {
             loop {
                 if self.peek().typ == LeftParen {
                     let paren = self.advance();
-                    let arguments = self.arguments()?;
+                    let arguments = self.arguments(&RightParen, "arguments")?;
                     result = Expr::Call(Call {
                         callee: Box::new(result),
                         paren,
@@ -722,6 +1215,15 @@ pub mod parser {
                         object: Box::new(result),
                         name,
                     });
+                } else if self.peek().typ == LeftBracket {
+                    let bracket = self.advance();
+                    let index = self.expression()?;
+                    self.consume(&RightBracket, "Expect ']' after index.")?;
+                    result = Expr::Index(Index {
+                        object: Box::new(result),
+                        bracket,
+                        index: Box::new(index),
+                    });
                 } else {
                     break;
                 }
@@ -730,9 +1232,17 @@ pub mod parser {
         }
 
         fn primary(&mut self) -> Result<Expr, ParseError> {
+            // advancing past the end would yield the previous token again
+            if self.is_at_end() {
+                return Err(ParseError {
+                    message: "Expect expression at end of file.".to_string(),
+                    token: self.peek().clone(),
+                });
+            }
             let token = self.advance();
             match token.typ {
                 Str(s) => Ok(Expr::Literal(Literal::Str(s))),
+                Char(c) => Ok(Expr::Literal(Literal::Char(c))),
                 Number(x) => Ok(Expr::Literal(Literal::Number(x))),
                 True => Ok(Expr::Literal(Literal::True)),
                 False => Ok(Expr::Literal(Literal::False)),
@@ -751,6 +1261,35 @@ pub mod parser {
                     }))
                 }
                 Identifier(_) => Ok(Expr::Variable(Variable { name: token })),
+                Slf => Ok(Expr::Slf(token)),
+                Fun => {
+                    let name = Token {
+                        typ: Identifier("lambda".to_string()),
+                        lexeme: "lambda".to_string(),
+                        line: token.line,
+                    };
+                    self.consume(&LeftParen, "Expect '(' after 'fun'.")?;
+                    let params = self.parameters()?;
+                    self.consume(&LeftBrace, "Expect '{' before lambda body.")?;
+                    let body = self.block()?;
+                    Ok(Expr::Lambda(FunDecl { name, params, body }))
+                }
+                LeftBracket => {
+                    let elements = self.arguments(&RightBracket, "elements")?;
+                    self.consume(&RightBracket, "Expect ']' after list elements.")?;
+                    Ok(Expr::List(List {
+                        bracket: token,
+                        elements,
+                    }))
+                }
+                LeftBrace => {
+                    let entries = self.entries()?;
+                    self.consume(&RightBrace, "Expect '}' after map entries.")?;
+                    Ok(Expr::Map(Map {
+                        brace: token,
+                        entries,
+                    }))
+                }
                 _ => Err(ParseError {
                     message: "Expect expression".to_string(),
                     token,
@@ -758,9 +1297,10 @@ pub mod parser {
             }
         }
 
-        fn arguments(&mut self) -> Result<Vec<Expr>, ParseError> {
+        /// expressions separated by commas until `closing`, at most 255 `noun`
+        fn arguments(&mut self, closing: &TokenType, noun: &str) -> Result<Vec<Expr>, ParseError> {
             let mut arguments = vec![];
-            if self.peek().typ != RightParen {
+            if self.peek().typ != *closing {
                 loop {
                     let expr = self.expression()?;
                     arguments.push(expr);
@@ -769,14 +1309,135 @@ pub mod parser {
                     }
                 }
             }
-            if arguments.len() >= 255 {
+            if arguments.len() > 255 {
                 // FIXME: we don't want the parser to enter panic mode here
                 return Err(ParseError {
                     token: self.peek().clone(),
-                    message: "Can't have more than 255 arguments.".to_string(),
+                    message: format!("Can't have more than 255 {}.", noun),
                 });
             }
             Ok(arguments)
         }
+
+        fn entries(&mut self) -> Result<Vec<(Expr, Expr)>, ParseError> {
+            let mut entries = vec![];
+            if self.peek().typ != RightBrace {
+                loop {
+                    let key = self.expression()?;
+                    self.consume(&Colon, "Expect ':' after map key.")?;
+                    let value = self.expression()?;
+                    entries.push((key, value));
+                    if !self.matches(&vec![Comma]) {
+                        break;
+                    }
+                }
+            }
+            if entries.len() > 255 {
+                return Err(ParseError {
+                    token: self.peek().clone(),
+                    message: "Can't have more than 255 map entries.".to_string(),
+                });
+            }
+            Ok(entries)
+        }
     }
 }
+
+#[test]
+fn test_parse_error_display() {
+    let error = parser::ParseError {
+        message: "Expect expression.".to_string(),
+        token: Token {
+            typ: TokenType::RightBrace,
+            lexeme: "}".to_string(),
+            line: 3,
+        },
+    };
+    assert_eq!(error.to_string(), "[line 3] Error at '}': Expect expression.");
+    let error = parser::ParseError {
+        message: "Expect ';' after value.".to_string(),
+        token: Token {
+            typ: TokenType::Eof,
+            lexeme: "".to_string(),
+            line: 1,
+        },
+    };
+    assert_eq!(error.to_string(), "[line 1] Error at end: Expect ';' after value.");
+}
+
+#[cfg(feature = "astJson")]
+#[test]
+fn test_ast_json() {
+    use crate::scanner::Scanner;
+    let tokens = Scanner::new("let x = 1.5;\nprint -x;".to_string())
+        .scan_tokens()
+        .unwrap();
+    let program = parser::Parser::new(tokens).parse().unwrap();
+    let json = serde_json::to_string(&program).unwrap();
+    assert!(json.contains("\"LetDecl\""));
+    assert!(json.contains("\"PrintStmt\""));
+    assert!(json.contains("\"Unary\""));
+    assert!(json.contains("\"Number\":1.5"));
+    assert!(json.contains("\"line\":2"));
+}
+
+#[test]
+fn test_parse_continues_after_invalid_assignment_target() {
+    use crate::scanner::Scanner;
+    let tokens = Scanner::new("1 + 2 = 3;\nprint 4\n".to_string())
+        .scan_tokens()
+        .unwrap();
+    let errors = parser::Parser::new(tokens).parse().unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "[line 1] Error at '=': Invalid assignment target.",
+            "[line 3] Error at end: Expect ';' after value.",
+        ]
+    );
+}
+
+#[test]
+fn test_parse_error_at_end_of_file() {
+    use crate::scanner::Scanner;
+    let tokens = Scanner::new("1 +".to_string()).scan_tokens().unwrap();
+    let errors = parser::Parser::new(tokens).parse().unwrap_err();
+    assert_eq!(
+        errors[0].to_string(),
+        "[line 1] Error at end: Expect expression at end of file."
+    );
+}
+
+#[test]
+fn test_import_expects_a_file_path() {
+    use crate::scanner::Scanner;
+    let tokens = Scanner::new("import \"lib.rox\";\nimport lib;".to_string())
+        .scan_tokens()
+        .unwrap();
+    let errors = parser::Parser::new(tokens).parse().unwrap_err();
+    assert_eq!(
+        errors[0].to_string(),
+        "[line 2] Error at 'lib': Expect file path after 'import'."
+    );
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn test_at_most_255_arguments_and_elements() {
+    use crate::scanner::Scanner;
+    let parse = |source: String| {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        parser::Parser::new(tokens).parse()
+    };
+    let values = |n: usize| vec!["1"; n].join(", ");
+    assert!(parse(format!("f({});\n[{}];", values(255), values(255))).is_ok());
+    let errors = parse(format!("f({});", values(256))).unwrap_err();
+    assert_eq!(errors[0].message, "Can't have more than 255 arguments.");
+    let errors = parse(format!("[{}];", values(300))).unwrap_err();
+    assert_eq!(errors[0].message, "Can't have more than 255 elements.");
+    let entries = |n: usize| (0..n).map(|i| format!("{}: 1", i)).collect::<Vec<_>>().join(", ");
+    assert!(parse(format!("print {{{}}};", entries(255))).is_ok());
+    let errors = parse(format!("print {{{}}};", entries(256))).unwrap_err();
+    assert_eq!(errors[0].message, "Can't have more than 255 map entries.");
+}
@@ -1,15 +1,22 @@
 use crate::value::Value;
+use std::cell::Cell;
 use std::convert::TryFrom;
+use std::fmt::Write;
+use std::rc::Rc;
 
+// OpEof must stay the last variant, see OPCODE_COUNT
 #[derive(Debug)]
 pub enum OpCode {
     OpConstant,
+    OpImmediate,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpFloorDivide,
     OpNegate,
     OpPrint,
+    OpPrintList,
     OpReturn,
     OpTrue,
     OpFalse,
@@ -17,24 +24,49 @@ pub enum OpCode {
     OpAnd,
     OpOr,
     OpEqualEqual,
-    OpBangEqual,
     OpLess,
-    OpLessEqual,
     OpGreater,
-    OpGreaterEqual,
     OpDefineGlobal,
+    // a global declared with `const`, which OpSetGlobal then refuses to assign
+    OpDefineConstant,
     OpGetGlobal,
     OpPop,
     OpPopN,
     OpGetLocal,
+    OpNil,
+    OpBuildList,
+    OpBuildMap,
+    OpIndexGet,
+    OpIndexSet,
+    OpGetField,
+    OpSetField,
+    OpInherit,
+    OpInvoke,
+    OpCall,
+    OpTailCall,
+    OpSetGlobal,
+    OpSetLocal,
+    OpConstantLong,
+    OpGetLocalLong,
+    OpSetLocalLong,
+    OpJump,
+    // pops the condition, whether it jumps or not
+    OpJumpIfFalsePop,
+    OpLoop,
+    // registers the handler its operand jumps to, for errors raised until OpEndTry
+    OpTry,
+    OpEndTry,
+    OpThrow,
     OpEof,
 }
 
+pub const OPCODE_COUNT: usize = OpCode::OpEof as usize + 1;
+
 impl OpCode {
     pub fn new(byte: u8) -> Self {
         // [perf] - try_into might incurr an avoidable perf penalty
         byte.try_into()
-            .expect(&format!("Could not decode byte {}", byte))
+            .unwrap_or_else(|_| panic!("Could not decode byte {}", byte))
     }
 }
 
@@ -45,12 +77,15 @@ impl TryFrom<u8> for OpCode {
     fn try_from(v: u8) -> Result<Self, Self::Error> {
         match v {
             x if x == OpCode::OpConstant as u8 => Ok(OpCode::OpConstant),
+            x if x == OpCode::OpImmediate as u8 => Ok(OpCode::OpImmediate),
             x if x == OpCode::OpAdd as u8 => Ok(OpCode::OpAdd),
             x if x == OpCode::OpSubtract as u8 => Ok(OpCode::OpSubtract),
             x if x == OpCode::OpMultiply as u8 => Ok(OpCode::OpMultiply),
             x if x == OpCode::OpDivide as u8 => Ok(OpCode::OpDivide),
+            x if x == OpCode::OpFloorDivide as u8 => Ok(OpCode::OpFloorDivide),
             x if x == OpCode::OpNegate as u8 => Ok(OpCode::OpNegate),
             x if x == OpCode::OpPrint as u8 => Ok(OpCode::OpPrint),
+            x if x == OpCode::OpPrintList as u8 => Ok(OpCode::OpPrintList),
             x if x == OpCode::OpReturn as u8 => Ok(OpCode::OpReturn),
             x if x == OpCode::OpTrue as u8 => Ok(OpCode::OpTrue),
             x if x == OpCode::OpFalse as u8 => Ok(OpCode::OpFalse),
@@ -58,16 +93,36 @@ impl TryFrom<u8> for OpCode {
             x if x == OpCode::OpAnd as u8 => Ok(OpCode::OpAnd),
             x if x == OpCode::OpOr as u8 => Ok(OpCode::OpOr),
             x if x == OpCode::OpEqualEqual as u8 => Ok(OpCode::OpEqualEqual),
-            x if x == OpCode::OpBangEqual as u8 => Ok(OpCode::OpBangEqual),
             x if x == OpCode::OpLess as u8 => Ok(OpCode::OpLess),
-            x if x == OpCode::OpLessEqual as u8 => Ok(OpCode::OpLessEqual),
             x if x == OpCode::OpGreater as u8 => Ok(OpCode::OpGreater),
-            x if x == OpCode::OpGreaterEqual as u8 => Ok(OpCode::OpGreaterEqual),
             x if x == OpCode::OpDefineGlobal as u8 => Ok(OpCode::OpDefineGlobal),
+            x if x == OpCode::OpDefineConstant as u8 => Ok(OpCode::OpDefineConstant),
             x if x == OpCode::OpGetGlobal as u8 => Ok(OpCode::OpGetGlobal),
             x if x == OpCode::OpPop as u8 => Ok(OpCode::OpPop),
             x if x == OpCode::OpPopN as u8 => Ok(OpCode::OpPopN),
             x if x == OpCode::OpGetLocal as u8 => Ok(OpCode::OpGetLocal),
+            x if x == OpCode::OpNil as u8 => Ok(OpCode::OpNil),
+            x if x == OpCode::OpBuildList as u8 => Ok(OpCode::OpBuildList),
+            x if x == OpCode::OpBuildMap as u8 => Ok(OpCode::OpBuildMap),
+            x if x == OpCode::OpIndexGet as u8 => Ok(OpCode::OpIndexGet),
+            x if x == OpCode::OpIndexSet as u8 => Ok(OpCode::OpIndexSet),
+            x if x == OpCode::OpGetField as u8 => Ok(OpCode::OpGetField),
+            x if x == OpCode::OpSetField as u8 => Ok(OpCode::OpSetField),
+            x if x == OpCode::OpInherit as u8 => Ok(OpCode::OpInherit),
+            x if x == OpCode::OpInvoke as u8 => Ok(OpCode::OpInvoke),
+            x if x == OpCode::OpCall as u8 => Ok(OpCode::OpCall),
+            x if x == OpCode::OpTailCall as u8 => Ok(OpCode::OpTailCall),
+            x if x == OpCode::OpSetGlobal as u8 => Ok(OpCode::OpSetGlobal),
+            x if x == OpCode::OpSetLocal as u8 => Ok(OpCode::OpSetLocal),
+            x if x == OpCode::OpConstantLong as u8 => Ok(OpCode::OpConstantLong),
+            x if x == OpCode::OpGetLocalLong as u8 => Ok(OpCode::OpGetLocalLong),
+            x if x == OpCode::OpSetLocalLong as u8 => Ok(OpCode::OpSetLocalLong),
+            x if x == OpCode::OpJump as u8 => Ok(OpCode::OpJump),
+            x if x == OpCode::OpJumpIfFalsePop as u8 => Ok(OpCode::OpJumpIfFalsePop),
+            x if x == OpCode::OpLoop as u8 => Ok(OpCode::OpLoop),
+            x if x == OpCode::OpTry as u8 => Ok(OpCode::OpTry),
+            x if x == OpCode::OpEndTry as u8 => Ok(OpCode::OpEndTry),
+            x if x == OpCode::OpThrow as u8 => Ok(OpCode::OpThrow),
             x if x == OpCode::OpEof as u8 => Ok(OpCode::OpEof),
             _ => Err(()),
         }
@@ -78,6 +133,17 @@ pub struct Chunk {
     code: Vec<u8>,
     constants: Vec<Value>,
     line_info: LineInfo,
+    // slot of the global named by each constant, once the VM has looked it up
+    global_slots: Vec<Cell<Option<usize>>>,
+}
+
+// the cache of global slots doesn't change what the chunk does
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code
+            && self.constants == other.constants
+            && self.line_info == other.line_info
+    }
 }
 
 impl Chunk {
@@ -86,6 +152,7 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             line_info: LineInfo::new(),
+            global_slots: Vec::new(),
         }
     }
 
@@ -94,38 +161,196 @@ impl Chunk {
     }
 
     pub fn write(&mut self, op_code: u8, lineno: usize) {
+        self.write_in_file(op_code, lineno, None);
+    }
+
+    /// write a byte compiled from line `lineno` of an imported `file`, or of the file
+    /// being compiled when `None`
+    pub fn write_in_file(&mut self, op_code: u8, lineno: usize, file: Option<Rc<str>>) {
         self.code.push(op_code);
-        self.line_info.add(self.count() - 1, lineno);
+        self.line_info.add(self.count() - 1, lineno, file);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    /// Strings, chars and numbers already in the chunk are reused, so that a name used
+    /// again doesn't take another constant.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let existing = match &value {
+            Value::Str(_) | Value::Char(_) => self.constants.iter().position(|c| *c == value),
+            // comparing the bits keeps -0 apart from 0
+            Value::Number(n) => self.constants.iter().position(|c| match c {
+                Value::Number(c) => c.to_bits() == n.to_bits(),
+                _ => false,
+            }),
+            _ => None,
+        };
+        if let Some(index) = existing {
+            return index;
+        }
         self.constants.push(value);
-        (self.constants.len() - 1)
-            .try_into()
-            .expect("Constant index didn't fit in byte")
+        self.global_slots.push(Cell::new(None));
+        self.constants.len() - 1
     }
 
     pub fn read_byte(&self, offset: usize) -> u8 {
         self.code[offset]
     }
 
-    pub fn read_constant(&self, address: u8) -> Value {
-        // [perf] what's the perf impact of this clone ?
-        self.constants[address as usize].clone()
+    /// read a big endian 16 bits operand
+    pub fn read_short(&self, offset: usize) -> u16 {
+        ((self.code[offset] as u16) << 8) | self.code[offset + 1] as u16
+    }
+
+    /// read a big endian 24 bits operand
+    pub fn read_long(&self, offset: usize) -> u32 {
+        ((self.code[offset] as u32) << 16)
+            | ((self.code[offset + 1] as u32) << 8)
+            | self.code[offset + 2] as u32
+    }
+
+    pub fn patch(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    pub fn read_constant(&self, address: usize) -> Value {
+        // cheap: constants are either copied scalars or behind an `Rc`
+        self.constants[address].clone()
+    }
+
+    /// Check that the chunk can be run without the VM reading out of it: each byte decodes
+    /// to an instruction with all its operands, constants and jump targets exist, and the
+    /// code ends with a return. The chunks of the functions among the constants, and of the
+    /// methods of structs, are checked as well. Stack slots are not, they depend on what is run.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut instructions = vec![];
+        let mut jumps = vec![];
+        let mut offset: usize = 0;
+        while offset < self.count() {
+            instructions.push(offset);
+            let byte = self.code[offset];
+            let instruction = OpCode::try_from(byte)
+                .map_err(|_| format!("Invalid opcode {:#04x} at offset {}", byte, offset))?;
+            let (size, constant) = match instruction {
+                OpCode::OpConstant
+                | OpCode::OpDefineGlobal
+                | OpCode::OpDefineConstant
+                | OpCode::OpGetGlobal
+                | OpCode::OpSetGlobal
+                | OpCode::OpGetField
+                | OpCode::OpSetField => (2, true),
+                OpCode::OpInvoke => (3, true),
+                OpCode::OpImmediate
+                | OpCode::OpPrintList
+                | OpCode::OpPopN
+                | OpCode::OpGetLocal
+                | OpCode::OpSetLocal
+                | OpCode::OpBuildList
+                | OpCode::OpBuildMap
+                | OpCode::OpCall
+                | OpCode::OpTailCall => (2, false),
+                OpCode::OpJump | OpCode::OpJumpIfFalsePop | OpCode::OpLoop | OpCode::OpTry => {
+                    (3, false)
+                }
+                OpCode::OpConstantLong => (4, true),
+                OpCode::OpGetLocalLong | OpCode::OpSetLocalLong => (4, false),
+                _ => (1, false),
+            };
+            if offset + size > self.count() {
+                return Err(format!("Truncated {:?} at offset {}", instruction, offset));
+            }
+            if constant {
+                let address = match instruction {
+                    OpCode::OpConstantLong => self.read_long(offset + 1) as usize,
+                    _ => self.code[offset + 1] as usize,
+                };
+                if address >= self.constants.len() {
+                    return Err(format!("Undefined constant {} at offset {}", address, offset));
+                }
+            }
+            match instruction {
+                OpCode::OpJump | OpCode::OpJumpIfFalsePop | OpCode::OpTry => {
+                    jumps.push((offset, offset + 3 + self.read_short(offset + 1) as usize))
+                }
+                OpCode::OpLoop => {
+                    let target = (offset + 3).checked_sub(self.read_short(offset + 1) as usize);
+                    let target = target.ok_or_else(|| {
+                        format!("Jump at offset {} goes before the chunk", offset)
+                    })?;
+                    jumps.push((offset, target))
+                }
+                _ => {}
+            }
+            offset += size;
+        }
+        for (offset, target) in jumps {
+            if instructions.binary_search(&target).is_err() {
+                return Err(format!(
+                    "Jump at offset {} doesn't land on an instruction",
+                    offset
+                ));
+            }
+        }
+        let last_instruction = instructions.last().map(|offset| self.code[*offset]);
+        if last_instruction != Some(OpCode::OpReturn as u8)
+            && last_instruction != Some(OpCode::OpEof as u8)
+        {
+            return Err("Chunk doesn't end with a return".to_string());
+        }
+        for constant in self.constants.iter() {
+            match constant {
+                Value::Function(function) => function
+                    .chunk
+                    .validate()
+                    .map_err(|err| format!("{} in {}()", err, function.name))?,
+                Value::Struct(struct_type) => {
+                    for method in struct_type.methods.values() {
+                        method.chunk.validate().map_err(|err| {
+                            format!("{} in {}.{}()", err, struct_type.name, method.name)
+                        })?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// slot of the global named by the constant at `address`, if cached by `cache_global_slot`
+    pub fn cached_global_slot(&self, address: u8) -> Option<usize> {
+        self.global_slots[address as usize].get()
+    }
+
+    pub fn cache_global_slot(&self, address: u8, slot: usize) {
+        self.global_slots[address as usize].set(Some(slot));
     }
 
     pub fn get_lineno(&self, offset: usize) -> usize {
         self.line_info
             .get_lineno(offset)
-            .expect(&format!("Couldn't retrieve lineno for offset {}", offset))
+            .unwrap_or_else(|| panic!("Couldn't retrieve lineno for offset {}", offset))
+    }
+
+    /// imported file the code at `offset` was compiled from, if not the one being compiled
+    pub fn get_file(&self, offset: usize) -> Option<&str> {
+        self.line_info
+            .get(offset)
+            .and_then(|(_, _, file)| file.as_deref())
     }
 }
 
-/// Line info is encoded with tuples like representing `(offset, lineno).`
-/// where offset is the first offset comprised in lineno.
-/// Assumption: offsets are added in ascending order.
+/// Line info is encoded with tuples like representing `(offset, lineno, file).`
+/// where offset is the first offset comprised in lineno, and file the imported file
+/// the line belongs to, if any.
+/// Assumption: offsets are added in ascending order. Line numbers are not, since
+/// desugared code (e.g. `for` loops) doesn't follow the source order.
+#[derive(PartialEq)]
 struct LineInfo {
-    info: Vec<(usize, usize)>,
+    info: Vec<(usize, usize, Option<Rc<str>>)>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LineInfo {
@@ -133,103 +358,375 @@ impl LineInfo {
         LineInfo { info: Vec::new() }
     }
 
-    fn add(&mut self, offset: usize, lineno: usize) {
+    fn add(&mut self, offset: usize, lineno: usize, file: Option<Rc<str>>) {
         match self.info.last() {
             None => {
-                self.info.push((offset, lineno));
+                self.info.push((offset, lineno, file));
             }
-            Some((_, current_lineno)) => {
-                if lineno > *current_lineno {
-                    self.info.push((offset, lineno))
+            Some((_, current_lineno, current_file)) => {
+                if lineno != *current_lineno || file != *current_file {
+                    self.info.push((offset, lineno, file))
                 }
             }
         }
     }
 
     fn get_lineno(&self, offset: usize) -> Option<usize> {
+        self.get(offset).map(|(_, lineno, _)| *lineno)
+    }
+
+    /// the entry `offset` belongs to
+    fn get(&self, offset: usize) -> Option<&(usize, usize, Option<Rc<str>>)> {
         for index in 0..self.info.len() {
-            let (current_offset, current_lineno) = self.info[index];
+            let current_offset = self.info[index].0;
             if offset == current_offset {
-                return Some(current_lineno);
+                return Some(&self.info[index]);
             }
             if offset < current_offset {
                 if index > 0 {
-                    return Some(self.info[index - 1].1);
+                    return Some(&self.info[index - 1]);
                 } else {
                     return None;
                 }
             }
         }
-        match self.info.last() {
-            None => None,
-            Some((_, last_lineno)) => Some(*last_lineno),
-        }
+        self.info.last()
     }
 }
 
 /// debug implementation
 impl Chunk {
     pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+        print!("{}", self.disassembly(name));
+    }
+
+    /// every instruction of the chunk, the targets of jumps preceded by a label like `L0:`
+    pub fn disassembly(&self, name: &str) -> String {
+        let targets = self.jump_targets();
+        let mut out = format!("== {} ==\n", name);
+        let mut offset: usize = 0;
+        while offset < self.count() {
+            if let Ok(label) = targets.binary_search(&offset) {
+                writeln!(out, "L{}:", label).unwrap();
+            }
+            offset = self.write_instruction(&mut out, offset);
+        }
+        out
+    }
+
+    /// offsets jumped to by the instructions of the chunk, in order
+    fn jump_targets(&self) -> Vec<usize> {
+        let mut targets = vec![];
+        // the disassembler is what knows the size of each instruction
+        let mut scratch = String::new();
         let mut offset: usize = 0;
         while offset < self.count() {
-            offset = self.disassemble_instruction(offset);
+            match OpCode::try_from(self.read_byte(offset)) {
+                Ok(OpCode::OpJump | OpCode::OpJumpIfFalsePop | OpCode::OpTry) => {
+                    targets.push(self.jump_target(offset, 1))
+                }
+                Ok(OpCode::OpLoop) => targets.push(self.jump_target(offset, -1)),
+                _ => {}
+            }
+            offset = self.write_instruction(&mut scratch, offset);
+            scratch.clear();
         }
+        targets.sort();
+        targets.dedup();
+        targets
+    }
+
+    /// offset the jump instruction at `offset` goes to, backwards if `sign` is negative
+    fn jump_target(&self, offset: usize, sign: i32) -> usize {
+        let jump = self.read_short(offset + 1) as i32;
+        (offset as i32 + 3 + sign * jump) as usize
     }
+
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+        let mut out = String::new();
+        let next_offset = self.write_instruction(&mut out, offset);
+        print!("{}", out);
+        next_offset
+    }
+
+    /// append the instruction at `offset` to `out`, returning the offset of the next one
+    pub fn write_instruction(&self, out: &mut String, offset: usize) -> usize {
+        write!(out, "{:04} ", offset).unwrap();
         let current_lineno = self.line_info.get_lineno(offset).unwrap();
         if offset > 0 && current_lineno == self.line_info.get_lineno(offset - 1).unwrap() {
-            print!("   | ");
+            write!(out, "   | ").unwrap();
         } else {
-            print!("{:4} ", current_lineno);
+            write!(out, "{:4} ", current_lineno).unwrap();
         }
 
-        let instruction: &OpCode = &OpCode::new(self.read_byte(offset));
+        let byte = self.read_byte(offset);
+        // a corrupt chunk can still be traced up to the point where it fails
+        let Ok(instruction) = OpCode::try_from(byte) else {
+            writeln!(out, "Invalid opcode {:#04x}", byte).unwrap();
+            return offset + 1;
+        };
         match instruction {
-            OpCode::OpReturn => self.simple_instruction("OP_RETURN", offset),
-            OpCode::OpAdd => self.simple_instruction("OP_ADD", offset),
-            OpCode::OpSubtract => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::OpMultiply => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::OpDivide => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::OpNegate => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::OpPrint => self.simple_instruction("OP_PRINT", offset),
-            OpCode::OpConstant => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::OpTrue => self.simple_instruction("OP_TRUE", offset),
-            OpCode::OpFalse => self.simple_instruction("OP_FALSE", offset),
-            OpCode::OpNot => self.simple_instruction("OP_NOT", offset),
-            OpCode::OpAnd => self.simple_instruction("OP_AND", offset),
-            OpCode::OpOr => self.simple_instruction("OP_OR", offset),
-            OpCode::OpEqualEqual => self.simple_instruction("OP_EQUAL_EQUAL", offset),
-            OpCode::OpBangEqual => self.simple_instruction("OP_BANG_EQUAL", offset),
-            OpCode::OpLess => self.simple_instruction("OP_LESS", offset),
-            OpCode::OpLessEqual => self.simple_instruction("OP_LESS_EQUAL", offset),
-            OpCode::OpGreater => self.simple_instruction("OP_GREATER", offset),
-            OpCode::OpGreaterEqual => self.simple_instruction("OP_GREATER_EQUAL", offset),
-            OpCode::OpDefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            OpCode::OpGetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
-            OpCode::OpPop => self.simple_instruction("OP_POP", offset),
-            OpCode::OpPopN => self.instruction_with_operand("OP_POPN", offset),
-            OpCode::OpGetLocal => self.instruction_with_operand("OP_GET_LOCAL", offset),
-            OpCode::OpEof => self.simple_instruction("OP_EOF", offset),
+            OpCode::OpReturn => self.simple_instruction(out, "OP_RETURN", offset),
+            OpCode::OpAdd => self.simple_instruction(out, "OP_ADD", offset),
+            OpCode::OpSubtract => self.simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::OpMultiply => self.simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::OpDivide => self.simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::OpFloorDivide => self.simple_instruction(out, "OP_FLOOR_DIVIDE", offset),
+            OpCode::OpNegate => self.simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::OpPrint => self.simple_instruction(out, "OP_PRINT", offset),
+            OpCode::OpPrintList => self.instruction_with_operand(out, "OP_PRINT_LIST", offset),
+            OpCode::OpConstant => self.constant_instruction(out, "OP_CONSTANT", offset),
+            OpCode::OpConstantLong => {
+                self.long_constant_instruction(out, "OP_CONSTANT_LONG", offset)
+            }
+            OpCode::OpImmediate => self.instruction_with_operand(out, "OP_IMMEDIATE", offset),
+            OpCode::OpTrue => self.simple_instruction(out, "OP_TRUE", offset),
+            OpCode::OpFalse => self.simple_instruction(out, "OP_FALSE", offset),
+            OpCode::OpNot => self.simple_instruction(out, "OP_NOT", offset),
+            OpCode::OpAnd => self.simple_instruction(out, "OP_AND", offset),
+            OpCode::OpOr => self.simple_instruction(out, "OP_OR", offset),
+            OpCode::OpEqualEqual => self.simple_instruction(out, "OP_EQUAL_EQUAL", offset),
+            OpCode::OpLess => self.simple_instruction(out, "OP_LESS", offset),
+            OpCode::OpGreater => self.simple_instruction(out, "OP_GREATER", offset),
+            OpCode::OpDefineGlobal => self.constant_instruction(out, "OP_DEFINE_GLOBAL", offset),
+            OpCode::OpDefineConstant => {
+                self.constant_instruction(out, "OP_DEFINE_CONSTANT", offset)
+            }
+            OpCode::OpGetGlobal => self.constant_instruction(out, "OP_GET_GLOBAL", offset),
+            OpCode::OpPop => self.simple_instruction(out, "OP_POP", offset),
+            OpCode::OpPopN => self.instruction_with_operand(out, "OP_POPN", offset),
+            OpCode::OpGetLocal => self.instruction_with_operand(out, "OP_GET_LOCAL", offset),
+            OpCode::OpNil => self.simple_instruction(out, "OP_NIL", offset),
+            OpCode::OpBuildList => self.instruction_with_operand(out, "OP_BUILD_LIST", offset),
+            OpCode::OpBuildMap => self.instruction_with_operand(out, "OP_BUILD_MAP", offset),
+            OpCode::OpIndexGet => self.simple_instruction(out, "OP_INDEX_GET", offset),
+            OpCode::OpIndexSet => self.simple_instruction(out, "OP_INDEX_SET", offset),
+            OpCode::OpGetField => self.constant_instruction(out, "OP_GET_FIELD", offset),
+            OpCode::OpSetField => self.constant_instruction(out, "OP_SET_FIELD", offset),
+            OpCode::OpInherit => self.simple_instruction(out, "OP_INHERIT", offset),
+            OpCode::OpInvoke => self.invoke_instruction(out, "OP_INVOKE", offset),
+            OpCode::OpCall => self.instruction_with_operand(out, "OP_CALL", offset),
+            OpCode::OpTailCall => self.instruction_with_operand(out, "OP_TAIL_CALL", offset),
+            OpCode::OpSetGlobal => self.constant_instruction(out, "OP_SET_GLOBAL", offset),
+            OpCode::OpSetLocal => self.instruction_with_operand(out, "OP_SET_LOCAL", offset),
+            OpCode::OpGetLocalLong => {
+                self.instruction_with_long_operand(out, "OP_GET_LOCAL_LONG", offset)
+            }
+            OpCode::OpSetLocalLong => {
+                self.instruction_with_long_operand(out, "OP_SET_LOCAL_LONG", offset)
+            }
+            OpCode::OpJump => self.jump_instruction(out, "OP_JUMP", 1, offset),
+            OpCode::OpJumpIfFalsePop => {
+                self.jump_instruction(out, "OP_JUMP_IF_FALSE_POP", 1, offset)
+            }
+            OpCode::OpLoop => self.jump_instruction(out, "OP_LOOP", -1, offset),
+            OpCode::OpTry => self.jump_instruction(out, "OP_TRY", 1, offset),
+            OpCode::OpEndTry => self.simple_instruction(out, "OP_END_TRY", offset),
+            OpCode::OpThrow => self.simple_instruction(out, "OP_THROW", offset),
+            OpCode::OpEof => self.simple_instruction(out, "OP_EOF", offset),
         }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
+    fn simple_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        writeln!(out, "{}", name).unwrap();
         offset + 1
     }
-    fn instruction_with_operand(&self, name: &str, offset: usize) -> usize {
+    fn instruction_with_operand(&self, out: &mut String, name: &str, offset: usize) -> usize {
         let operand = self.code[offset + 1];
-        println!("{:<16} {}", name, operand);
+        writeln!(out, "{:<16} {}", name, operand).unwrap();
         offset + 2
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn instruction_with_long_operand(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let operand = self.read_long(offset + 1);
+        writeln!(out, "{:<16} {}", name, operand).unwrap();
+        offset + 4
+    }
+
+    fn jump_instruction(&self, out: &mut String, name: &str, sign: i32, offset: usize) -> usize {
+        let target = self.jump_target(offset, sign);
+        writeln!(out, "{:<16} {:4} -> {}", name, offset, target).unwrap();
+        offset + 3
+    }
+
+    fn invoke_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let constant_addr = self.code[offset + 1];
+        let nb_arguments = self.code[offset + 2];
+        let constant = &self.constants[constant_addr as usize];
+        writeln!(
+            out,
+            "{:<16} ({} args) {} '{}'",
+            name, nb_arguments, constant_addr, constant
+        )
+        .unwrap();
+        offset + 3
+    }
+
+    fn constant_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
         let constant_addr = self.code[offset + 1];
-        print!("{:<16} {} '", name, constant_addr);
-        print!("{}", self.constants[constant_addr as usize]);
-        println!("'");
+        let constant = &self.constants[constant_addr as usize];
+        writeln!(out, "{:<16} {} '{}'", name, constant_addr, constant).unwrap();
         offset + 2
     }
+
+    fn long_constant_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let constant_addr = self.read_long(offset + 1);
+        let constant = &self.constants[constant_addr as usize];
+        writeln!(out, "{:<16} {} '{}'", name, constant_addr, constant).unwrap();
+        offset + 4
+    }
+}
+
+#[cfg(test)]
+mod chunk_tests {
+    use super::*;
+    use crate::ast::parser::Parser;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+    use crate::value::{Function, Struct};
+    use std::rc::Rc;
+
+    fn compile(source: &str) -> Chunk {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let mut chunk = Chunk::new();
+        Compiler::new(&mut chunk).run(program).unwrap();
+        chunk
+    }
+
+    #[test]
+    fn compiled_chunks_are_valid() {
+        let chunk = compile(
+            "fun f(n) {\n  while (n > 0) { n = n - 1; }\n  return n;\n}\nif (f(3) == 0) print \"ok\"; else print [1, 2];",
+        );
+        assert_eq!(chunk.validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_opcode_is_rejected() {
+        let mut chunk = compile("print 1;");
+        chunk.patch(0, 0xff);
+        assert_eq!(
+            chunk.validate(),
+            Err("Invalid opcode 0xff at offset 0".to_string())
+        );
+        let code = chunk.disassembly("code");
+        assert!(code.contains("0000    1 Invalid opcode 0xff\n"), "{}", code);
+    }
+
+    #[test]
+    fn truncated_instruction_is_rejected() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OpEof as u8, 1);
+        chunk.write(OpCode::OpJump as u8, 1);
+        chunk.write(0, 1);
+        assert_eq!(
+            chunk.validate(),
+            Err("Truncated OpJump at offset 1".to_string())
+        );
+    }
+
+    #[test]
+    fn undefined_constant_is_rejected() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OpConstant as u8, 1);
+        chunk.write(3, 1);
+        chunk.write(OpCode::OpEof as u8, 1);
+        assert_eq!(
+            chunk.validate(),
+            Err("Undefined constant 3 at offset 0".to_string())
+        );
+    }
+
+    #[test]
+    fn jump_into_an_instruction_is_rejected() {
+        let mut chunk = compile("if (true) print 1;");
+        let jump_offset = chunk
+            .code
+            .iter()
+            .position(|byte| *byte == OpCode::OpJumpIfFalsePop as u8)
+            .unwrap();
+        // lands on the operand of the constant instruction right after the jump
+        chunk.patch(jump_offset + 1, 0);
+        chunk.patch(jump_offset + 2, 1);
+        assert_eq!(
+            chunk.validate(),
+            Err(format!(
+                "Jump at offset {} doesn't land on an instruction",
+                jump_offset
+            ))
+        );
+    }
+
+    #[test]
+    fn loop_before_the_chunk_is_rejected() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OpLoop as u8, 1);
+        chunk.write(0, 1);
+        chunk.write(10, 1);
+        chunk.write(OpCode::OpEof as u8, 1);
+        assert_eq!(
+            chunk.validate(),
+            Err("Jump at offset 0 goes before the chunk".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_return_is_rejected() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OpNil as u8, 1);
+        assert_eq!(
+            chunk.validate(),
+            Err("Chunk doesn't end with a return".to_string())
+        );
+    }
+
+    #[test]
+    fn function_chunks_are_validated() {
+        let mut chunk = compile("fun f() { return 1; }");
+        let Value::Function(function) = &chunk.constants[0] else {
+            panic!("Expected the function as first constant");
+        };
+        let mut body = Chunk::new();
+        body.write(OpCode::OpReturn as u8, 1);
+        body.write(0xff, 1);
+        chunk.constants[0] = Value::Function(Rc::new(Function {
+            name: function.name.clone(),
+            arity: 0,
+            chunk: Rc::new(body),
+        }));
+        assert_eq!(
+            chunk.validate(),
+            Err("Invalid opcode 0xff at offset 1 in f()".to_string())
+        );
+    }
+
+    #[test]
+    fn method_chunks_are_validated() {
+        let mut chunk = compile("struct Point {\n  x\n  fun getX() { return self.x; }\n}");
+        let Value::Struct(struct_type) = &chunk.constants[0] else {
+            panic!("Expected the struct as first constant");
+        };
+        let mut body = Chunk::new();
+        body.write(OpCode::OpReturn as u8, 1);
+        body.write(0xff, 1);
+        let mut methods = struct_type.methods.clone();
+        methods.insert(
+            "getX".to_string(),
+            Rc::new(Function {
+                name: "getX".to_string(),
+                arity: 0,
+                chunk: Rc::new(body),
+            }),
+        );
+        chunk.constants[0] = Value::Struct(Rc::new(Struct {
+            name: struct_type.name.clone(),
+            fields: struct_type.fields.clone(),
+            methods,
+        }));
+        assert_eq!(
+            chunk.validate(),
+            Err("Invalid opcode 0xff at offset 1 in Point.getX()".to_string())
+        );
+    }
 }
@@ -1,21 +1,76 @@
+use crate::ast::parser::Parser;
 use crate::ast::{
-    Binary, Declaration, DeclarationWithLineNo, Expr, LetDecl, Literal, Logical, Program,
-    Statement, Unary, Variable,
+    Assignment, Binary, Block, Call, Declaration, DeclarationWithLineNo, Expr, FunDecl, Get,
+    IfStmt, ImportStmt, Index, IndexSet, LetDecl, List, Literal, Logical, Map, Program, ReturnStmt,
+    Set, Statement, StructDecl, ThrowStmt, TryStmt, Unary, Variable, WhileStmt,
 };
 use crate::chunk::{Chunk, OpCode};
+use crate::scanner::Scanner;
 use crate::token::{Token, TokenType};
-use crate::value::Value;
+use crate::value::{Function, Struct, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// local indices are encoded on at most 3 bytes
+#[cfg(not(test))]
+const LOCALS_MAX: usize = 1 << 24;
+// low enough for the tests to reach it
+#[cfg(test)]
+const LOCALS_MAX: usize = 1 << 10;
+
+// OpConstantLong encodes constant indices on 3 bytes
+const CONSTANTS_MAX: usize = 1 << 24;
 
 pub struct Compiler<'a> {
     current_line: u16,
     current_chunk: &'a mut Chunk,
     locals: Vec<Local>,
     scope_depth: u8,
+    function_type: FunctionType,
+    // loops being compiled, the innermost one last
+    loops: Vec<Loop>,
+    // number of `try` blocks being compiled in the current function
+    try_depth: usize,
+    // print the value of expression statements instead of discarding it
+    echo: bool,
+    // globals declared with `const` so far. Functions only see the ones declared before them.
+    constant_globals: HashSet<String>,
+    // locals of the enclosing functions, which a function body can't reach without closures
+    enclosing_locals: HashSet<String>,
+    // file being compiled, if it isn't typed in the REPL
+    path: Option<PathBuf>,
+    // files being imported, the innermost one last. Imports are relative to its directory.
+    importing: Vec<PathBuf>,
+    // files imported so far, which are only run once
+    imported: HashSet<PathBuf>,
+    // imported file the code comes from, as shown in errors, if not the one being compiled
+    file: Option<Rc<str>>,
+}
+
+struct Loop {
+    // locals deeper than this belong to the loop body
+    scope_depth: u8,
+    // `try` blocks deeper than this are in the loop body
+    try_depth: usize,
+    // `continue` statements, jumping to the end of the body
+    continue_jumps: Vec<usize>,
+}
+
+#[derive(PartialEq)]
+enum FunctionType {
+    Function,
+    // a function declared in a struct, its receiver is bound to `self`
+    Method,
+    Script,
 }
 
 struct Local {
     name: Token,
     depth: u8,
+    // declared with `const`
+    constant: bool,
 }
 
 impl<'a> Compiler<'a> {
@@ -25,14 +80,93 @@ impl<'a> Compiler<'a> {
             current_chunk: chunk,
             locals: Vec::new(),
             scope_depth: 0,
+            function_type: FunctionType::Script,
+            loops: Vec::new(),
+            try_depth: 0,
+            echo: false,
+            constant_globals: HashSet::new(),
+            enclosing_locals: HashSet::new(),
+            path: None,
+            importing: Vec::new(),
+            imported: HashSet::new(),
+            file: None,
+        }
+    }
+
+    /// compiler for the script in the file at `path`, which its imports are relative to
+    pub fn new_file(chunk: &'a mut Chunk, path: &Path) -> Self {
+        // the same file can be reached through different paths
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        Compiler {
+            path: Some(path),
+            ..Compiler::new(chunk)
+        }
+    }
+
+    /// compiler for a line typed in the REPL, echoing the value of a lone expression
+    pub fn new_repl(chunk: &'a mut Chunk) -> Self {
+        Compiler {
+            echo: true,
+            ..Compiler::new(chunk)
+        }
+    }
+
+    /// compiler for a function body. Its locals live in their own call frame.
+    fn new_function(chunk: &'a mut Chunk, current_line: u16, function_type: FunctionType) -> Self {
+        // the first slot of a call frame holds the function being called,
+        // or the receiver for a method
+        let slot_name = match function_type {
+            FunctionType::Method => "self",
+            _ => "",
+        };
+        let callee_slot = Local {
+            name: Token {
+                typ: TokenType::Identifier(slot_name.to_string()),
+                lexeme: slot_name.to_string(),
+                line: current_line,
+            },
+            depth: 0,
+            constant: false,
+        };
+        Compiler {
+            current_line,
+            current_chunk: chunk,
+            locals: vec![callee_slot],
+            // parameters and top level declarations of the body are locals
+            scope_depth: 1,
+            function_type,
+            loops: Vec::new(),
+            try_depth: 0,
+            echo: false,
+            constant_globals: HashSet::new(),
+            enclosing_locals: HashSet::new(),
+            path: None,
+            importing: Vec::new(),
+            imported: HashSet::new(),
+            file: None,
         }
     }
 
     pub fn run(&mut self, program_ast: Program) -> Result<(), String> {
+        // only a lone expression is echoed, not each statement of a longer program
+        self.echo &= matches!(
+            program_ast.declarations.as_slice(),
+            [DeclarationWithLineNo {
+                decl: Declaration::Statement(Statement::ExprStmt(_)),
+                ..
+            }]
+        );
         for decl in program_ast.declarations {
             self.declaration(decl)?;
         }
-        self.emit_byte(OpCode::OpEof as u8);
+        match self.function_type {
+            // functions falling off their end return null
+            FunctionType::Function | FunctionType::Method => {
+                self.emit_byte(OpCode::OpNil as u8);
+                self.emit_byte(OpCode::OpReturn as u8);
+            }
+            FunctionType::Script => self.emit_byte(OpCode::OpEof as u8),
+        }
         #[cfg(feature = "debugPrintCode")]
         {
             self.current_chunk.disassemble("code");
@@ -44,57 +178,91 @@ impl<'a> Compiler<'a> {
         let inner_decl = decl.decl;
         self.current_line = decl.lineno;
         match inner_decl {
-            Declaration::FunDecl(_) => todo!(),
+            Declaration::FunDecl(decl) => self.fun_decl(decl),
             Declaration::LetDecl(decl) => self.let_decl(decl),
+            Declaration::LetMulti(decls) => {
+                for decl in decls {
+                    self.let_decl(decl)?;
+                }
+                Ok(())
+            }
+            Declaration::StructDecl(decl) => self.struct_decl(decl),
             Declaration::Statement(statement) => self.statement(statement),
         }
     }
 
     fn statement(&mut self, statement: Statement) -> Result<(), String> {
         match statement {
-            Statement::ExprStmt(expr) => self.expression(expr),
-            Statement::IfStmt(_) => todo!(),
-            Statement::PrintStmt(expr) => self.print_statement(expr),
-            Statement::ReturnStmt(_) => self.return_statement(),
-            Statement::WhileStmt(_) => todo!(),
-            Statement::Block(declarations) => self.block(declarations),
+            Statement::ExprStmt(expr) => self.expression_statement(expr),
+            Statement::IfStmt(if_stmt) => self.if_statement(if_stmt),
+            Statement::PrintStmt(values) => self.print_statement(values),
+            Statement::ReturnStmt(return_stmt) => self.return_statement(return_stmt),
+            Statement::ContinueStmt(_) => self.continue_statement(),
+            Statement::WhileStmt(while_stmt) => self.while_statement(while_stmt),
+            Statement::DoWhileStmt(do_while_stmt) => self.do_while_statement(do_while_stmt),
+            Statement::Block(block) => self.block(block),
+            Statement::TryStmt(try_stmt) => self.try_statement(try_stmt),
+            Statement::ThrowStmt(throw_stmt) => self.throw_statement(throw_stmt),
+            Statement::ImportStmt(import_stmt) => self.import_statement(import_stmt),
         }
     }
 
     fn expression(&mut self, expr: Expr) -> Result<(), String> {
-        match expr {
+        // instructions of a sub-expression are attributed to its own line,
+        // the enclosing code gets its line back afterwards
+        let enclosing_line = self.current_line;
+        if let Some(line) = expr.line() {
+            self.current_line = line;
+        }
+        let result = match expr {
             Expr::Literal(literal) => self.literal(literal),
             Expr::Unary(op) => self.unary(op),
             Expr::Binary(op) => self.binary(op),
-            Expr::Call(_) => todo!(),
+            Expr::Call(call) => self.call(call),
             Expr::Grouping(group) => self.expression(*group.expression),
             Expr::Variable(variable) => self.variable(variable),
-            Expr::Assignment(_) => Err(self.report_error("Assignment not supported".to_string())),
+            Expr::Assignment(assignment) => self.assignment(assignment),
             Expr::Logical(logical) => self.logical(logical),
-            Expr::Get(_) => todo!(),
-            Expr::Set(_) => Err(self.report_error("Set not supported".to_string())),
-        }
+            Expr::Get(get) => self.get(get),
+            Expr::Set(set) => self.set(set),
+            Expr::List(list) => self.list(list),
+            Expr::Map(map) => self.map(map),
+            Expr::Index(index) => self.index(index),
+            Expr::IndexSet(index_set) => self.index_set(index_set),
+            Expr::Slf(keyword) => self.self_expression(keyword),
+            Expr::Lambda(lambda) => {
+                let function = self.function(lambda, FunctionType::Function)?;
+                self.emit_constant(Value::Function(Rc::new(function)))
+            }
+        };
+        self.current_line = enclosing_line;
+        result
     }
 
     fn literal(&mut self, literal: Literal) -> Result<(), String> {
         match literal {
-            Literal::Number(number) => self.emit_constant(Value::Number(number)),
-            Literal::Str(s) => self.emit_constant(Value::Str(s)),
+            Literal::Number(number) => self.emit_number(number)?,
+            Literal::Str(s) => self.emit_constant(Value::Str(s.into()))?,
+            Literal::Char(c) => self.emit_constant(Value::Char(c))?,
             Literal::True => self.emit_byte(OpCode::OpTrue as u8),
             Literal::False => self.emit_byte(OpCode::OpFalse as u8),
-            Literal::Null => todo!(),
+            Literal::Null => self.emit_byte(OpCode::OpNil as u8),
         }
         Ok(())
     }
 
     fn unary(&mut self, op: Unary) -> Result<(), String> {
+        if let Some(number) = fold_unary(&op) {
+            return self.emit_number(number);
+        }
         match op.operator.typ {
             TokenType::Minus => {
                 self.expression(*op.right)?;
                 self.emit_byte(OpCode::OpNegate as u8);
                 Ok(())
             }
-            TokenType::Not => {
+            // `not` is an alias of `!`
+            TokenType::Bang | TokenType::Not => {
                 self.expression(*op.right)?;
                 self.emit_byte(OpCode::OpNot as u8);
                 Ok(())
@@ -107,19 +275,33 @@ impl<'a> Compiler<'a> {
     }
 
     fn binary(&mut self, op: Binary) -> Result<(), String> {
+        if let Some(number) = fold_binary(&op) {
+            return self.emit_number(number);
+        }
         self.expression(*op.left)?;
         self.expression(*op.right)?;
+        // `a != b` is `not (a == b)`, so that both operators can't disagree
+        // `a >= b` is `not (a < b)` and `a <= b` is `not (a > b)`. Unlike IEEE 754, where
+        // any comparison with NaN is false, both are thus true when an operand is NaN.
+        let negated = match op.operator.typ {
+            TokenType::BangEqual => Some(OpCode::OpEqualEqual),
+            TokenType::GreaterEqual => Some(OpCode::OpLess),
+            TokenType::LessEqual => Some(OpCode::OpGreater),
+            _ => None,
+        };
+        if let Some(op_code) = negated {
+            self.emit_bytes(op_code as u8, OpCode::OpNot as u8);
+            return Ok(());
+        }
         let op_code = match op.operator.typ {
             TokenType::Minus => OpCode::OpSubtract,
             TokenType::Plus => OpCode::OpAdd,
             TokenType::Slash => OpCode::OpDivide,
+            TokenType::TildeSlash => OpCode::OpFloorDivide,
             TokenType::Star => OpCode::OpMultiply,
             TokenType::EqualEqual => OpCode::OpEqualEqual,
-            TokenType::BangEqual => OpCode::OpBangEqual,
             TokenType::Less => OpCode::OpLess,
-            TokenType::LessEqual => OpCode::OpLessEqual,
             TokenType::Greater => OpCode::OpGreater,
-            TokenType::GreaterEqual => OpCode::OpGreaterEqual,
             _ => Err(format!(
                 "Unexpected binary operator: {} at line {}",
                 op.operator.lexeme, op.operator.line
@@ -144,28 +326,269 @@ impl<'a> Compiler<'a> {
         Ok(())
     }
 
-    fn return_statement(&mut self) -> Result<(), String> {
-        self.emit_byte(OpCode::OpReturn as u8);
+    fn call(&mut self, call: Call) -> Result<(), String> {
+        match *call.callee {
+            Expr::Get(get) => self.invoke(get, call.arguments),
+            callee => self.emit_call(
+                Call {
+                    callee: Box::new(callee),
+                    ..call
+                },
+                OpCode::OpCall,
+            ),
+        }
+    }
+
+    /// `object.name(arguments)`, calling a method without binding it to the object first
+    fn invoke(&mut self, get: Get, arguments: Vec<Expr>) -> Result<(), String> {
+        // the parser already rejects calls with more than 255 arguments
+        let nb_arguments = arguments.len() as u8;
+        self.expression(*get.object)?;
+        for argument in arguments {
+            self.expression(argument)?;
+        }
+        let constant = self.make_constant(Value::Str(get.name.lexeme.into()))?;
+        self.emit_bytes(OpCode::OpInvoke as u8, constant);
+        self.emit_byte(nb_arguments);
+        Ok(())
+    }
+
+    fn emit_call(&mut self, call: Call, instruction: OpCode) -> Result<(), String> {
+        // the parser already rejects calls with more than 255 arguments
+        let nb_arguments = call.arguments.len() as u8;
+        self.expression(*call.callee)?;
+        for argument in call.arguments {
+            self.expression(argument)?;
+        }
+        self.emit_bytes(instruction as u8, nb_arguments);
+        Ok(())
+    }
+
+    fn list(&mut self, list: List) -> Result<(), String> {
+        let nb_elements: u8 = list
+            .elements
+            .len()
+            .try_into()
+            .map_err(|_| self.report_error("Too many elements in list literal".to_string()))?;
+        for element in list.elements {
+            self.expression(element)?;
+        }
+        self.emit_bytes(OpCode::OpBuildList as u8, nb_elements);
         Ok(())
     }
 
-    fn print_statement(&mut self, expr: Expr) -> Result<(), String> {
+    fn map(&mut self, map: Map) -> Result<(), String> {
+        let nb_entries: u8 = map
+            .entries
+            .len()
+            .try_into()
+            .map_err(|_| self.report_error("Too many entries in map literal".to_string()))?;
+        for (key, value) in map.entries {
+            self.expression(key)?;
+            self.expression(value)?;
+        }
+        self.emit_bytes(OpCode::OpBuildMap as u8, nb_entries);
+        Ok(())
+    }
+
+    fn index(&mut self, index: Index) -> Result<(), String> {
+        self.expression(*index.object)?;
+        self.expression(*index.index)?;
+        self.emit_byte(OpCode::OpIndexGet as u8);
+        Ok(())
+    }
+
+    fn index_set(&mut self, index_set: IndexSet) -> Result<(), String> {
+        self.expression(*index_set.object)?;
+        self.expression(*index_set.index)?;
+        self.expression(*index_set.value)?;
+        self.emit_byte(OpCode::OpIndexSet as u8);
+        Ok(())
+    }
+
+    fn get(&mut self, get: Get) -> Result<(), String> {
+        self.expression(*get.object)?;
+        let constant = self.make_constant(Value::Str(get.name.lexeme.into()))?;
+        self.emit_bytes(OpCode::OpGetField as u8, constant);
+        Ok(())
+    }
+
+    fn set(&mut self, set: Set) -> Result<(), String> {
+        self.expression(*set.object)?;
+        self.expression(*set.value)?;
+        let constant = self.make_constant(Value::Str(set.name.lexeme.into()))?;
+        self.emit_bytes(OpCode::OpSetField as u8, constant);
+        Ok(())
+    }
+
+    fn expression_statement(&mut self, expr: Expr) -> Result<(), String> {
         self.expression(expr)?;
-        self.emit_byte(OpCode::OpPrint as u8);
+        // the value of an expression statement is discarded, unless echoed
+        if self.echo {
+            self.emit_byte(OpCode::OpPrint as u8);
+        } else {
+            self.emit_byte(OpCode::OpPop as u8);
+        }
+        Ok(())
+    }
+
+    /// Locals of the blocks being left are not popped: returning discards the
+    /// whole frame, including them.
+    /// A script can return too, handing the value over to the host, but not a file it imports.
+    fn return_statement(&mut self, return_stmt: ReturnStmt) -> Result<(), String> {
+        if self.function_type == FunctionType::Script && !self.importing.is_empty() {
+            return Err(self.report_error("Can't return from an imported file.".to_string()));
+        }
+        match return_stmt.expr {
+            // a call in tail position reuses the frame of the current function, unless
+            // the call is in a `try` block, whose handler needs the frame, or the
+            // script's, which is kept in error traces
+            Some(Expr::Call(call))
+                if self.try_depth == 0 && self.function_type != FunctionType::Script =>
+            {
+                return self.emit_call(call, OpCode::OpTailCall);
+            }
+            Some(expr) => self.expression(expr)?,
+            None => self.emit_byte(OpCode::OpNil as u8),
+        }
+        self.emit_byte(OpCode::OpReturn as u8);
+        Ok(())
+    }
+
+    fn if_statement(&mut self, if_stmt: IfStmt) -> Result<(), String> {
+        self.expression(if_stmt.condition)?;
+        let then_jump = self.emit_condition_jump();
+        self.statement(*if_stmt.then_branch)?;
+        let else_jump = self.emit_jump(OpCode::OpJump);
+        self.patch_jump(then_jump)?;
+        if let Some(else_branch) = if_stmt.else_branch {
+            self.statement(*else_branch)?;
+        }
+        self.patch_jump(else_jump)?;
+        Ok(())
+    }
+
+    fn print_statement(&mut self, values: Vec<Expr>) -> Result<(), String> {
+        // the parser already rejects more than 255 values
+        let nb_values = values.len() as u8;
+        for value in values {
+            self.expression(value)?;
+        }
+        if nb_values == 1 {
+            self.emit_byte(OpCode::OpPrint as u8);
+        } else {
+            self.emit_bytes(OpCode::OpPrintList as u8, nb_values);
+        }
         Ok(())
     }
 
     fn let_decl(&mut self, decl: LetDecl) -> Result<(), String> {
-        // FIXME: allow absence of initializer
-        let initializer = decl
-            .initializer
-            .expect("Expected initializer to let declaration");
-        self.expression(initializer)?;
+        match decl.initializer {
+            Some(initializer) => self.expression(initializer)?,
+            // a variable declared without a value is null
+            None => self.emit_byte(OpCode::OpNil as u8),
+        }
         if self.scope_depth > 0 {
             self.add_local(decl.identifier)?;
+            let local = self.locals.last_mut().expect("Expected the variable to be a local");
+            local.constant = decl.is_const;
             return Ok(());
         }
-        let constant = self.make_constant(Value::Str(decl.identifier.lexeme));
+        // a global declared again with `let` becomes assignable. Functions compiled
+        // before the declaration can't know it is constant, so the VM checks it too.
+        let instruction = if decl.is_const {
+            self.constant_globals.insert(decl.identifier.lexeme.clone());
+            OpCode::OpDefineConstant
+        } else {
+            self.constant_globals.remove(&decl.identifier.lexeme);
+            OpCode::OpDefineGlobal
+        };
+        let constant = self.make_constant(Value::Str(decl.identifier.lexeme.into()))?;
+        self.emit_bytes(instruction as u8, constant);
+        Ok(())
+    }
+
+    fn fun_decl(&mut self, decl: FunDecl) -> Result<(), String> {
+        let name = decl.name.clone();
+        let function = self.function(decl, FunctionType::Function)?;
+        self.emit_constant(Value::Function(Rc::new(function)))?;
+        self.define_variable(name)
+    }
+
+    fn function(&mut self, decl: FunDecl, function_type: FunctionType) -> Result<Function, String> {
+        let arity = decl.params.len();
+        let mut chunk = Chunk::new();
+        let mut compiler = Compiler::new_function(&mut chunk, self.current_line, function_type);
+        compiler.constant_globals = self.constant_globals.clone();
+        compiler.enclosing_locals = self.enclosing_locals.clone();
+        compiler.file = self.file.clone();
+        compiler
+            .enclosing_locals
+            .extend(self.locals.iter().map(|local| local.name.lexeme.clone()));
+        for (index, param) in decl.params.iter().enumerate() {
+            let previous_params = &decl.params[..index];
+            if previous_params.iter().any(|previous| self.identifiers_equal(previous, param)) {
+                return Err(self.report_error_at(
+                    param,
+                    format!("Already a parameter with name '{}'.", param.lexeme),
+                ));
+            }
+        }
+        for param in decl.params {
+            compiler.add_local(param)?;
+        }
+        compiler.run(Program {
+            declarations: decl.body,
+        })?;
+        Ok(Function {
+            name: decl.name.lexeme,
+            arity,
+            chunk: Rc::new(chunk),
+        })
+    }
+
+    fn struct_decl(&mut self, decl: StructDecl) -> Result<(), String> {
+        if let Some(parent) = &decl.parent {
+            if self.identifiers_equal(parent, &decl.name) {
+                return Err(self.report_error("A struct can't inherit from itself".to_string()));
+            }
+            self.variable(Variable {
+                name: parent.clone(),
+            })?;
+        }
+        let mut methods = HashMap::new();
+        for method in decl.methods {
+            let method = self.function(method, FunctionType::Method)?;
+            methods.insert(method.name.clone(), Rc::new(method));
+        }
+        let struct_type = Struct {
+            name: decl.name.lexeme.clone(),
+            fields: decl.fields.into_iter().map(|field| field.lexeme).collect(),
+            methods,
+        };
+        self.emit_constant(Value::Struct(Rc::new(struct_type)))?;
+        if decl.parent.is_some() {
+            self.emit_byte(OpCode::OpInherit as u8);
+        }
+        self.define_variable(decl.name)
+    }
+
+    fn self_expression(&mut self, keyword: Token) -> Result<(), String> {
+        match self.resolve_local(&keyword) {
+            Some(index) if self.function_type == FunctionType::Method => {
+                self.emit_indexed(OpCode::OpGetLocal, OpCode::OpGetLocalLong, index);
+                Ok(())
+            }
+            _ => Err(self.report_error("Can't use 'self' outside of a method".to_string())),
+        }
+    }
+
+    /// bind the value on top of the stack to `name`
+    fn define_variable(&mut self, name: Token) -> Result<(), String> {
+        if self.scope_depth > 0 {
+            return self.add_local(name);
+        }
+        let constant = self.make_constant(Value::Str(name.lexeme.into()))?;
         self.emit_bytes(OpCode::OpDefineGlobal as u8, constant);
         Ok(())
     }
@@ -173,51 +596,245 @@ impl<'a> Compiler<'a> {
     fn variable(&mut self, variable: Variable) -> Result<(), String> {
         let local_index = self.resolve_local(&variable.name);
         match local_index {
-            Some(index) => self.emit_bytes(OpCode::OpGetLocal as u8, index.try_into().unwrap()),
+            Some(index) => self.emit_indexed(OpCode::OpGetLocal, OpCode::OpGetLocalLong, index),
             None => {
-                let constant = self.make_constant(Value::Str(variable.name.lexeme));
+                let constant = self.global_constant(variable.name)?;
                 self.emit_bytes(OpCode::OpGetGlobal as u8, constant);
             }
         };
         Ok(())
     }
 
-    fn block(&mut self, declarations: Vec<DeclarationWithLineNo>) -> Result<(), String> {
+    fn assignment(&mut self, assignment: Assignment) -> Result<(), String> {
+        self.expression(*assignment.value)?;
+        let local_index = self.resolve_local(&assignment.name);
+        let constant = match local_index {
+            Some(index) => self.locals[index].constant,
+            None => self.constant_globals.contains(&assignment.name.lexeme),
+        };
+        if constant {
+            return Err(self.report_error(format!(
+                "Cannot assign to constant '{}'.",
+                assignment.name.lexeme
+            )));
+        }
+        match local_index {
+            Some(index) => self.emit_indexed(OpCode::OpSetLocal, OpCode::OpSetLocalLong, index),
+            None => {
+                let constant = self.global_constant(assignment.name)?;
+                self.emit_bytes(OpCode::OpSetGlobal as u8, constant);
+            }
+        };
+        Ok(())
+    }
+
+    /// use the short form when the index fits in a byte, the 24 bits one otherwise
+    fn emit_indexed(&mut self, instruction: OpCode, long_instruction: OpCode, index: usize) {
+        match u8::try_from(index) {
+            Ok(index) => self.emit_bytes(instruction as u8, index),
+            Err(_) => {
+                self.emit_byte(long_instruction as u8);
+                self.emit_byte((index >> 16) as u8);
+                self.emit_bytes((index >> 8) as u8, index as u8);
+            }
+        }
+    }
+
+    /// The condition is popped by its jump, whether the loop goes on or not.
+    /// A `return` from the body discards the whole frame.
+    fn while_statement(&mut self, while_stmt: WhileStmt) -> Result<(), String> {
+        let loop_start = self.current_chunk.count();
+        self.expression(while_stmt.condition)?;
+        let exit_jump = self.emit_condition_jump();
+        self.loop_body(*while_stmt.body)?;
+        if let Some(increment) = while_stmt.increment {
+            self.declaration(*increment)?;
+        }
+        self.emit_loop(loop_start)?;
+        self.patch_jump(exit_jump)?;
+        Ok(())
+    }
+
+    fn do_while_statement(&mut self, do_while_stmt: WhileStmt) -> Result<(), String> {
+        let loop_start = self.current_chunk.count();
+        self.loop_body(*do_while_stmt.body)?;
+        self.expression(do_while_stmt.condition)?;
+        let exit_jump = self.emit_condition_jump();
+        self.emit_loop(loop_start)?;
+        self.patch_jump(exit_jump)?;
+        Ok(())
+    }
+
+    /// `continue` statements of the body jump right after it
+    fn loop_body(&mut self, body: Statement) -> Result<(), String> {
+        self.loops.push(Loop {
+            scope_depth: self.scope_depth,
+            try_depth: self.try_depth,
+            continue_jumps: Vec::new(),
+        });
+        self.statement(body)?;
+        let current_loop = self.loops.pop().expect("Expected loop to be set");
+        for continue_jump in current_loop.continue_jumps {
+            self.patch_jump(continue_jump)?;
+        }
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<(), String> {
+        let (loop_depth, loop_try_depth) = match self.loops.last() {
+            Some(current_loop) => (current_loop.scope_depth, current_loop.try_depth),
+            None => {
+                return Err(self.report_error("Can't use 'continue' outside of a loop.".to_string()))
+            }
+        };
+        // so are the handlers of the `try` blocks being left
+        for _ in loop_try_depth..self.try_depth {
+            self.emit_byte(OpCode::OpEndTry as u8);
+        }
+        // the locals of the body are left on the stack by the jump: pop them, but
+        // keep them in scope for the rest of the body
+        let nb_vars_to_pop = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > loop_depth)
+            .count();
+        self.emit_pops(nb_vars_to_pop);
+        let continue_jump = self.emit_jump(OpCode::OpJump);
+        self.loops
+            .last_mut()
+            .expect("Expected loop to be set")
+            .continue_jumps
+            .push(continue_jump);
+        Ok(())
+    }
+
+    fn block(&mut self, block: Block) -> Result<(), String> {
         self.scope_depth += 1;
-        // FIXME: line number are not tracked inside blocks
-        for decl in declarations {
+        for decl in block.declarations {
             self.declaration(decl)?;
         }
         self.scope_depth -= 1;
-        let mut nb_vars_to_pop: u8 = 0;
-        while self.locals.len() > 0 && self.locals[self.locals.len() - 1].depth > self.scope_depth {
+        // locals are popped when reaching the closing brace
+        self.current_line = block.end_lineno;
+        let mut nb_vars_to_pop: usize = 0;
+        while !self.locals.is_empty() && self.locals[self.locals.len() - 1].depth > self.scope_depth {
             self.locals.pop();
             nb_vars_to_pop += 1;
         }
+        self.emit_pops(nb_vars_to_pop);
+        Ok(())
+    }
+
+    /// The handler is registered by OpTry and removed by OpEndTry once the body is done.
+    /// When an error is raised in between, the VM unwinds the stack back to the locals
+    /// declared before the `try` block, and pushes the error value on top of them, where
+    /// the handler finds its variable.
+    fn try_statement(&mut self, try_stmt: TryStmt) -> Result<(), String> {
+        let try_jump = self.emit_jump(OpCode::OpTry);
+        self.try_depth += 1;
+        self.block(try_stmt.body)?;
+        self.try_depth -= 1;
+        self.emit_byte(OpCode::OpEndTry as u8);
+        let end_jump = self.emit_jump(OpCode::OpJump);
+        self.patch_jump(try_jump)?;
+        self.scope_depth += 1;
+        self.add_local(try_stmt.name)?;
+        self.block(try_stmt.handler)?;
+        self.scope_depth -= 1;
+        self.locals.pop();
+        self.emit_byte(OpCode::OpPop as u8);
+        self.patch_jump(end_jump)?;
+        Ok(())
+    }
+
+    fn throw_statement(&mut self, throw_stmt: ThrowStmt) -> Result<(), String> {
+        self.expression(throw_stmt.expr)?;
+        self.emit_byte(OpCode::OpThrow as u8);
+        Ok(())
+    }
+
+    /// The declarations of the imported file are compiled in place of the import, defining
+    /// its globals for the importing file. A file is only run by its first import, and
+    /// importing a file which is being imported is an error, as the import would never end.
+    fn import_statement(&mut self, import_stmt: ImportStmt) -> Result<(), String> {
+        if self.function_type != FunctionType::Script || self.scope_depth > 0 {
+            return Err(self.report_error("Can only import at top-level.".to_string()));
+        }
+        let directory = match self.importing.last().or(self.path.as_ref()) {
+            Some(file) => file.parent().unwrap_or(Path::new("")).to_path_buf(),
+            // the REPL imports from the working directory
+            None => PathBuf::new(),
+        };
+        let path = fs::canonicalize(directory.join(&import_stmt.path)).map_err(|err| {
+            self.report_error(format!("Can't import '{}': {}", import_stmt.path, err))
+        })?;
+        if self.path.as_ref() == Some(&path) || self.importing.contains(&path) {
+            return Err(self.report_error(format!("Import cycle through '{}'.", import_stmt.path)));
+        }
+        if !self.imported.insert(path.clone()) {
+            return Ok(());
+        }
+        let program = parse_file(&path)
+            .map_err(|err| self.report_error(format!("In '{}':\n{}", import_stmt.path, err)))?;
+        let enclosing_line = self.current_line;
+        let enclosing_file = self.file.replace(self.display_path(&import_stmt.path));
+        self.importing.push(path);
+        for decl in program.declarations {
+            self.declaration(decl)?;
+        }
+        self.importing.pop();
+        self.file = enclosing_file;
+        self.current_line = enclosing_line;
+        Ok(())
+    }
+
+    /// path of an imported file as shown in errors, relative to the file being compiled
+    /// like the imports written there
+    fn display_path(&self, import_path: &str) -> Rc<str> {
+        let directory = match &self.file {
+            Some(file) => Path::new(&**file).parent().unwrap_or(Path::new("")),
+            None => Path::new(""),
+        };
+        directory.join(import_path).display().to_string().into()
+    }
+
+    fn emit_pops(&mut self, mut nb_vars_to_pop: usize) {
+        // a block can hold more locals than OpPopN's operand can encode
+        while nb_vars_to_pop > u8::MAX as usize {
+            self.emit_bytes(OpCode::OpPopN as u8, u8::MAX);
+            nb_vars_to_pop -= u8::MAX as usize;
+        }
         if nb_vars_to_pop == 1 {
             self.emit_byte(OpCode::OpPop as u8);
         } else if nb_vars_to_pop > 1 {
-            self.emit_bytes(OpCode::OpPopN as u8, nb_vars_to_pop);
+            self.emit_bytes(OpCode::OpPopN as u8, nb_vars_to_pop as u8);
         }
-        Ok(())
     }
 
     fn add_local(&mut self, name: Token) -> Result<(), String> {
+        if self.locals.len() == LOCALS_MAX {
+            return Err(self.report_error("Too many local variables in function.".to_string()));
+        }
         for index in (0..self.locals.len()).rev() {
             let local = &self.locals[index];
             if local.depth < self.scope_depth {
                 break;
             }
             if self.identifiers_equal(&local.name, &name) {
-                return Err(self.report_error(format!(
-                    "Already a variable with the name {} in this scope",
-                    name.lexeme
-                )));
+                return Err(self.report_error_at(
+                    &name,
+                    format!(
+                        "Already a variable with the name {} in this scope",
+                        name.lexeme
+                    ),
+                ));
             }
         }
         self.locals.push(Local {
             name,
             depth: self.scope_depth,
+            constant: false,
         });
         Ok(())
     }
@@ -226,26 +843,47 @@ impl<'a> Compiler<'a> {
     fn resolve_local(&self, name: &Token) -> Option<usize> {
         for index in (0..self.locals.len()).rev() {
             let local = &self.locals[index];
-            if self.identifiers_equal(&local.name, &name) {
+            if self.identifiers_equal(&local.name, name) {
                 return Some(index);
             }
         }
         None
     }
 
+    /// name of a variable which isn't a local, so it must be a global rather than
+    /// a local of an enclosing function
+    fn global_constant(&mut self, name: Token) -> Result<u8, String> {
+        if self.enclosing_locals.contains(&name.lexeme) {
+            let message = format!("Can't capture local '{}'.", name.lexeme);
+            return Err(self.report_error_at(&name, message));
+        }
+        self.make_constant(Value::Str(name.lexeme.into()))
+    }
+
     fn identifiers_equal(&self, first: &Token, second: &Token) -> bool {
         first.lexeme == second.lexeme
     }
 
     fn report_error(&self, message: String) -> String {
-        format!(
-            "Compilation error: {}\nat line {}",
-            message, self.current_line
-        )
+        self.report_error_at_line(self.current_line, message)
+    }
+
+    /// report an error about `token`, which may not be on the line of the declaration
+    fn report_error_at(&self, token: &Token, message: String) -> String {
+        self.report_error_at_line(token.line, message)
+    }
+
+    fn report_error_at_line(&self, line: u16, message: String) -> String {
+        let location = match &self.file {
+            Some(file) => format!("line {} of {}", line, file),
+            None => format!("line {}", line),
+        };
+        format!("Compilation error: {}\nat {}", message, location)
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.current_chunk.write(byte, self.current_line as usize);
+        let (line, file) = (self.current_line as usize, self.file.clone());
+        self.current_chunk.write_in_file(byte, line, file);
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -253,12 +891,435 @@ impl<'a> Compiler<'a> {
         self.emit_byte(byte2);
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::OpConstant as u8, constant);
+    /// emit a jump instruction with a placeholder operand, and return the offset
+    /// of that operand so that it can be patched later on.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk.count() - 2
+    }
+
+    /// jump over what follows when the condition on top of the stack is falsey,
+    /// popping the condition on both paths
+    fn emit_condition_jump(&mut self) -> usize {
+        self.emit_jump(OpCode::OpJumpIfFalsePop)
+    }
+
+    fn patch_jump(&mut self, offset: usize) -> Result<(), String> {
+        // -2 to adjust for the jump operand itself
+        let jump: u16 = (self.current_chunk.count() - offset - 2)
+            .try_into()
+            .map_err(|_| self.report_error("Too much code to jump over.".to_string()))?;
+        self.current_chunk.patch(offset, (jump >> 8) as u8);
+        self.current_chunk.patch(offset + 1, jump as u8);
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), String> {
+        self.emit_byte(OpCode::OpLoop as u8);
+        // +2 to jump over the loop operand as well
+        let loop_size: u16 = (self.current_chunk.count() - loop_start + 2)
+            .try_into()
+            .map_err(|_| self.report_error("Loop body too large.".to_string()))?;
+        self.emit_bytes((loop_size >> 8) as u8, loop_size as u8);
+        Ok(())
+    }
+
+    /// small integers are encoded in the instruction, sparing a constant
+    fn emit_number(&mut self, number: f64) -> Result<(), String> {
+        let immediate = number as i8;
+        // comparing the bits rules out -0, which would come back as 0
+        if (immediate as f64).to_bits() == number.to_bits() {
+            self.emit_bytes(OpCode::OpImmediate as u8, immediate as u8);
+            Ok(())
+        } else {
+            self.emit_constant(Value::Number(number))
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), String> {
+        let constant = self.current_chunk.add_constant(value);
+        if constant >= CONSTANTS_MAX {
+            return Err(self.report_error("Too many constants in one chunk.".to_string()));
+        }
+        self.emit_indexed(OpCode::OpConstant, OpCode::OpConstantLong, constant);
+        Ok(())
+    }
+
+    /// constant for an instruction taking a one byte operand, such as the name of a global
+    fn make_constant(&mut self, value: Value) -> Result<u8, String> {
+        let constant = self.current_chunk.add_constant(value);
+        u8::try_from(constant)
+            .map_err(|_| self.report_error("Too many constants in one chunk.".to_string()))
+    }
+}
+
+/// Value of a numeric expression made only of number literals, computed at
+/// compile time. Follows the VM's arithmetic, which operates on f64.
+fn fold_number(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Literal(Literal::Number(number)) => Some(*number),
+        Expr::Grouping(group) => fold_number(&group.expression),
+        Expr::Unary(op) => fold_unary(op),
+        Expr::Binary(op) => fold_binary(op),
+        _ => None,
+    }
+}
+
+fn fold_unary(op: &Unary) -> Option<f64> {
+    match op.operator.typ {
+        TokenType::Minus => fold_number(&op.right).map(|number| -number),
+        _ => None,
+    }
+}
+
+fn fold_binary(op: &Binary) -> Option<f64> {
+    let left = fold_number(&op.left)?;
+    let right = fold_number(&op.right)?;
+    match op.operator.typ {
+        TokenType::Plus => Some(left + right),
+        TokenType::Minus => Some(left - right),
+        TokenType::Star => Some(left * right),
+        TokenType::Slash => Some(left / right),
+        // dividing by zero is left for the VM to report
+        TokenType::TildeSlash if right != 0.0 => Some((left / right).floor()),
+        _ => None,
+    }
+}
+
+/// scan and parse the file at `path`, returning its errors one per line otherwise
+fn parse_file(path: &Path) -> Result<Program, String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let tokens = Scanner::new(source).scan_tokens().map_err(join_errors)?;
+    Parser::new(tokens).parse().map_err(join_errors)
+}
+
+fn join_errors<E: ToString>(errors: Vec<E>) -> String {
+    let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    errors.join("\n")
+}
+
+#[cfg(test)]
+mod compiler_tests {
+    use super::*;
+    use crate::ast::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn try_compile(source: &str) -> Result<Chunk, String> {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let mut chunk = Chunk::new();
+        Compiler::new(&mut chunk).run(program)?;
+        Ok(chunk)
+    }
+
+    fn compile(source: &str) -> Chunk {
+        try_compile(source).unwrap()
+    }
+
+    #[test]
+    fn block_locals_are_popped_at_closing_brace_line() {
+        let chunk = compile("{\n  let a = 1;\n  print a;\n}");
+        // the pop right before OP_EOF
+        let pop_offset = chunk.count() - 2;
+        assert!(matches!(
+            OpCode::new(chunk.read_byte(pop_offset)),
+            OpCode::OpPop
+        ));
+        assert_eq!(chunk.get_lineno(pop_offset), 4);
+    }
+
+    #[test]
+    fn small_integers_are_immediate() {
+        let chunk = compile("print 1;");
+        assert!(matches!(OpCode::new(chunk.read_byte(0)), OpCode::OpImmediate));
+        assert_eq!(chunk.read_byte(1), 1);
+        assert!(matches!(OpCode::new(chunk.read_byte(2)), OpCode::OpPrint));
+        let chunk = compile("print -0;");
+        assert!(matches!(OpCode::new(chunk.read_byte(0)), OpCode::OpConstant));
+    }
+
+    #[test]
+    fn oversized_loop_body_is_rejected() {
+        let source = format!("while (false) {{\n{}}}", "print 1;\n".repeat(30000));
+        match try_compile(&source) {
+            Err(err) => assert!(err.contains("Loop body too large."), "{}", err),
+            Ok(_) => panic!("Expected the loop body to be rejected"),
+        }
+    }
+
+    #[test]
+    fn oversized_if_body_is_rejected() {
+        let source = format!("if (false) {{\n{}}}", "print 1;\n".repeat(30000));
+        match try_compile(&source) {
+            Err(err) => assert!(err.contains("Too much code to jump over."), "{}", err),
+            Ok(_) => panic!("Expected the if body to be rejected"),
+        }
+    }
+
+    #[test]
+    fn imports_are_only_allowed_at_top_level() {
+        match try_compile("fun f() {\n  import \"lib.rox\";\n}") {
+            Err(err) => assert!(err.contains("Can only import at top-level."), "{}", err),
+            Ok(_) => panic!("Expected the import to be rejected"),
+        }
+        match try_compile("import \"missing.rox\";") {
+            Err(err) => assert!(err.contains("Can't import 'missing.rox'"), "{}", err),
+            Ok(_) => panic!("Expected a missing file to be rejected"),
+        }
+    }
+
+    #[test]
+    fn imported_file_is_compiled_in_place() {
+        // relative to the working directory, without a file being compiled
+        let chunk = compile("import \"scripts/test_data/modules/shapes.rox\";\nprint 1;");
+        let disassembly = chunk.disassembly("code");
+        assert!(disassembly.contains("'shapes imported'"), "{}", disassembly);
+        assert_eq!(disassembly.matches("OP_EOF").count(), 1);
+        // its code is traced back to it
+        let shapes = "scripts/test_data/modules/shapes.rox";
+        assert_eq!(chunk.get_file(0), Some(shapes));
+        assert_eq!(chunk.get_file(chunk.count() - 1), None);
+    }
+
+    #[test]
+    fn enclosing_locals_cant_be_captured() {
+        let source = "let n = 10;\nfun outer() {\n  let n = 3;\n  return map([1], fun(x) {\n    return x + n;\n  });\n}";
+        assert_eq!(
+            try_compile(source).err(),
+            Some("Compilation error: Can't capture local 'n'.\nat line 5".to_string())
+        );
+        let err = try_compile("{\n  let n = 1;\n  fun f() {\n    n = 2;\n  }\n}").err();
+        assert!(err.unwrap().contains("Can't capture local 'n'."));
+        assert!(try_compile("let n = 10;\nfun f(x) {\n  return x + n;\n}").is_ok());
+    }
+
+    #[test]
+    fn duplicate_parameters_are_rejected() {
+        match try_compile("fun f(a, a) {}") {
+            Err(err) => assert!(err.contains("Already a parameter with name 'a'.")),
+            Ok(_) => panic!("Expected duplicate parameters to be rejected"),
+        }
+        assert!(try_compile("fun f(a, b) {}").is_ok());
+    }
+
+    #[test]
+    fn duplicate_local_error_has_the_line_of_the_redeclaration() {
+        match try_compile("{\n  let a = 1;\n  let\n    a = 2;\n}") {
+            Err(err) => assert!(err.ends_with("at line 4"), "{}", err),
+            Ok(_) => panic!("Expected the redeclaration to be rejected"),
+        }
+    }
+
+    #[test]
+    fn script_can_return() {
+        let chunk = compile("return 1;");
+        assert!(matches!(OpCode::new(chunk.read_byte(2)), OpCode::OpReturn));
+        assert!(try_compile("{\n  return;\n}").is_ok());
+        // the script's frame is kept
+        let disassembly = compile("fun f() {}\nreturn f();").disassembly("code");
+        assert!(!disassembly.contains("OP_TAIL_CALL"), "{}", disassembly);
+    }
+
+    #[test]
+    fn imported_file_cant_return() {
+        match try_compile("import \"scripts/test_data/modules/returning.rox\";") {
+            Err(err) => assert!(err.contains("Can't return from an imported file."), "{}", err),
+            Ok(_) => panic!("Expected the return of the imported file to be rejected"),
+        }
+    }
+
+    #[test]
+    fn conditions_are_popped_by_their_jump() {
+        // the condition jump pops, sparing an OP_POP on each of its paths
+        let code = compile("let x = true;\nif (x) print 1; else print 2;").disassembly("code");
+        assert_eq!(code.matches("OP_JUMP_IF_FALSE_POP").count(), 1);
+        assert_eq!(code.matches("OP_POP\n").count(), 0, "{}", code);
+        // the only OP_POP discards the value of the assignment
+        let code = compile("let x = true;\nwhile (x) x = false;").disassembly("code");
+        assert_eq!(code.matches("OP_JUMP_IF_FALSE_POP").count(), 1);
+        assert_eq!(code.matches("OP_POP\n").count(), 1, "{}", code);
+    }
+
+    #[test]
+    fn disassembly_labels_jump_targets() {
+        let chunk = compile("let x = true;\nif (x) print 1; else print 2;");
+        let disassembly = chunk.disassembly("code");
+        let lines: Vec<&str> = disassembly.lines().collect();
+        let jump = lines
+            .iter()
+            .find(|line| line.contains("OP_JUMP_IF_FALSE_POP"))
+            .unwrap();
+        let target: usize = jump.rsplit(' ').next().unwrap().parse().unwrap();
+        // the else branch, then the end of the if statement
+        let label = lines.iter().position(|line| *line == "L0:").unwrap();
+        assert!(lines[label + 1].starts_with(&format!("{:04} ", target)));
+        assert!(lines.contains(&"L1:"));
+        assert!(!lines.contains(&"L2:"));
+    }
+
+    #[test]
+    fn method_call_is_a_single_invoke() {
+        let chunk = compile("struct A {\n  fun m(x) { return x; }\n}\nA().m(1);");
+        // OP_INVOKE and its two operands, OP_POP and OP_EOF
+        let invoke_offset = chunk.count() - 5;
+        assert!(matches!(
+            OpCode::new(chunk.read_byte(invoke_offset)),
+            OpCode::OpInvoke
+        ));
+        assert_eq!(chunk.read_byte(invoke_offset + 2), 1);
+    }
+
+    #[test]
+    fn calls_in_try_blocks_are_not_tail_calls() {
+        let chunk = compile(
+            "fun f() {\n  try {\n    return f();\n  } catch (e) {\n    return f();\n  }\n}",
+        );
+        let Value::Function(f) = chunk.read_constant(0) else {
+            panic!("Expected the function as first constant");
+        };
+        let disassembly = f.chunk.disassembly("f");
+        assert_eq!(disassembly.matches("OP_CALL").count(), 1);
+        assert_eq!(disassembly.matches("OP_TAIL_CALL").count(), 1);
+    }
+
+    #[test]
+    fn continue_leaves_try_blocks() {
+        let chunk = compile("while (true) {\n  try {\n    try {\n      continue;\n    } catch (e) {}\n  } catch (e) {}\n}");
+        let disassembly = chunk.disassembly("code");
+        // two for `continue`, then one at the end of each block
+        assert_eq!(disassembly.matches("OP_END_TRY").count(), 4);
+    }
+
+    #[test]
+    fn max_short_locals() {
+        let declarations: Vec<String> = (0..256).map(|i| format!("let a{} = true;", i)).collect();
+        assert!(try_compile(&format!("{{ {} }}", declarations.join(" "))).is_ok());
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let declarations: Vec<String> = (0..LOCALS_MAX + 1)
+            .map(|i| format!("let a{} = true;", i))
+            .collect();
+        let result = try_compile(&format!("{{ {} }}", declarations.join(" ")));
+        assert_eq!(
+            result.err(),
+            Some("Compilation error: Too many local variables in function.\nat line 1".to_string())
+        );
+    }
+
+    #[test]
+    fn locals_beyond_a_byte_use_long_operand() {
+        let declarations: Vec<String> = (0..300).map(|i| format!("let a{} = true;", i)).collect();
+        let source = format!("{{ {} a255; a256 = a299; }}", declarations.join(" "));
+        let disassembly = compile(&source).disassembly("code");
+        for instruction in [
+            "OP_GET_LOCAL     255\n",
+            "OP_GET_LOCAL_LONG 299\n",
+            "OP_SET_LOCAL_LONG 256\n",
+        ] {
+            assert!(disassembly.contains(instruction), "{}", disassembly);
+        }
+    }
+
+    #[test]
+    fn constants_beyond_a_byte_use_long_operand() {
+        let declarations: Vec<String> =
+            (0..300).map(|i| format!("let s{} = \"s{}\";", i, i)).collect();
+        let disassembly = compile(&format!("{{ {} }}", declarations.join(" "))).disassembly("code");
+        for instruction in ["OP_CONSTANT      255 's255'\n", "OP_CONSTANT_LONG 299 's299'\n"] {
+            assert!(disassembly.contains(instruction), "{}", disassembly);
+        }
+    }
+
+    #[test]
+    fn names_strings_and_numbers_are_stored_once() {
+        let source = "let a = \"a\";\nprint a + \"a\";\na = 1.5;\nprint a * 1.5;";
+        let disassembly = compile(source).disassembly("code");
+        assert_eq!(disassembly.matches(" 0 'a'\n").count(), 6, "{}", disassembly);
+        assert_eq!(disassembly.matches(" 1 '1.5'\n").count(), 2, "{}", disassembly);
+    }
+
+    #[test]
+    fn too_many_named_constants() {
+        // the names of globals need a constant index fitting in a byte
+        let declarations: Vec<String> =
+            (0..300).map(|i| format!("let g{} = {}.5;", i, i)).collect();
+        match try_compile(&declarations.join("\n")) {
+            Err(err) => assert!(err.contains("Too many constants in one chunk."), "{}", err),
+            Ok(_) => panic!("Expected the globals to be rejected"),
+        }
+    }
+
+    fn assert_single_constant(chunk: &Chunk, value: f64) {
+        // OP_CONSTANT or OP_IMMEDIATE, its operand, OP_POP and OP_EOF
+        assert_eq!(chunk.count(), 4);
+        match OpCode::new(chunk.read_byte(0)) {
+            OpCode::OpConstant => assert!(chunk.read_constant(0) == Value::Number(value)),
+            OpCode::OpImmediate => assert_eq!(chunk.read_byte(1) as i8 as f64, value),
+            _ => panic!("Expected a single constant"),
+        }
+    }
+
+    #[test]
+    fn negative_literal_is_folded() {
+        assert_single_constant(&compile("-5;"), -5.0);
+    }
+
+    #[test]
+    fn binary_arithmetic_is_folded() {
+        assert_single_constant(&compile("2 + 3;"), 5.0);
+        assert_single_constant(&compile("-(2 + 3) * 4 / 2 - 1;"), -11.0);
+        assert_single_constant(&compile("-7 ~/ 2;"), -4.0);
+    }
+
+    #[test]
+    fn floor_division_by_zero_is_not_folded() {
+        let chunk = compile("1 ~/ 0;");
+        assert!(matches!(
+            OpCode::new(chunk.read_byte(chunk.count() - 3)),
+            OpCode::OpFloorDivide
+        ));
+    }
+
+    #[test]
+    fn non_numeric_operands_are_not_folded() {
+        let chunk = compile("2 + true;");
+        assert!(matches!(
+            OpCode::new(chunk.read_byte(chunk.count() - 3)),
+            OpCode::OpAdd
+        ));
+    }
+
+    #[test]
+    fn sub_expression_has_its_own_line() {
+        let chunk = compile("print 1 +\n  (2 *\n  \"a\");");
+        // OP_MULTIPLY, OP_ADD, OP_PRINT and OP_EOF
+        let multiply_offset = chunk.count() - 4;
+        assert!(matches!(
+            OpCode::new(chunk.read_byte(multiply_offset)),
+            OpCode::OpMultiply
+        ));
+        assert_eq!(chunk.get_lineno(multiply_offset), 2);
+        assert_eq!(chunk.get_lineno(multiply_offset + 1), 1);
+        assert_eq!(chunk.get_lineno(multiply_offset + 2), 1);
+    }
+
+    #[test]
+    fn assigning_to_constant_is_an_error() {
+        let err = try_compile("const x = 1;\nx = 2;").err().unwrap();
+        assert!(err.contains("Cannot assign to constant 'x'."));
+        let err = try_compile("{\n  const x = 1;\n  x = 2;\n}").err().unwrap();
+        assert!(err.contains("Cannot assign to constant 'x'."));
+        let err = try_compile("const x = 1;\nfun f() {\n  x = 2;\n}").err().unwrap();
+        assert!(err.contains("Cannot assign to constant 'x'."));
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        self.current_chunk.add_constant(value)
+    #[test]
+    fn assigning_to_variable_is_allowed() {
+        assert!(try_compile("let y = 1;\ny = 2;").is_ok());
+        // a local shadowing a constant is a variable of its own
+        assert!(try_compile("const x = 1;\n{\n  let x = 1;\n  x = 2;\n}").is_ok());
     }
 }
@@ -0,0 +1,169 @@
+//! Values are reference counted, which frees them as soon as they are no longer used,
+//! except for containers referencing each other, like `a.next = a`. The heap keeps track
+//! of every container allocated, to find those only kept alive by such cycles.
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use crate::value::{Instance, List, Value};
+
+// number of tracked containers from which a collection is run
+const COLLECTION_MIN: usize = 1024;
+
+/// A container allocated by the heap, which may have been freed since.
+enum Object {
+    List(Weak<List>),
+    Map(Weak<RefCell<HashMap<Value, Value>>>),
+    Instance(Weak<RefCell<Instance>>),
+}
+
+impl Object {
+    /// address of the container, if it is still alive
+    fn address(&self) -> Option<usize> {
+        match self {
+            Object::List(list) => list.upgrade().map(|list| Rc::as_ptr(&list) as usize),
+            Object::Map(map) => map.upgrade().map(|map| Rc::as_ptr(&map) as usize),
+            Object::Instance(instance) => instance
+                .upgrade()
+                .map(|instance| Rc::as_ptr(&instance) as usize),
+        }
+    }
+
+    /// drop the values held by the container, breaking the cycles it is part of
+    fn clear(&self) {
+        match self {
+            Object::List(list) => {
+                if let Some(list) = list.upgrade() {
+                    list.elements.borrow_mut().clear();
+                }
+            }
+            Object::Map(map) => {
+                if let Some(map) = map.upgrade() {
+                    map.borrow_mut().clear();
+                }
+            }
+            Object::Instance(instance) => {
+                if let Some(instance) = instance.upgrade() {
+                    instance.borrow_mut().values.clear();
+                }
+            }
+        }
+    }
+}
+
+pub struct Heap {
+    objects: Vec<Object>,
+    // number of tracked containers from which the next collection is due
+    next_collection: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            next_collection: COLLECTION_MIN,
+        }
+    }
+
+    pub fn new_list(&mut self, elements: Vec<Value>) -> Rc<List> {
+        let list = Rc::new(List::new(elements));
+        self.objects.push(Object::List(Rc::downgrade(&list)));
+        list
+    }
+
+    // keys are checked by the VM before insertion, they can't be mutated through a `RefCell`
+    #[allow(clippy::mutable_key_type)]
+    pub fn new_map(
+        &mut self,
+        entries: HashMap<Value, Value>,
+    ) -> Rc<RefCell<HashMap<Value, Value>>> {
+        let map = Rc::new(RefCell::new(entries));
+        self.objects.push(Object::Map(Rc::downgrade(&map)));
+        map
+    }
+
+    pub fn new_instance(&mut self, instance: Instance) -> Rc<RefCell<Instance>> {
+        let instance = Rc::new(RefCell::new(instance));
+        self.objects
+            .push(Object::Instance(Rc::downgrade(&instance)));
+        instance
+    }
+
+    /// number of containers allocated and not known to be freed yet
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// whether enough containers were allocated since the last collection to run one
+    pub fn collection_due(&self) -> bool {
+        self.objects.len() >= self.next_collection
+    }
+
+    /// Free the containers not reachable from `roots`, which are only kept alive by
+    /// reference cycles, and return how many there were.
+    /// Every value still in use must be reachable from `roots`, or it may be emptied.
+    pub fn collect_garbage<'a>(&mut self, roots: impl Iterator<Item = &'a Value>) -> usize {
+        let mut marked = HashSet::new();
+        for value in roots {
+            mark(value, &mut marked);
+        }
+        let mut garbage = vec![];
+        for object in std::mem::take(&mut self.objects) {
+            match object.address() {
+                Some(address) if marked.contains(&address) => self.objects.push(object),
+                Some(_) => garbage.push(object),
+                // freed already
+                None => {}
+            }
+        }
+        let nb_collected = garbage.len();
+        for object in garbage {
+            object.clear();
+        }
+        self.next_collection = COLLECTION_MIN.max(2 * self.objects.len());
+        nb_collected
+    }
+}
+
+/// add the address of the containers reachable from `value` to `marked`
+fn mark(value: &Value, marked: &mut HashSet<usize>) {
+    let address = match value {
+        Value::List(list) => Rc::as_ptr(list) as usize,
+        Value::Map(map) => Rc::as_ptr(map) as usize,
+        Value::Instance(instance) => Rc::as_ptr(instance) as usize,
+        Value::BoundMethod(bound) => return mark(&bound.receiver, marked),
+        _ => return,
+    };
+    if !marked.insert(address) {
+        return;
+    }
+    match value {
+        Value::List(list) => {
+            for element in list.elements.borrow().iter() {
+                mark(element, marked);
+            }
+        }
+        Value::Map(map) => {
+            for (key, value) in map.borrow().iter() {
+                mark(key, marked);
+                mark(value, marked);
+            }
+        }
+        Value::Instance(instance) => {
+            for value in instance.borrow().values.iter() {
+                mark(value, marked);
+            }
+        }
+        _ => unreachable!("only containers are marked"),
+    }
+}
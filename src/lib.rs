@@ -1,7 +1,11 @@
 pub mod ast;
 pub mod chunk;
 pub mod compiler;
+pub mod gc;
+pub mod native;
 pub mod scanner;
 pub mod token;
+#[cfg(feature = "treewalk")]
+pub mod treewalk;
 pub mod value;
 pub mod vm;
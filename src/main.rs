@@ -1,88 +1,261 @@
 use rox::ast::parser::Parser;
+use rox::ast::Program;
 use rox::chunk::Chunk;
 use rox::compiler::Compiler;
 use rox::scanner::Scanner;
 use rox::vm::RuntimeError;
 use rox::vm::VM;
+use rustyline::DefaultEditor;
 use std::env;
 use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::path::Path;
 use std::process::exit;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() > 2 {
-        println!("Usage: rox [script]");
+    if args.len() == 3 && args[1] == "--emit-ast-json" {
+        emit_ast_json(&args[2]);
+    } else if args.len() == 3 && args[1] == "--repl-history" {
+        repl(Some(&args[2]));
+    } else if args.len() == 3 && args[1] == "--trace" {
+        run_file(&args[2], true);
+    } else if args.len() == 3 && args[1] == "--check" {
+        check_file(&args[2]);
+    } else if args.len() > 2 {
+        println!(
+            "Usage: rox [--emit-ast-json script | --repl-history file | --trace script | --check script | script]"
+        );
         exit(64);
     } else if args.len() == 2 {
-        run_file(&args[1]);
+        run_file(&args[1], false);
     } else {
-        repl();
+        repl(None);
     }
 }
 
-fn repl() {
+/// Each input is run on the same VM, so that globals are kept from one input to the next.
+/// The value of an input made of a lone expression is echoed.
+/// An input which ends too early, e.g. in the middle of a block, goes on over the next lines.
+/// When `history_file` is given, the history of the lines typed is kept there across sessions.
+fn repl(history_file: Option<&str>) {
+    let mut reader = LineReader::new(history_file);
+    let mut vm = VM::new();
+    let mut input = String::new();
     loop {
-        print!("> ");
-        io::stdout()
-            .flush()
-            .expect("Somethig went wrong when flushing IO");
-        let mut line = String::new();
-        io::stdin()
-            .read_line(&mut line)
-            .expect("Something went wrong when reading the line");
-        if line == "\n" {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        let Some(line) = reader.read_line(prompt) else {
+            break;
+        };
+        if input.is_empty() && line.trim().is_empty() {
             break;
         }
-        if !line.ends_with(";\n") {
-            line.insert(line.len() - 1, ';')
+        input.push_str(line.trim_end_matches('\n'));
+        input.push('\n');
+        // the final semicolon can be omitted
+        let trimmed = input.trim_end();
+        let source = if !trimmed.ends_with(';') && !trimmed.ends_with('}') {
+            format!("{};", trimmed)
+        } else {
+            input.clone()
+        };
+        let chunk = match compile(source, None) {
+            Ok(chunk) => chunk,
+            Err(_) if is_incomplete(&input) => continue,
+            Err(err) => {
+                println!("{}", err);
+                input.clear();
+                continue;
+            }
+        };
+        input.clear();
+        match vm.interpret(chunk) {
+            Ok(_) => {}
+            Err(RuntimeError::Error { msg, .. }) => println!("{}", msg),
+            Err(RuntimeError::Halt(code)) => {
+                reader.save_history();
+                halt(code);
+            }
         }
-        run(format!("print {}", line));
     }
+    reader.save_history();
 }
 
-fn run_file(filename: &str) {
-    let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
-    run(contents);
+/// whether `input` only fails to parse because it ends too early, in which case the
+/// REPL waits for more lines
+fn is_incomplete(input: &str) -> bool {
+    let Ok(tokens) = Scanner::new(input.to_string()).scan_tokens() else {
+        return false;
+    };
+    match Parser::new(tokens).parse() {
+        Ok(_) => false,
+        Err(errors) => errors.iter().all(|err| err.at_end()),
+    }
 }
 
-/// source processing pipeline
-/// 1. scan
-/// 2. parse
-/// 3. compile to bytecode chunk
-/// 4. vm execs bytecode chunk
-fn run(source: String) {
-    // FIXME: proper error handling
-    let scanner = Scanner::new(source);
-    let tokens = scanner.scan_tokens();
-    if let Err(errors) = tokens {
-        let str_errors = errors.iter().map(|err| format!("{:?}", err));
-        println!("{}", str_errors.collect::<Vec<String>>().join("\n"));
-        exit(65);
+/// Lines typed in the REPL are read with a line editor when they come from a terminal,
+/// and plainly otherwise, e.g. when the input is piped.
+enum LineReader<'a> {
+    Editor {
+        editor: Box<DefaultEditor>,
+        history_file: Option<&'a str>,
+    },
+    Plain,
+}
+
+impl<'a> LineReader<'a> {
+    fn new(history_file: Option<&'a str>) -> Self {
+        if !io::stdin().is_terminal() {
+            return LineReader::Plain;
+        }
+        match DefaultEditor::new() {
+            Ok(mut editor) => {
+                if let Some(history_file) = history_file {
+                    // the file doesn't exist before the first session
+                    let _ = editor.load_history(history_file);
+                }
+                LineReader::Editor {
+                    editor: Box::new(editor),
+                    history_file,
+                }
+            }
+            Err(_) => LineReader::Plain,
+        }
     }
 
-    let mut parser = Parser::new(tokens.expect("Expected successful scan"));
-    let program_ast = parser.parse();
-    if let Err(error) = program_ast {
-        println!("{:?}", error);
-        exit(65);
+    /// the next line, or None once the input is exhausted
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        match self {
+            LineReader::Editor { editor, .. } => {
+                // Ctrl-C and Ctrl-D end the session as well
+                let line = editor.readline(prompt).ok()?;
+                let _ = editor.add_history_entry(line.as_str());
+                Some(line)
+            }
+            LineReader::Plain => {
+                print!("{}", prompt);
+                io::stdout()
+                    .flush()
+                    .expect("Somethig went wrong when flushing IO");
+                let mut line = String::new();
+                match io::stdin().read_line(&mut line) {
+                    Ok(0) | Err(_) => None,
+                    Ok(_) => Some(line),
+                }
+            }
+        }
     }
 
-    let mut chunk = Chunk::new();
-    let mut compiler = Compiler::new(&mut chunk);
-    let compilation_result = compiler.run(program_ast.expect("Expected successful parse"));
-    if let Err(err) = compilation_result {
-        println!("{}", err);
-        exit(65);
+    fn save_history(&mut self) {
+        if let LineReader::Editor {
+            editor,
+            history_file: Some(history_file),
+        } = self
+        {
+            if let Err(err) = editor.save_history(history_file) {
+                println!("Couldn't save the REPL history: {}", err);
+            }
+        }
     }
+}
 
+/// With `trace`, the execution is traced on stderr.
+fn run_file(filename: &str, trace: bool) {
+    let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
+    let chunk = match compile(contents, Some(filename)) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            println!("{}", err);
+            exit(65);
+        }
+    };
     let mut vm = VM::new();
+    // without the flag, keep the default, which traces in debugTraceExecution builds
+    if trace {
+        vm.set_trace(true);
+    }
     match vm.interpret(chunk) {
-        Err(RuntimeError { msg }) => {
+        Ok(_) => {}
+        Err(RuntimeError::Error { msg, .. }) => {
             println!("{}", msg);
             exit(70);
         }
-        Ok(()) => {}
+        Err(RuntimeError::Halt(code)) => halt(code),
+    }
+}
+
+/// report the errors found compiling a script, without running it, for editors
+fn check_file(filename: &str) {
+    let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
+    if let Err(err) = compile(contents, Some(filename)) {
+        println!("{}", err);
+        exit(65);
+    }
+}
+
+fn halt(code: i32) -> ! {
+    // process::exit doesn't flush, and `write` leaves the line unterminated
+    io::stdout()
+        .flush()
+        .expect("Something went wrong when flushing IO");
+    exit(code);
+}
+
+/// print the AST of a script as JSON, for tooling
+#[cfg(feature = "astJson")]
+fn emit_ast_json(filename: &str) {
+    let contents = fs::read_to_string(filename).expect("Something went wrong reading the file");
+    let program_ast = match parse(contents) {
+        Ok(program_ast) => program_ast,
+        Err(err) => {
+            println!("{}", err);
+            exit(65);
+        }
+    };
+    let json = serde_json::to_string(&program_ast).expect("Couldn't serialize the AST");
+    println!("{}", json);
+}
+
+#[cfg(not(feature = "astJson"))]
+fn emit_ast_json(_filename: &str) {
+    println!("rox was built without the astJson feature");
+    exit(64);
+}
+
+/// source processing pipeline
+/// 1. scan
+/// 2. parse
+/// 3. compile to bytecode chunk
+///
+/// then the vm execs the bytecode chunk.
+/// `filename` is the script compiled, which imports are relative to. Without one, the
+/// source was typed in the REPL, which prints the value of a lone expression statement.
+fn compile(source: String, filename: Option<&str>) -> Result<Chunk, String> {
+    let program_ast = parse(source)?;
+    let mut chunk = Chunk::new();
+    let mut compiler = match filename {
+        Some(filename) => Compiler::new_file(&mut chunk, Path::new(filename)),
+        None => Compiler::new_repl(&mut chunk),
+    };
+    compiler.run(program_ast)?;
+    Ok(chunk)
+}
+
+/// scan and parse the source, returning the errors to report otherwise.
+/// Warnings are printed on stderr right away.
+fn parse(source: String) -> Result<Program, String> {
+    let scanner = Scanner::new(source);
+    let (tokens, warnings) = scanner.scan_tokens_with_warnings();
+    for warning in warnings {
+        eprintln!("{}", warning);
     }
+    let tokens = tokens.map_err(|errors| {
+        let str_errors = errors.iter().map(|err| err.to_string());
+        str_errors.collect::<Vec<String>>().join("\n")
+    })?;
+    Parser::new(tokens).parse().map_err(|errors| {
+        let str_errors = errors.iter().map(|err| err.to_string());
+        str_errors.collect::<Vec<String>>().join("\n")
+    })
 }
@@ -0,0 +1,572 @@
+//! Functions implemented in Rust, exposed to scripts as globals by the VM.
+//! Argument count is checked by the VM before calling them, according to their arity.
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::value::{Instance, NativeError, Value};
+use crate::vm::VM;
+
+pub fn len(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let len = match &args[0] {
+        Value::Str(s) => s.chars().count(),
+        Value::List(list) => list.elements.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::Bytes(bytes) => bytes.borrow().len(),
+        _ => return Err("len() expects a string, a list, a map or bytes".into()),
+    };
+    Ok(Value::Number(len as f64))
+}
+
+/// The order of the keys is unspecified.
+pub fn keys(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Map(map) => {
+            let keys = map.borrow().keys().cloned().collect();
+            Ok(Value::List(vm.new_list(keys)))
+        }
+        _ => Err("keys() expects a map".into()),
+    }
+}
+
+/// The order of the values is unspecified, but matches the one of `keys`
+/// as long as the map isn't modified in between.
+pub fn values(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Map(map) => {
+            let values = map.borrow().values().cloned().collect();
+            Ok(Value::List(vm.new_list(values)))
+        }
+        _ => Err("values() expects a map".into()),
+    }
+}
+
+/// Print a value without appending a newline.
+pub fn write(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let printed = vm.show(&args[0]);
+    vm.write_output(&printed);
+    Ok(Value::Nil)
+}
+
+// significant digits an f64 holds at most, any further one being noise
+const PRECISION_MAX: usize = 17;
+
+/// `setPrecision(n)` rounds the decimals of numbers printed or formatted to `n` digits,
+/// `setPrecision(null)` prints them in full again.
+pub fn set_precision(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(n) => {
+            let precision = as_index(*n, "setPrecision()")?;
+            if precision > PRECISION_MAX {
+                let msg = format!("setPrecision() expects at most {} digits", PRECISION_MAX);
+                return Err(msg.into());
+            }
+            vm.set_precision(Some(precision))
+        }
+        Value::Nil => vm.set_precision(None),
+        _ => return Err("setPrecision() expects a number or null".into()),
+    }
+    Ok(Value::Nil)
+}
+
+/// Print a value on the error output, to keep diagnostics apart from the output.
+pub fn eprint(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let text = format!("{}\n", vm.show(&args[0]));
+    vm.write_error_output(&text);
+    Ok(Value::Nil)
+}
+
+/// Stop the script, handing `code` over to the host.
+pub fn exit(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(code) if code.fract() == 0.0 && i32::try_from(*code as i64).is_ok() => {
+            Err(NativeError::Halt(*code as i32))
+        }
+        _ => Err("exit() expects an integer code".into()),
+    }
+}
+
+/// Seconds elapsed since the Unix epoch.
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| err.to_string())?;
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+pub fn is_nan(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(n) => Ok(Value::Boolean(n.is_nan())),
+        _ => Err("isNan() expects a number".into()),
+    }
+}
+
+pub fn type_of(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(Value::Str(args[0].type_name().into()))
+}
+
+/// Raise an error when the condition is falsey, with an optional message.
+pub fn assert(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    if !args[0].is_falsey() {
+        return Ok(Value::Nil);
+    }
+    match args.get(1) {
+        Some(message) => Err(format!("Assertion failed: {}", message).into()),
+        None => Err("Assertion failed.".into()),
+    }
+}
+
+pub fn min(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a.min(*b))),
+        (Value::Str(a), Value::Str(b)) => Ok(Value::Str(a.min(b).clone())),
+        _ => Err("min() expects two numbers or two strings".into()),
+    }
+}
+
+pub fn max(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a.max(*b))),
+        (Value::Str(a), Value::Str(b)) => Ok(Value::Str(a.max(b).clone())),
+        _ => Err("max() expects two numbers or two strings".into()),
+    }
+}
+
+/// `sum(...)`, the total of any number of numbers, 0 when there is none.
+pub fn sum(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let mut total = 0.0;
+    for arg in args {
+        match arg {
+            Value::Number(n) => total += n,
+            _ => return Err("sum() expects numbers".into()),
+        }
+    }
+    Ok(Value::Number(total))
+}
+
+/// `format(template, ...)`, the template with each `{}` replaced by the next argument.
+/// `{{` and `}}` stand for literal braces.
+pub fn format(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let Value::Str(template) = &args[0] else {
+        return Err("format() expects a string template".into());
+    };
+    let mut arguments = args[1..].iter();
+    let mut nb_placeholders = 0;
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                result.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                nb_placeholders += 1;
+                if let Some(argument) = arguments.next() {
+                    result.push_str(&vm.show(argument));
+                }
+            }
+            ('{', _) | ('}', _) => {
+                return Err(format!("format() found an unmatched '{}' in its template", c).into())
+            }
+            _ => result.push(c),
+        }
+    }
+    if nb_placeholders != args.len() - 1 {
+        return Err(format!(
+            "format() expects {} arguments for its placeholders, got {}",
+            nb_placeholders,
+            args.len() - 1
+        )
+        .into());
+    }
+    Ok(Value::Str(result.into()))
+}
+
+/// `substring(s, start, len)`, indices count characters and not bytes.
+pub fn substring(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1], &args[2]) {
+        (Value::Str(s), Value::Number(start), Value::Number(len)) => {
+            let start = as_index(*start, "substring()")?;
+            let len = as_index(*len, "substring()")?;
+            let length = s.chars().count();
+            if start.checked_add(len).is_none_or(|end| end > length) {
+                return Err(format!(
+                    "substring() range {}..{} out of bounds for a string of length {}",
+                    start,
+                    // doesn't overflow, unlike the sum of two usize
+                    start as u128 + len as u128,
+                    length
+                )
+                .into());
+            }
+            let substring: String = s.chars().skip(start).take(len).collect();
+            Ok(Value::Str(substring.into()))
+        }
+        _ => Err("substring() expects a string and two numbers".into()),
+    }
+}
+
+/// index of the first occurence of `needle` in `s`, or -1 if absent.
+pub fn index_of(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Str(s), Value::Str(needle)) => {
+            let index = match s.find(&**needle) {
+                Some(byte_index) => s[..byte_index].chars().count() as f64,
+                None => -1.0,
+            };
+            Ok(Value::Number(index))
+        }
+        _ => Err("indexOf() expects two strings".into()),
+    }
+}
+
+pub fn to_upper(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Str(s) => Ok(Value::Str(s.to_uppercase().into())),
+        _ => Err("toUpper() expects a string".into()),
+    }
+}
+
+pub fn to_lower(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Str(s) => Ok(Value::Str(s.to_lowercase().into())),
+        _ => Err("toLower() expects a string".into()),
+    }
+}
+
+/// List of the characters of a string.
+pub fn chars(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Str(s) => {
+            let chars = s.chars().map(Value::Char).collect();
+            Ok(Value::List(vm.new_list(chars)))
+        }
+        _ => Err("chars() expects a string".into()),
+    }
+}
+
+/// `split(s, sep)`, list of the parts of `s` separated by `sep`.
+pub fn split(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Str(_), Value::Str(sep)) if sep.is_empty() => {
+            Err("split() separator can't be empty, use chars() instead".into())
+        }
+        (Value::Str(s), Value::Str(sep)) => {
+            let parts = s
+                .split(&**sep)
+                .map(|part| Value::Str(part.into()))
+                .collect();
+            Ok(Value::List(vm.new_list(parts)))
+        }
+        _ => Err("split() expects two strings".into()),
+    }
+}
+
+/// `join(list, sep)`, the strings or characters of `list` separated by `sep`.
+pub fn join(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let (Value::List(list), Value::Str(sep)) = (&args[0], &args[1]) else {
+        return Err("join() expects a list and a string".into());
+    };
+    let mut parts = vec![];
+    for element in list.elements.borrow().iter() {
+        match element {
+            Value::Str(s) => parts.push(s.to_string()),
+            Value::Char(c) => parts.push(c.to_string()),
+            _ => {
+                return Err(format!(
+                    "join() expects a list of strings, got a {}",
+                    element.type_name()
+                )
+                .into())
+            }
+        }
+    }
+    Ok(Value::Str(parts.join(sep).into()))
+}
+
+/// `repeat(s, n)`, the string `s` repeated `n` times.
+pub fn repeat(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Str(s), Value::Number(n)) => {
+            let n = as_index(*n, "repeat()")?;
+            match s.len().checked_mul(n) {
+                Some(size) if size <= SIZE_MAX => Ok(Value::Str(s.repeat(n).into())),
+                _ => Err(format!(
+                    "repeat() can't build a string of more than {} bytes",
+                    SIZE_MAX
+                )
+                .into()),
+            }
+        }
+        _ => Err("repeat() expects a string and a number".into()),
+    }
+}
+
+// size in bytes of the largest string or buffer a native builds, asking for more
+// being a bug rather than a need
+const SIZE_MAX: usize = 1 << 30;
+
+/// `bytes(len)`, a buffer of `len` zeroed bytes.
+pub fn bytes(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::Number(len) => {
+            let len = as_index(*len, "bytes()")?;
+            if len > SIZE_MAX {
+                return Err(format!("bytes() can't allocate more than {} bytes", SIZE_MAX).into());
+            }
+            Ok(Value::Bytes(Rc::new(RefCell::new(vec![0; len]))))
+        }
+        _ => Err("bytes() expects a number".into()),
+    }
+}
+
+/// `byteGet(buf, i)`, the byte at index `i` as a number.
+pub fn byte_get(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1]) {
+        (Value::Bytes(bytes), Value::Number(index)) => {
+            let bytes = bytes.borrow();
+            let index = byte_index(*index, bytes.len(), "byteGet()")?;
+            Ok(Value::Number(bytes[index] as f64))
+        }
+        _ => Err("byteGet() expects bytes and a number".into()),
+    }
+}
+
+/// `byteSet(buf, i, v)`, where `v` is an integer between 0 and 255.
+pub fn byte_set(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match (&args[0], &args[1], &args[2]) {
+        (Value::Bytes(bytes), Value::Number(index), Value::Number(value)) => {
+            let mut bytes = bytes.borrow_mut();
+            let index = byte_index(*index, bytes.len(), "byteSet()")?;
+            if value.fract() != 0.0 || !(0.0..=255.0).contains(value) {
+                return Err(format!("byteSet() expects a byte value, got {}", value).into());
+            }
+            bytes[index] = *value as u8;
+            Ok(Value::Nil)
+        }
+        _ => Err("byteSet() expects bytes and two numbers".into()),
+    }
+}
+
+/// Unicode code point of a character, or of the first character of a string.
+pub fn ord(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let c = match &args[0] {
+        Value::Char(c) => *c,
+        Value::Str(s) => s
+            .chars()
+            .next()
+            .ok_or("ord() expects a non empty string")?,
+        _ => return Err("ord() expects a character or a string".into()),
+    };
+    Ok(Value::Number(c as u32 as f64))
+}
+
+/// One character string for a Unicode code point.
+pub fn chr(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let c = match &args[0] {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n <= u32::MAX as f64 => {
+            char::from_u32(*n as u32)
+        }
+        _ => None,
+    };
+    match c {
+        Some(c) => Ok(Value::Str(c.to_string().into())),
+        None => Err(format!("chr() expects a valid code point, got {}", args[0]).into()),
+    }
+}
+
+/// Deep copy of lists, maps, bytes and instances. Immutable values are returned as is.
+pub fn clone(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    Ok(deep_copy(vm, &args[0], &mut HashMap::new()))
+}
+
+/// Names of the global variables, natives included, for introspection.
+pub fn globals(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+    let names = vm.global_names().into_iter().map(Value::Str).collect();
+    Ok(Value::List(vm.new_list(names)))
+}
+
+/// New list made of the results of `function` called on each element.
+pub fn map(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let elements = list_elements(&args[0], "map")?;
+    let mut results = Vec::with_capacity(elements.len());
+    for element in elements {
+        results.push(vm.call(args[1].clone(), &[element])?);
+    }
+    Ok(Value::List(vm.new_list(results)))
+}
+
+/// New list made of the elements for which `predicate` returns a truthy value.
+pub fn filter(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let elements = list_elements(&args[0], "filter")?;
+    let mut results = Vec::new();
+    for element in elements {
+        if !vm.call(args[1].clone(), std::slice::from_ref(&element))?.is_falsey() {
+            results.push(element);
+        }
+    }
+    Ok(Value::List(vm.new_list(results)))
+}
+
+/// Fold the elements from the left, `function` being given the accumulator and an element.
+pub fn reduce(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let elements = list_elements(&args[0], "reduce")?;
+    let mut accumulator = args[2].clone();
+    for element in elements {
+        accumulator = vm.call(args[1].clone(), &[accumulator, element])?;
+    }
+    Ok(accumulator)
+}
+
+/// New list with the elements in ascending order. Without a comparator, the elements must
+/// all be numbers, strings or characters. A comparator is given two elements and returns a
+/// negative number, zero or a positive number when the first goes before, alongside or
+/// after the second. The sort is stable.
+pub fn sort(vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    let elements = list_elements(&args[0], "sort")?;
+    let sorted = match args.get(1) {
+        None => merge_sort(elements, &mut |a, b| match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(a.total_cmp(b)),
+            (Value::Str(a), Value::Str(b)) => Ok(a.cmp(b)),
+            (Value::Char(a), Value::Char(b)) => Ok(a.cmp(b)),
+            _ => Err("sort() expects numbers, strings or characters of a single type".into()),
+        })?,
+        Some(comparator) => merge_sort(elements, &mut |a, b| {
+            match vm.call(comparator.clone(), &[a.clone(), b.clone()])? {
+                Value::Number(n) if n < 0.0 => Ok(Ordering::Less),
+                Value::Number(n) if n > 0.0 => Ok(Ordering::Greater),
+                Value::Number(_) => Ok(Ordering::Equal),
+                _ => Err("sort() expects the comparator to return a number".into()),
+            }
+        })?,
+    };
+    Ok(Value::List(vm.new_list(sorted)))
+}
+
+// unlike slice::sort_by, the comparison can fail, and an inconsistent one doesn't panic
+fn merge_sort(
+    mut elements: Vec<Value>,
+    compare: &mut dyn FnMut(&Value, &Value) -> Result<Ordering, NativeError>,
+) -> Result<Vec<Value>, NativeError> {
+    if elements.len() <= 1 {
+        return Ok(elements);
+    }
+    let right = elements.split_off(elements.len() / 2);
+    let left = merge_sort(elements, compare)?;
+    let right = merge_sort(right, compare)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // taking from the left on ties keeps the sort stable
+        if compare(a, b)? == Ordering::Greater {
+            merged.extend(right.next());
+        } else {
+            merged.extend(left.next());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+/// Mark the list as frozen and return it: its elements can no longer be assigned.
+/// Freezing is shallow, and `clone` gives back a mutable copy.
+pub fn freeze(_vm: &mut VM, args: &[Value]) -> Result<Value, NativeError> {
+    match &args[0] {
+        Value::List(list) => {
+            list.frozen.set(true);
+            Ok(args[0].clone())
+        }
+        _ => Err("freeze() expects a list".into()),
+    }
+}
+
+/// Snapshot of the elements of a list, which the functions called back may mutate.
+fn list_elements(value: &Value, function_name: &str) -> Result<Vec<Value>, String> {
+    match value {
+        Value::List(list) => Ok(list.elements.borrow().clone()),
+        _ => Err(format!("{}() expects a list", function_name)),
+    }
+}
+
+/// `copies` maps the address of each container already copied to its copy, so that
+/// shared and cyclic references are kept as such in the result.
+fn deep_copy(vm: &mut VM, value: &Value, copies: &mut HashMap<usize, Value>) -> Value {
+    let address = match value {
+        Value::List(list) => Rc::as_ptr(list) as usize,
+        Value::Map(map) => Rc::as_ptr(map) as usize,
+        Value::Bytes(bytes) => Rc::as_ptr(bytes) as usize,
+        Value::Instance(instance) => Rc::as_ptr(instance) as usize,
+        _ => return value.clone(),
+    };
+    if let Some(copy) = copies.get(&address) {
+        return copy.clone();
+    }
+    match value {
+        Value::List(list) => {
+            // the copy is a mutable list, even when the original is frozen
+            let copy = vm.new_list(Vec::new());
+            copies.insert(address, Value::List(copy.clone()));
+            for element in list.elements.borrow().iter() {
+                let element = deep_copy(vm, element, copies);
+                copy.elements.borrow_mut().push(element);
+            }
+            Value::List(copy)
+        }
+        Value::Map(map) => {
+            let copy = vm.new_map(HashMap::new());
+            copies.insert(address, Value::Map(copy.clone()));
+            // keys are immutable, only values need a copy
+            for (key, value) in map.borrow().iter() {
+                let value = deep_copy(vm, value, copies);
+                copy.borrow_mut().insert(key.clone(), value);
+            }
+            Value::Map(copy)
+        }
+        Value::Bytes(bytes) => {
+            let copy = Value::Bytes(Rc::new(RefCell::new(bytes.borrow().clone())));
+            copies.insert(address, copy.clone());
+            copy
+        }
+        Value::Instance(instance) => {
+            let struct_type = instance.borrow().struct_type.clone();
+            let copy = vm.new_instance(Instance {
+                struct_type,
+                values: Vec::new(),
+            });
+            copies.insert(address, Value::Instance(copy.clone()));
+            for value in instance.borrow().values.iter() {
+                let value = deep_copy(vm, value, copies);
+                copy.borrow_mut().values.push(value);
+            }
+            Value::Instance(copy)
+        }
+        _ => unreachable!("only containers have an address"),
+    }
+}
+
+fn byte_index(n: f64, len: usize, function_name: &str) -> Result<usize, String> {
+    let index = as_index(n, function_name)?;
+    if index >= len {
+        return Err(format!(
+            "{} index {} out of bounds for bytes of length {}",
+            function_name, index, len
+        ));
+    }
+    Ok(index)
+}
+
+fn as_index(n: f64, function_name: &str) -> Result<usize, String> {
+    if n.fract() != 0.0 || n < 0.0 {
+        return Err(format!(
+            "{} expects a non negative integer, got {}",
+            function_name, n
+        ));
+    }
+    Ok(n as usize)
+}
@@ -1,41 +1,54 @@
 use crate::token::{Token, TokenType};
+use std::fmt;
 
 pub struct Scanner {
-    source: String,
+    // indexed by character, not by byte, so that non ASCII source is handled
+    source: Vec<char>,
     tokens: Vec<Token>,
     errors: Vec<ScannerError>,
-    start: u16,
-    current: u16,
+    warnings: Vec<ScannerWarning>,
+    start: usize,
+    current: usize,
     line: u16,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
         Self {
-            source,
+            source: source.chars().collect(),
             tokens: vec![],
             errors: vec![],
+            warnings: vec![],
             start: 0,
             current: 0,
             line: 1,
         }
     }
 
-    pub fn scan_tokens(mut self) -> Result<Vec<Token>, Vec<ScannerError>> {
+    pub fn scan_tokens(self) -> Result<Vec<Token>, Vec<ScannerError>> {
+        self.scan_tokens_with_warnings().0
+    }
+
+    /// scan the tokens, along with the warnings about source that is valid but likely
+    /// not to do what is meant
+    #[allow(clippy::type_complexity)]
+    pub fn scan_tokens_with_warnings(
+        mut self,
+    ) -> (Result<Vec<Token>, Vec<ScannerError>>, Vec<ScannerWarning>) {
         while !self.is_at_end() {
             // we are at the beginning of the next lexeme
             self.start = self.current;
             self.scan_token();
         }
-        if self.errors.len() > 0 {
-            return Err(self.errors);
+        if !self.errors.is_empty() {
+            return (Err(self.errors), self.warnings);
         }
         self.tokens.push(Token {
             typ: TokenType::Eof,
             lexeme: String::from(""),
             line: self.line,
         });
-        Ok(self.tokens)
+        (Ok(self.tokens), self.warnings)
     }
 
     fn scan_token(&mut self) {
@@ -45,11 +58,21 @@ impl Scanner {
             ')' => self.add_token(TokenType::RightParen),
             '{' => self.add_token(TokenType::LeftBrace),
             '}' => self.add_token(TokenType::RightBrace),
+            '[' => self.add_token(TokenType::LeftBracket),
+            ']' => self.add_token(TokenType::RightBracket),
             ',' => self.add_token(TokenType::Comma),
-            '.' => self.add_token(TokenType::Dot),
+            '.' => {
+                let token_type = if self.current_match('.') {
+                    TokenType::DotDot
+                } else {
+                    TokenType::Dot
+                };
+                self.add_token(token_type);
+            }
             '-' => self.add_token(TokenType::Minus),
             '+' => self.add_token(TokenType::Plus),
             ';' => self.add_token(TokenType::Semicolon),
+            ':' => self.add_token(TokenType::Colon),
             '*' => self.add_token(TokenType::Star),
             '!' => {
                 let token_type = if self.current_match('=') {
@@ -93,13 +116,22 @@ impl Scanner {
                     self.add_token(TokenType::Slash);
                 }
             }
-            '"' => self.string(),
+            '~' if self.peek() == '/' => {
+                self.advance();
+                self.add_token(TokenType::TildeSlash);
+            }
+            '"' => self.string(false),
+            'r' if self.peek() == '"' => {
+                self.advance();
+                self.string(true);
+            }
+            '\'' => self.character(),
             ' ' => (),
             '\t' => (),
             '\r' => (),
             '\n' => self.increment_line(),
             _ => {
-                if c.is_digit(10) {
+                if c.is_ascii_digit() {
                     self.number();
                 } else if c.is_alphabetic() {
                     self.identifier();
@@ -111,35 +143,29 @@ impl Scanner {
     }
 
     fn add_token(&mut self, typ: TokenType) {
-        let text = &self.source[self.start as usize..self.current as usize];
+        let lexeme = self.lexeme();
         self.tokens.push(Token {
             typ,
-            lexeme: text.to_string(),
+            lexeme,
             line: self.line,
         });
     }
 
+    /// text of the current lexeme
+    fn lexeme(&self) -> String {
+        self.source[self.start..self.current].iter().collect()
+    }
+
     fn peek(&self) -> char {
         // FIXME: write a test for end of file case
         if self.is_at_end() {
             return '\0';
         }
-        self.source
-            .chars()
-            .nth(self.current as usize)
-            .expect("Couldn't peek character from source")
+        self.source[self.current]
     }
 
     fn peek_next(&self) -> char {
-        // NOTE: not great, duplicating code from peek
-        // one alternative is to do `current++, peek, current--`, but it would require to declare self as mutable
-        if self.current as usize + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source
-            .chars()
-            .nth(self.current as usize + 1)
-            .expect("Couldn't peek character from source")
+        self.source.get(self.current + 1).copied().unwrap_or('\0')
     }
 
     fn advance(&mut self) -> char {
@@ -149,14 +175,38 @@ impl Scanner {
     }
 
     fn is_at_end(&self) -> bool {
-        self.current as usize >= self.source.len()
+        self.current >= self.source.len()
     }
 
+    /// record an error located at the start of the current lexeme
     fn add_error(&mut self, message: String) {
-        self.errors.push(ScannerError {
+        let error = self.diagnostic(message);
+        self.errors.push(error);
+    }
+
+    fn add_warning(&mut self, message: String) {
+        let warning = ScannerWarning(self.diagnostic(message));
+        self.warnings.push(warning);
+    }
+
+    /// `message` about the current lexeme, located in the source
+    fn diagnostic(&self, message: String) -> ScannerError {
+        let chars = &self.source;
+        let start = self.start;
+        let line_start = chars[..start]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |index| index + 1);
+        let line_end = chars[start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(chars.len(), |index| start + index);
+        ScannerError {
             message,
             line: self.line,
-        });
+            column: (start - line_start + 1) as u16,
+            excerpt: chars[line_start..line_end].iter().collect(),
+        }
     }
 
     fn increment_line(&mut self) {
@@ -175,22 +225,32 @@ impl Scanner {
         true
     }
 
-    fn string(&mut self) {
-        let mut c = self.advance();
+    /// a string literal, whose backslashes are copied verbatim if it is raw, like `r"C:\new"`
+    fn string(&mut self, raw: bool) {
+        let mut string_literal = String::new();
         let mut nb_lines = 0;
-        while c != '"' {
+        loop {
             if self.is_at_end() {
                 self.add_error(String::from("Unterminated string."));
                 break;
             }
-            if c == '\n' {
-                nb_lines += 1;
+            match self.advance() {
+                '"' => break,
+                '\\' if !raw => {
+                    if let Some(c) = self.escape_sequence() {
+                        string_literal.push(c);
+                    }
+                }
+                c => {
+                    if c == '\n' {
+                        nb_lines += 1;
+                    }
+                    string_literal.push(c);
+                }
             }
-            c = self.advance();
         }
 
-        let string_literal = &self.source[self.start as usize + 1..self.current as usize - 1];
-        let token_type = TokenType::Str(string_literal.to_string());
+        let token_type = TokenType::Str(string_literal);
         self.add_token(token_type);
         // multi line string: need to increment the lines after recording the token, because we want the string to be recorded
         // with line where it started.
@@ -199,41 +259,193 @@ impl Scanner {
         }
     }
 
+    /// a single character between single quotes, like `'a'` or `'\n'`
+    fn character(&mut self) {
+        let mut characters = vec![];
+        loop {
+            if self.is_at_end() || self.peek() == '\n' {
+                self.add_error(String::from("Unterminated character literal."));
+                return;
+            }
+            match self.advance() {
+                '\'' => break,
+                '\\' => match self.escape_sequence() {
+                    Some(c) => characters.push(c),
+                    None if self.is_at_end() => continue,
+                    // already reported
+                    None => return,
+                },
+                c => characters.push(c),
+            }
+        }
+        match characters[..] {
+            [c] => self.add_token(TokenType::Char(c)),
+            _ => self.add_error(String::from(
+                "Character literal must contain exactly one character.",
+            )),
+        }
+    }
+
+    /// decode the escape sequence following a backslash in a string
+    fn escape_sequence(&mut self) -> Option<char> {
+        if self.is_at_end() {
+            // nothing left to escape: the caller reports the unterminated literal
+            return None;
+        }
+        match self.advance() {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '0' => Some('\0'),
+            '\\' => Some('\\'),
+            '"' => Some('"'),
+            '\'' => Some('\''),
+            'u' => self.unicode_escape_sequence(),
+            c => {
+                self.add_error(format!("Invalid escape sequence: \\{}.", c));
+                None
+            }
+        }
+    }
+
+    /// decode `\u{XXXX}`, where XXXX is an hexadecimal unicode code point
+    fn unicode_escape_sequence(&mut self) -> Option<char> {
+        if !self.current_match('{') {
+            self.add_error(String::from("Expect '{' after \\u."));
+            return None;
+        }
+        let mut digits = String::new();
+        while self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+        if !self.current_match('}') || digits.is_empty() || digits.len() > 6 {
+            self.add_error(String::from(
+                "Invalid unicode escape sequence, expect 1 to 6 hexadecimal digits between braces.",
+            ));
+            return None;
+        }
+        let code_point = u32::from_str_radix(&digits, 16).expect("Expected hexadecimal digits");
+        let c = char::from_u32(code_point);
+        if c.is_none() {
+            self.add_error(format!("Invalid unicode code point: {}.", digits));
+        }
+        c
+    }
+
     fn number(&mut self) {
-        while self.peek().is_digit(10) {
-            self.advance();
+        if self.lexeme() == "0" {
+            let radix = match self.peek() {
+                'x' => Some((16, "hexadecimal")),
+                'b' => Some((2, "binary")),
+                'o' => Some((8, "octal")),
+                _ => None,
+            };
+            if let Some((radix, radix_name)) = radix {
+                self.advance(); // discard radix prefix
+                return self.radix_number(radix, radix_name);
+            }
         }
+        self.digits();
         // if there is a dot followed by a digit, then we have a float
         // else it's an int followed by a dot.
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
-            while self.peek().is_digit(10) {
+            self.digits();
+        }
+        if self.peek() == 'e' || self.peek() == 'E' {
+            self.advance();
+            if self.peek() == '+' || self.peek() == '-' {
                 self.advance();
             }
+            if !self.peek().is_ascii_digit() {
+                let lexeme = self.lexeme();
+                self.add_error(format!("Expect digits in exponent of number: {}.", lexeme));
+                return;
+            }
+            self.digits();
         }
-        let string_number = &self.source[self.start as usize..self.current as usize];
+        let lexeme = self.lexeme();
+        if !separators_well_placed(&lexeme, |c| c.is_ascii_digit()) {
+            self.add_error(format!("Misplaced '_' in number: {}.", lexeme));
+            return;
+        }
+        let string_number = lexeme.replace('_', "");
         let literal_value = string_number
             .parse::<f64>()
-            .expect(&format!("Could not parse float: {}", string_number));
+            .unwrap_or_else(|_| panic!("Could not parse float: {}", string_number));
+        if string_number.bytes().all(|c| c.is_ascii_digit()) {
+            // beyond u128, the literal is way past 2^53 anyway
+            let exact = string_number
+                .parse::<u128>()
+                .is_ok_and(|integer| literal_value as u128 == integer);
+            if !exact {
+                self.warn_inexact_integer(literal_value);
+            }
+        }
         self.add_token(TokenType::Number(literal_value));
     }
 
+    /// integers beyond 2^53 are not all representable as numbers, they are rounded
+    fn warn_inexact_integer(&mut self, value: f64) {
+        let lexeme = self.lexeme();
+        self.add_warning(format!(
+            "Integer literal {} can't be represented exactly, it is rounded to {}.",
+            lexeme, value
+        ));
+    }
+
+    /// integer literal like `0xFF`, the prefix has already been consumed
+    fn radix_number(&mut self, radix: u32, radix_name: &str) {
+        let digits_start = self.current;
+        // invalid digits are consumed as well, to be reported
+        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+        let digits: String = self.source[digits_start..self.current].iter().collect();
+        if !separators_well_placed(&digits, |c| c.is_ascii_alphanumeric()) {
+            let lexeme = self.lexeme();
+            self.add_error(format!("Misplaced '_' in number: {}.", lexeme));
+            return;
+        }
+        match u64::from_str_radix(&digits.replace('_', ""), radix) {
+            Ok(value) => {
+                if value as f64 as u128 != value as u128 {
+                    self.warn_inexact_integer(value as f64);
+                }
+                self.add_token(TokenType::Number(value as f64))
+            }
+            Err(_) => {
+                let lexeme = self.lexeme();
+                self.add_error(format!("Invalid {} literal: {}.", radix_name, lexeme));
+            }
+        }
+    }
+
+    /// decimal digits, possibly separated by `_`
+    fn digits(&mut self) {
+        while self.peek().is_ascii_digit() || self.peek() == '_' {
+            self.advance();
+        }
+    }
+
     fn identifier(&mut self) {
         while self.peek().is_alphanumeric() {
             self.advance();
         }
-        // FIXME: duplicated with code in add_token
-        let identifier = &self.source[self.start as usize..self.current as usize];
-        match identifier {
+        let identifier = self.lexeme();
+        match identifier.as_str() {
             // reserved keywords
             "and" => self.add_token(TokenType::And),
             "not" => self.add_token(TokenType::Not),
             "struct" => self.add_token(TokenType::Struct),
+            "continue" => self.add_token(TokenType::Continue),
+            "do" => self.add_token(TokenType::Do),
             "else" => self.add_token(TokenType::Else),
             "false" => self.add_token(TokenType::False),
             "fun" => self.add_token(TokenType::Fun),
             "for" => self.add_token(TokenType::For),
             "if" => self.add_token(TokenType::If),
+            "in" => self.add_token(TokenType::In),
             "null" => self.add_token(TokenType::Null),
             "or" => self.add_token(TokenType::Or),
             "return" => self.add_token(TokenType::Return),
@@ -241,21 +453,76 @@ impl Scanner {
             "self" => self.add_token(TokenType::Slf),
             "true" => self.add_token(TokenType::True),
             "let" => self.add_token(TokenType::Let),
+            "const" => self.add_token(TokenType::Const),
             "while" => self.add_token(TokenType::While),
             "print" => self.add_token(TokenType::Print),
+            "try" => self.add_token(TokenType::Try),
+            "catch" => self.add_token(TokenType::Catch),
+            "throw" => self.add_token(TokenType::Throw),
+            "import" => self.add_token(TokenType::Import),
             // bare identifier
             _ => {
-                let token_type = TokenType::Identifier(identifier.to_string());
+                let token_type = TokenType::Identifier(identifier);
                 self.add_token(token_type);
             }
         }
     }
 }
 
+/// `_` separators must sit between two digits
+fn separators_well_placed(number: &str, is_digit: fn(char) -> bool) -> bool {
+    let chars: Vec<char> = number.chars().collect();
+    chars.iter().enumerate().all(|(index, c)| {
+        *c != '_'
+            || (index > 0
+                && index + 1 < chars.len()
+                && is_digit(chars[index - 1])
+                && is_digit(chars[index + 1]))
+    })
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ScannerError {
     message: String,
     line: u16,
+    column: u16,
+    /// source line where the error occured
+    excerpt: String,
+}
+
+impl ScannerError {
+    /// render the diagnostic along with its source line, and a caret pointing at the column
+    fn render(&self, f: &mut fmt::Formatter<'_>, severity: &str) -> fmt::Result {
+        // keep tabs so that the caret is aligned whatever the tab width
+        let padding: String = self
+            .excerpt
+            .chars()
+            .take(self.column as usize - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(
+            f,
+            "[line {}, column {}] {}: {}\n{}\n{}^",
+            self.line, self.column, severity, self.message, self.excerpt, padding
+        )
+    }
+}
+
+impl fmt::Display for ScannerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render(f, "Error")
+    }
+}
+
+/// Source that scans fine but likely doesn't mean what was intended, like an integer
+/// literal too large to be represented exactly.
+#[derive(Debug, PartialEq)]
+pub struct ScannerWarning(ScannerError);
+
+impl fmt::Display for ScannerWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.render(f, "Warning")
+    }
 }
 
 #[cfg(test)]
@@ -298,6 +565,40 @@ mod scanner_tests {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn multi_byte_character_before_number() {
+        let scanner = Scanner::new(String::from("\"é\" 1.5 2."));
+        let result = scanner.scan_tokens().unwrap();
+        let expected = vec![
+            Token {
+                typ: TokenType::Str(String::from("é")),
+                lexeme: String::from("\"é\""),
+                line: 1,
+            },
+            Token {
+                typ: TokenType::Number(1.5),
+                lexeme: String::from("1.5"),
+                line: 1,
+            },
+            Token {
+                typ: TokenType::Number(2.0),
+                lexeme: String::from("2"),
+                line: 1,
+            },
+            Token {
+                typ: TokenType::Dot,
+                lexeme: String::from("."),
+                line: 1,
+            },
+            Token {
+                typ: TokenType::Eof,
+                lexeme: String::from(""),
+                line: 1,
+            },
+        ];
+        assert_eq!(result, expected);
+    }
+
     #[test]
     fn multi_line_parens() {
         let scanner = Scanner::new(String::from("()\n()"));
@@ -459,10 +760,116 @@ mod scanner_tests {
         let expected = Err(vec![ScannerError {
             message: String::from("Unterminated string."),
             line: 1,
+            column: 1,
+            excerpt: String::from("\"hello world"),
         }]);
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn error_points_at_column() {
+        let scanner = Scanner::new(String::from("()\n\tlet @ = 1;"));
+        let errors = scanner.scan_tokens().unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 2, column 6] Error: Unexpected character: @.\n\tlet @ = 1;\n\t    ^"
+        );
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let scanner = Scanner::new(String::from("\"a\\nb\\t\\\"c\\\\\""));
+        let result = scanner.scan_tokens().unwrap();
+        assert_eq!(result[0].typ, TokenType::Str(String::from("a\nb\t\"c\\")));
+        let errors = Scanner::new(String::from("print \"\\"))
+            .scan_tokens()
+            .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Unterminated string.");
+        let errors = Scanner::new(String::from("'\\")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Unterminated character literal.");
+    }
+
+    #[test]
+    fn inexact_integer_literal_warns() {
+        let scanner = Scanner::new(String::from("9007199254740993;\n0x20000000000001"));
+        let (result, warnings) = scanner.scan_tokens_with_warnings();
+        assert_eq!(
+            result.unwrap()[0].typ,
+            TokenType::Number(9007199254740992.0)
+        );
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].to_string(),
+            "[line 1, column 1] Warning: Integer literal 9007199254740993 can't be represented exactly, it is rounded to 9007199254740992.\n9007199254740993;\n^"
+        );
+        assert_eq!(warnings[1].0.line, 2);
+    }
+
+    #[test]
+    fn exact_number_literals_dont_warn() {
+        let source =
+            "9007199254740992 9007199254740994 1_000_000 12345678901234567890000 0.1 1e300 0xFF";
+        let (result, warnings) = Scanner::new(String::from(source)).scan_tokens_with_warnings();
+        assert!(result.is_ok());
+        // 12345678901234567890000 is rounded
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].0.message.contains("12345678901234567890000"));
+    }
+
+    #[test]
+    fn raw_string() {
+        let scanner = Scanner::new(String::from("r\"a\\nb\" \"a\\nb\" r\"C:\\new\\\""));
+        let result = scanner.scan_tokens().unwrap();
+        assert_eq!(result[0].typ, TokenType::Str(String::from("a\\nb")));
+        assert_eq!(result[0].lexeme, "r\"a\\nb\"");
+        assert_eq!(result[1].typ, TokenType::Str(String::from("a\nb")));
+        assert_eq!(result[2].typ, TokenType::Str(String::from("C:\\new\\")));
+    }
+
+    #[test]
+    fn r_is_still_an_identifier() {
+        let scanner = Scanner::new(String::from("r + rx"));
+        let result = scanner.scan_tokens().unwrap();
+        assert_eq!(result[0].typ, TokenType::Identifier(String::from("r")));
+        assert_eq!(result[2].typ, TokenType::Identifier(String::from("rx")));
+    }
+
+    #[test]
+    fn unicode_escape_sequence() {
+        let scanner = Scanner::new(String::from("\"\\u{41}\\u{1F600}\""));
+        let result = scanner.scan_tokens().unwrap();
+        let expected = vec![
+            Token {
+                typ: TokenType::Str(String::from("A\u{1F600}")),
+                lexeme: String::from("\"\\u{41}\\u{1F600}\""),
+                line: 1,
+            },
+            Token {
+                typ: TokenType::Eof,
+                lexeme: String::from(""),
+                line: 1,
+            },
+        ];
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn unicode_escape_sequence_out_of_range() {
+        let scanner = Scanner::new(String::from("\"\\u{110000}\""));
+        let errors = scanner.scan_tokens().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Invalid unicode code point: 110000.");
+    }
+
+    #[test]
+    fn malformed_unicode_escape_sequence() {
+        let scanner = Scanner::new(String::from("\"\\u{12\""));
+        assert!(scanner.scan_tokens().is_err());
+        let scanner = Scanner::new(String::from("\"\\u41\""));
+        assert!(scanner.scan_tokens().is_err());
+    }
+
     #[test]
     fn string_with_parens() {
         let scanner = Scanner::new(String::from("(\"hello world\")"));
@@ -678,4 +1085,116 @@ mod scanner_tests {
         ];
         assert_eq!(result, expected);
     }
+
+    fn scan_number(source: &str) -> f64 {
+        let tokens = Scanner::new(String::from(source)).scan_tokens().unwrap();
+        match tokens[0].typ {
+            TokenType::Number(number) => number,
+            _ => panic!("Expected a number, got {:?}", tokens[0].typ),
+        }
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_eq!(scan_number("0xFF"), 255.0);
+        assert_eq!(scan_number("0xff"), 255.0);
+        assert_eq!(scan_number("0b101"), 5.0);
+        assert_eq!(scan_number("0o17"), 15.0);
+        assert_eq!(scan_number("0"), 0.0);
+    }
+
+    #[test]
+    fn invalid_radix_digits() {
+        let errors = Scanner::new(String::from("0b102")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Invalid binary literal: 0b102.");
+        let errors = Scanner::new(String::from("0x")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Invalid hexadecimal literal: 0x.");
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(scan_number("1e3"), 1000.0);
+        assert_eq!(scan_number("2.5e-1"), 0.25);
+        assert_eq!(scan_number("1E+2"), 100.0);
+    }
+
+    #[test]
+    fn malformed_exponent() {
+        let errors = Scanner::new(String::from("1e")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Expect digits in exponent of number: 1e.");
+        let errors = Scanner::new(String::from("1e-;")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Expect digits in exponent of number: 1e-.");
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(scan_number("1_000"), 1000.0);
+        assert_eq!(scan_number("1_000.000_5"), 1000.0005);
+        assert_eq!(scan_number("0b1010_1010"), 170.0);
+    }
+
+    #[test]
+    fn misplaced_digit_separators() {
+        for source in ["1__0", "1_", "1_.5", "1e5_", "0x_F"] {
+            let errors = Scanner::new(String::from(source)).scan_tokens().unwrap_err();
+            assert_eq!(errors[0].message, format!("Misplaced '_' in number: {}.", source));
+        }
+        let errors = Scanner::new(String::from("_1")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Unexpected character: _.");
+    }
+
+    #[test]
+    fn range_of_numbers() {
+        let tokens = Scanner::new(String::from("0..3")).scan_tokens().unwrap();
+        let types: Vec<TokenType> = tokens.into_iter().map(|token| token.typ).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Number(0.0),
+                TokenType::DotDot,
+                TokenType::Number(3.0),
+                TokenType::Eof
+            ]
+        );
+    }
+
+    #[test]
+    fn floor_division_is_not_a_comment() {
+        let tokens = Scanner::new(String::from("7 ~/ 2 // 3"))
+            .scan_tokens()
+            .unwrap();
+        let types: Vec<TokenType> = tokens.into_iter().map(|token| token.typ).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Number(7.0),
+                TokenType::TildeSlash,
+                TokenType::Number(2.0),
+                TokenType::Eof
+            ]
+        );
+        let errors = Scanner::new(String::from("~1")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Unexpected character: ~.");
+    }
+
+    #[test]
+    fn character_literals() {
+        for (source, expected) in [("'a'", 'a'), ("'\\n'", '\n'), ("'\\''", '\''), ("'é'", 'é')] {
+            let tokens = Scanner::new(String::from(source)).scan_tokens().unwrap();
+            assert_eq!(tokens[0].typ, TokenType::Char(expected));
+        }
+    }
+
+    #[test]
+    fn character_literal_with_several_characters() {
+        for source in ["'ab'", "''"] {
+            let errors = Scanner::new(String::from(source)).scan_tokens().unwrap_err();
+            assert_eq!(
+                errors[0].message,
+                "Character literal must contain exactly one character."
+            );
+        }
+        let errors = Scanner::new(String::from("'a")).scan_tokens().unwrap_err();
+        assert_eq!(errors[0].message, "Unterminated character literal.");
+    }
 }
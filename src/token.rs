@@ -1,15 +1,22 @@
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    DotDot,
     Minus,
     Plus,
     Semicolon,
+    Colon,
     Slash,
+    // `~/`, as `//` starts a comment
+    TildeSlash,
     Star,
     Bang,
     BangEqual,
@@ -23,15 +30,19 @@ pub enum TokenType {
     Identifier(String),
     Number(f64),
     Str(String),
+    Char(char),
     // keywords
     And,
     Not,
     Struct,
+    Continue,
+    Do,
     Else,
     False,
     Fun,
     For,
     If,
+    In,
     Null,
     Or,
     Return,
@@ -39,13 +50,19 @@ pub enum TokenType {
     Slf, // Self is a reserved keyword
     True,
     Let,
+    Const,
     While,
     Print,
+    Try,
+    Catch,
+    Throw,
+    Import,
     // EOF
     Eof,
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "astJson", derive(serde::Serialize))]
 pub struct Token {
     pub typ: TokenType,
     pub lexeme: String,
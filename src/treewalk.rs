@@ -0,0 +1,749 @@
+//! Reference implementation of the language, evaluating the AST directly instead of
+//! compiling it to bytecode. It is slow, but simple enough to cross-check the VM with.
+//!
+//! Values and natives are shared with the VM, which holds the heap and runs the natives.
+//! Known differences with the VM:
+//! - there are no tail calls, so deep tail recursion overflows the stack
+//! - natives calling a function back, like `map()`, are not supported
+//! - compilation errors, like assigning to a constant, are not reported, and neither is
+//!   assigning to a constant at runtime
+//! - `import` is not supported, the AST of a single file being run
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::ast::{
+    Assignment, Binary, Block, Call, Declaration, DeclarationWithLineNo, Expr, FunDecl, Get,
+    IfStmt, Index, IndexSet, LetDecl, Literal, Logical, Program, Set, Statement, StructDecl,
+    ThrowStmt, TryStmt, Unary, WhileStmt,
+};
+use crate::chunk::Chunk;
+use crate::token::{Token, TokenType};
+use crate::value::{Arity, BoundMethod, Function, Instance, NativeError, Struct, Value};
+use crate::vm::{ErrorKind, RuntimeError, FRAMES_MAX, VM};
+
+// natives given a function to call, which can only run bytecode
+const CALLBACK_NATIVES: [&str; 4] = ["map", "filter", "reduce", "sort"];
+
+/// Run `program`, printing on `output`, and return the value it returned if any.
+pub fn interpret(program: Program, output: Box<dyn Write>) -> Result<Option<Value>, RuntimeError> {
+    let mut interpreter = Interpreter::new(output);
+    interpreter.frames.push(Frame {
+        name: String::new(),
+        line: 0,
+        scopes: vec![],
+    });
+    for decl in program.declarations {
+        let flow = interpreter.declaration(decl);
+        match flow.map_err(|error| interpreter.uncaught(error))? {
+            Flow::Normal => {}
+            Flow::Return(value) => return Ok(Some(value)),
+            Flow::Continue => {
+                return Err(interpreter.error(
+                    ErrorKind::TypeError,
+                    "Can't use 'continue' outside of a loop.".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// what a statement hands over to the enclosing one
+enum Flow {
+    Normal,
+    Return(Value),
+    Continue,
+}
+
+/// A function being run.
+struct Frame {
+    // empty for the script
+    name: String,
+    // line being run, for error traces
+    line: u16,
+    // variables of the blocks being run, the innermost last. Globals are looked up
+    // when a variable isn't found there.
+    scopes: Vec<HashMap<String, Value>>,
+}
+
+/// Parameters and body of a function, which has an empty chunk.
+struct Body {
+    params: Vec<String>,
+    declarations: Vec<DeclarationWithLineNo>,
+}
+
+struct Interpreter {
+    // runs the natives, and allocates the containers
+    vm: VM,
+    globals: HashMap<String, Value>,
+    // the function being run is the last one
+    frames: Vec<Frame>,
+    // bodies of the functions created so far, by address of the function
+    bodies: HashMap<usize, Rc<Body>>,
+    // value the `catch` clause handling the error being raised is given
+    error_value: Option<Value>,
+}
+
+impl Interpreter {
+    fn new(output: Box<dyn Write>) -> Self {
+        let vm = VM::with_output(output, Box::new(io::stderr()));
+        let globals = vm
+            .global_names()
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), vm.global(&name)?)))
+            .collect();
+        Interpreter {
+            vm,
+            globals,
+            frames: vec![],
+            bodies: HashMap::new(),
+            error_value: None,
+        }
+    }
+
+    fn frame(&mut self) -> &mut Frame {
+        self.frames.last_mut().expect("Expected frame to be set")
+    }
+
+    fn declaration(&mut self, decl: DeclarationWithLineNo) -> Result<Flow, RuntimeError> {
+        self.frame().line = decl.lineno;
+        match decl.decl {
+            Declaration::FunDecl(decl) => {
+                let name = decl.name.lexeme.clone();
+                let function = self.function(decl);
+                self.define_variable(name, function);
+            }
+            Declaration::LetDecl(decl) => self.let_decl(decl)?,
+            Declaration::LetMulti(decls) => {
+                for decl in decls {
+                    self.let_decl(decl)?;
+                }
+            }
+            Declaration::StructDecl(decl) => self.struct_decl(decl)?,
+            Declaration::Statement(statement) => return self.statement(statement),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn let_decl(&mut self, decl: LetDecl) -> Result<(), RuntimeError> {
+        let value = match decl.initializer {
+            Some(initializer) => self.expression(initializer)?,
+            None => Value::Nil,
+        };
+        self.define_variable(decl.identifier.lexeme, value);
+        Ok(())
+    }
+
+    /// a local of the innermost block, or a global outside of any
+    fn define_variable(&mut self, name: String, value: Value) {
+        match self.frame().scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(name, value);
+            }
+            None => {
+                self.globals.insert(name, value);
+            }
+        }
+    }
+
+    fn function(&mut self, decl: FunDecl) -> Value {
+        let function = Rc::new(Function {
+            name: decl.name.lexeme,
+            arity: decl.params.len(),
+            chunk: Rc::new(Chunk::new()),
+        });
+        let body = Body {
+            params: decl.params.into_iter().map(|param| param.lexeme).collect(),
+            declarations: decl.body,
+        };
+        self.bodies
+            .insert(Rc::as_ptr(&function) as usize, Rc::new(body));
+        Value::Function(function)
+    }
+
+    fn struct_decl(&mut self, decl: StructDecl) -> Result<(), RuntimeError> {
+        let parent = match &decl.parent {
+            Some(parent) => Some(self.variable(parent)?),
+            None => None,
+        };
+        let mut methods = HashMap::new();
+        for method in decl.methods {
+            let name = method.name.lexeme.clone();
+            let Value::Function(method) = self.function(method) else {
+                unreachable!("functions are functions");
+            };
+            methods.insert(name, method);
+        }
+        let mut struct_type = Struct {
+            name: decl.name.lexeme.clone(),
+            fields: decl.fields.into_iter().map(|field| field.lexeme).collect(),
+            methods,
+        };
+        if let Some(parent) = parent {
+            let Value::Struct(parent) = parent else {
+                return Err(self.error(ErrorKind::TypeError, "Parent must be a struct".to_string()));
+            };
+            let duplicate = struct_type
+                .fields
+                .iter()
+                .find(|field| parent.fields.contains(field));
+            if let Some(field) = duplicate {
+                let msg = format!("Field '{}' is already declared by {}", field, parent.name);
+                return Err(self.error(ErrorKind::DuplicateField, msg));
+            }
+            let mut fields = parent.fields.clone();
+            fields.append(&mut struct_type.fields);
+            struct_type.fields = fields;
+            let mut methods = parent.methods.clone();
+            methods.extend(struct_type.methods);
+            struct_type.methods = methods;
+        }
+        self.define_variable(decl.name.lexeme, Value::Struct(Rc::new(struct_type)));
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: Statement) -> Result<Flow, RuntimeError> {
+        match statement {
+            Statement::ExprStmt(expr) => {
+                self.expression(expr)?;
+            }
+            Statement::IfStmt(if_stmt) => return self.if_statement(if_stmt),
+            Statement::PrintStmt(values) => {
+                let mut printed = vec![];
+                for value in values {
+                    let value = self.expression(value)?;
+                    printed.push(self.vm.show(&value));
+                }
+                self.vm.write_output(&format!("{}\n", printed.join(" ")));
+            }
+            Statement::ReturnStmt(return_stmt) => {
+                let value = match return_stmt.expr {
+                    Some(expr) => self.expression(expr)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Statement::ContinueStmt(_) => return Ok(Flow::Continue),
+            Statement::WhileStmt(while_stmt) => return self.while_statement(while_stmt),
+            Statement::DoWhileStmt(while_stmt) => return self.do_while_statement(while_stmt),
+            Statement::Block(block) => return self.block(block),
+            Statement::TryStmt(try_stmt) => return self.try_statement(try_stmt),
+            Statement::ThrowStmt(throw_stmt) => return Err(self.throw_statement(throw_stmt)?),
+            Statement::ImportStmt(_) => {
+                let msg = "import isn't supported by the tree-walker".to_string();
+                return Err(self.error(ErrorKind::TypeError, msg));
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn if_statement(&mut self, if_stmt: IfStmt) -> Result<Flow, RuntimeError> {
+        if !self.expression(if_stmt.condition)?.is_falsey() {
+            self.statement(*if_stmt.then_branch)
+        } else if let Some(else_branch) = if_stmt.else_branch {
+            self.statement(*else_branch)
+        } else {
+            Ok(Flow::Normal)
+        }
+    }
+
+    fn while_statement(&mut self, while_stmt: WhileStmt) -> Result<Flow, RuntimeError> {
+        while !self.expression(while_stmt.condition.clone())?.is_falsey() {
+            if let Flow::Return(value) = self.statement((*while_stmt.body).clone())? {
+                return Ok(Flow::Return(value));
+            }
+            if let Some(increment) = &while_stmt.increment {
+                self.declaration((**increment).clone())?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn do_while_statement(&mut self, while_stmt: WhileStmt) -> Result<Flow, RuntimeError> {
+        loop {
+            if let Flow::Return(value) = self.statement((*while_stmt.body).clone())? {
+                return Ok(Flow::Return(value));
+            }
+            if self.expression(while_stmt.condition.clone())?.is_falsey() {
+                return Ok(Flow::Normal);
+            }
+        }
+    }
+
+    fn block(&mut self, block: Block) -> Result<Flow, RuntimeError> {
+        self.frame().scopes.push(HashMap::new());
+        let mut flow = Ok(Flow::Normal);
+        for decl in block.declarations {
+            flow = self.declaration(decl);
+            if !matches!(flow, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.frame().scopes.pop();
+        flow
+    }
+
+    fn try_statement(&mut self, try_stmt: TryStmt) -> Result<Flow, RuntimeError> {
+        let frames_len = self.frames.len();
+        let scopes_len = self.frame().scopes.len();
+        match self.block(try_stmt.body) {
+            Err(RuntimeError::Error { kind, .. }) if kind.is_catchable() => {
+                // unwind the calls made from the body
+                self.frames.truncate(frames_len);
+                self.frame().scopes.truncate(scopes_len);
+                let value = self.error_value.take().unwrap_or(Value::Nil);
+                let scope = HashMap::from([(try_stmt.name.lexeme, value)]);
+                self.frame().scopes.push(scope);
+                let flow = self.block(try_stmt.handler);
+                self.frame().scopes.pop();
+                flow
+            }
+            flow => flow,
+        }
+    }
+
+    /// the error raised, unless evaluating the value raises another one
+    fn throw_statement(&mut self, throw_stmt: ThrowStmt) -> Result<RuntimeError, RuntimeError> {
+        let value = self.expression(throw_stmt.expr)?;
+        // the message is built if the error turns out to be uncaught
+        let error = self.error(ErrorKind::Thrown, String::new());
+        self.error_value = Some(value);
+        Ok(error)
+    }
+
+    fn expression(&mut self, expr: Expr) -> Result<Value, RuntimeError> {
+        // errors are reported at the line of the innermost expression, like the VM does
+        let enclosing_line = self.frame().line;
+        if let Some(line) = expr.line() {
+            self.frame().line = line;
+        }
+        let value = match expr {
+            Expr::Literal(literal) => Ok(literal_value(literal)),
+            Expr::Unary(unary) => self.unary(unary),
+            Expr::Binary(binary) => self.binary(binary),
+            Expr::Call(call) => self.call(call),
+            Expr::Grouping(group) => self.expression(*group.expression),
+            Expr::Variable(variable) => self.variable(&variable.name),
+            Expr::Assignment(assignment) => self.assignment(assignment),
+            Expr::Logical(logical) => self.logical(logical),
+            Expr::Get(get) => self.get(get),
+            Expr::Set(set) => self.set(set),
+            Expr::List(list) => {
+                let mut elements = vec![];
+                for element in list.elements {
+                    elements.push(self.expression(element)?);
+                }
+                Ok(Value::List(self.vm.new_list(elements)))
+            }
+            Expr::Map(map) => {
+                // keys are checked before insertion, they can't be mutated through a `RefCell`
+                #[allow(clippy::mutable_key_type)]
+                let mut entries = HashMap::new();
+                for (key, value) in map.entries {
+                    let key = self.expression(key)?;
+                    let value = self.expression(value)?;
+                    entries.insert(self.map_key(key)?, value);
+                }
+                Ok(Value::Map(self.vm.new_map(entries)))
+            }
+            Expr::Index(index) => self.index(index),
+            Expr::IndexSet(index_set) => self.index_set(index_set),
+            Expr::Slf(keyword) => self.variable(&keyword),
+            Expr::Lambda(lambda) => Ok(self.function(lambda)),
+        };
+        if value.is_ok() {
+            self.frame().line = enclosing_line;
+        }
+        value
+    }
+
+    fn unary(&mut self, unary: Unary) -> Result<Value, RuntimeError> {
+        let value = self.expression(*unary.right)?;
+        match (unary.operator.typ, value) {
+            (TokenType::Minus, Value::Number(number)) => Ok(Value::Number(-number)),
+            (TokenType::Minus, _) => {
+                Err(self.error(ErrorKind::TypeError, "Operand must be a number".to_string()))
+            }
+            (_, value) => Ok(Value::Boolean(value.is_falsey())),
+        }
+    }
+
+    fn binary(&mut self, binary: Binary) -> Result<Value, RuntimeError> {
+        let a = self.expression(*binary.left)?;
+        let b = self.expression(*binary.right)?;
+        // `>=` and `<=` negate another operator, so that they are true when an operand is `NaN`
+        let value = match binary.operator.typ {
+            TokenType::EqualEqual => Value::Boolean(a == b),
+            TokenType::BangEqual => Value::Boolean(a != b),
+            TokenType::Plus => match (a, b) {
+                (Value::Number(x), Value::Number(y)) => Value::Number(x + y),
+                (Value::Str(x), Value::Str(y)) => Value::Str(format!("{}{}", x, y).into()),
+                (a, b) => return Err(self.operand_types_error("add", &a, &b)),
+            },
+            typ => {
+                let (Value::Number(x), Value::Number(y)) = (&a, &b) else {
+                    let verb = match typ {
+                        TokenType::Minus => "subtract",
+                        TokenType::Star => "multiply",
+                        TokenType::Slash | TokenType::TildeSlash => "divide",
+                        _ => "compare",
+                    };
+                    return Err(self.operand_types_error(verb, &a, &b));
+                };
+                match typ {
+                    TokenType::Minus => Value::Number(x - y),
+                    TokenType::Star => Value::Number(x * y),
+                    TokenType::Slash => Value::Number(x / y),
+                    TokenType::TildeSlash if *y == 0.0 => {
+                        return Err(
+                            self.error(ErrorKind::DivisionByZero, "Division by zero".to_string())
+                        )
+                    }
+                    TokenType::TildeSlash => Value::Number((x / y).floor()),
+                    TokenType::Less => Value::Boolean(x < y),
+                    TokenType::Greater => Value::Boolean(x > y),
+                    TokenType::GreaterEqual => {
+                        Value::Boolean(x.partial_cmp(y) != Some(Ordering::Less))
+                    }
+                    TokenType::LessEqual => {
+                        Value::Boolean(x.partial_cmp(y) != Some(Ordering::Greater))
+                    }
+                    _ => unreachable!("the parser only builds binary expressions of operators"),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    /// both operands are evaluated, like the VM does
+    fn logical(&mut self, logical: Logical) -> Result<Value, RuntimeError> {
+        let a = self.expression(*logical.left)?;
+        let b = self.expression(*logical.right)?;
+        match (a, b) {
+            (Value::Boolean(x), Value::Boolean(y)) if logical.operator.typ == TokenType::And => {
+                Ok(Value::Boolean(x && y))
+            }
+            (Value::Boolean(x), Value::Boolean(y)) => Ok(Value::Boolean(x || y)),
+            _ => Err(self.error(
+                ErrorKind::TypeError,
+                "Operands must be booleans".to_string(),
+            )),
+        }
+    }
+
+    fn variable(&mut self, name: &Token) -> Result<Value, RuntimeError> {
+        let scopes = &self.frame().scopes;
+        if let Some(value) = scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(&name.lexeme))
+        {
+            return Ok(value.clone());
+        }
+        match self.globals.get(&name.lexeme) {
+            Some(value) => Ok(value.clone()),
+            None => Err(self.error(
+                ErrorKind::UndefinedVariable,
+                format!("Undefined variable '{}'", name.lexeme),
+            )),
+        }
+    }
+
+    fn assignment(&mut self, assignment: Assignment) -> Result<Value, RuntimeError> {
+        let value = self.expression(*assignment.value)?;
+        let name = assignment.name.lexeme;
+        let scopes = &mut self.frame().scopes;
+        if let Some(variable) = scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(&name))
+        {
+            *variable = value.clone();
+            return Ok(value);
+        }
+        match self.globals.get_mut(&name) {
+            Some(variable) => {
+                *variable = value.clone();
+                Ok(value)
+            }
+            None => Err(self.error(
+                ErrorKind::UndefinedVariable,
+                format!("Undefined variable '{}'", name),
+            )),
+        }
+    }
+
+    fn call(&mut self, call: Call) -> Result<Value, RuntimeError> {
+        let callee = self.expression(*call.callee)?;
+        let mut arguments = vec![];
+        for argument in call.arguments {
+            arguments.push(self.expression(argument)?);
+        }
+        self.call_value(callee, arguments)
+    }
+
+    fn call_value(&mut self, callee: Value, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        match callee {
+            Value::NativeFunction(native) => {
+                self.check_arity(&native.arity, arguments.len())?;
+                if CALLBACK_NATIVES.contains(&native.name.as_str()) {
+                    let msg = format!("{}() isn't supported by the tree-walker", native.name);
+                    return Err(self.error(ErrorKind::Native, msg));
+                }
+                match (native.function)(&mut self.vm, &arguments) {
+                    Ok(result) => Ok(result),
+                    Err(NativeError::Error(msg)) => Err(self.error(ErrorKind::Native, msg)),
+                    Err(NativeError::Runtime(err)) => Err(err),
+                    Err(NativeError::Halt(code)) => Err(RuntimeError::Halt(code)),
+                }
+            }
+            Value::Function(function) => self.call_function(function, None, arguments),
+            Value::BoundMethod(bound) => self.call_function(
+                bound.method.clone(),
+                Some(bound.receiver.clone()),
+                arguments,
+            ),
+            Value::Struct(struct_type) => {
+                self.check_arity(&Arity::Exact(struct_type.fields.len()), arguments.len())?;
+                let instance = self.vm.new_instance(Instance {
+                    struct_type,
+                    values: arguments,
+                });
+                Ok(Value::Instance(instance))
+            }
+            _ => Err(self.error(ErrorKind::TypeError, "Can only call functions".to_string())),
+        }
+    }
+
+    /// run `function` in a new frame, where `receiver` is bound to `self`
+    fn call_function(
+        &mut self,
+        function: Rc<Function>,
+        receiver: Option<Value>,
+        arguments: Vec<Value>,
+    ) -> Result<Value, RuntimeError> {
+        self.check_arity(&Arity::Exact(function.arity), arguments.len())?;
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.error(ErrorKind::StackOverflow, "Stack overflow".to_string()));
+        }
+        let body = self.bodies[&(Rc::as_ptr(&function) as usize)].clone();
+        let mut locals: HashMap<String, Value> =
+            body.params.iter().cloned().zip(arguments).collect();
+        if let Some(receiver) = receiver {
+            locals.insert("self".to_string(), receiver);
+        }
+        let line = self.frame().line;
+        self.frames.push(Frame {
+            name: function.name.clone(),
+            line,
+            scopes: vec![locals],
+        });
+        let mut result = Value::Nil;
+        for decl in body.declarations.iter() {
+            match self.declaration(decl.clone())? {
+                Flow::Normal => {}
+                Flow::Return(value) => {
+                    result = value;
+                    break;
+                }
+                Flow::Continue => {
+                    return Err(self.error(
+                        ErrorKind::TypeError,
+                        "Can't use 'continue' outside of a loop.".to_string(),
+                    ))
+                }
+            }
+        }
+        self.frames.pop();
+        Ok(result)
+    }
+
+    /// value of the field `name` of an instance, or its method of that name bound to it
+    fn get(&mut self, get: Get) -> Result<Value, RuntimeError> {
+        let object = self.expression(*get.object)?;
+        if let Value::Instance(instance) = &object {
+            let instance = instance.borrow();
+            // fields shadow methods
+            if instance.field_index(&get.name.lexeme).is_none() {
+                if let Some(method) = instance.struct_type.methods.get(&get.name.lexeme) {
+                    return Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                        receiver: object.clone(),
+                        method: method.clone(),
+                    })));
+                }
+            }
+        }
+        let (instance, index) = self.field(&object, &get.name.lexeme)?;
+        let value = instance.borrow().values[index].clone();
+        Ok(value)
+    }
+
+    fn set(&mut self, set: Set) -> Result<Value, RuntimeError> {
+        let object = self.expression(*set.object)?;
+        let value = self.expression(*set.value)?;
+        let (instance, index) = self.field(&object, &set.name.lexeme)?;
+        instance.borrow_mut().values[index] = value.clone();
+        Ok(value)
+    }
+
+    fn field(
+        &mut self,
+        object: &Value,
+        name: &str,
+    ) -> Result<(Rc<RefCell<Instance>>, usize), RuntimeError> {
+        let Value::Instance(instance) = object else {
+            return Err(self.error(
+                ErrorKind::TypeError,
+                "Only instances have fields".to_string(),
+            ));
+        };
+        let index = instance.borrow().field_index(name);
+        match index {
+            Some(index) => Ok((instance.clone(), index)),
+            None => {
+                let struct_name = instance.borrow().struct_type.name.clone();
+                Err(self.error(
+                    ErrorKind::UndefinedField,
+                    format!("Undefined field '{}' on {}", name, struct_name),
+                ))
+            }
+        }
+    }
+
+    fn index(&mut self, index: Index) -> Result<Value, RuntimeError> {
+        let object = self.expression(*index.object)?;
+        let index = self.expression(*index.index)?;
+        match object {
+            Value::List(list) => {
+                let len = list.elements.borrow().len();
+                let index = self.list_index(&index, len)?;
+                let value = list.elements.borrow()[index].clone();
+                Ok(value)
+            }
+            Value::Map(map) => {
+                let key = self.map_key(index)?;
+                let value = map.borrow().get(&key).cloned();
+                value.ok_or_else(|| {
+                    self.error(ErrorKind::UndefinedKey, format!("Undefined key '{}'", key))
+                })
+            }
+            _ => Err(self.error(
+                ErrorKind::TypeError,
+                "Only lists and maps can be indexed".to_string(),
+            )),
+        }
+    }
+
+    fn index_set(&mut self, index_set: IndexSet) -> Result<Value, RuntimeError> {
+        let object = self.expression(*index_set.object)?;
+        let index = self.expression(*index_set.index)?;
+        let value = self.expression(*index_set.value)?;
+        match object {
+            Value::List(list) => {
+                if list.frozen.get() {
+                    return Err(self.error(
+                        ErrorKind::TypeError,
+                        "Cannot mutate a frozen list.".to_string(),
+                    ));
+                }
+                let len = list.elements.borrow().len();
+                let index = self.list_index(&index, len)?;
+                list.elements.borrow_mut()[index] = value.clone();
+            }
+            Value::Map(map) => {
+                let key = self.map_key(index)?;
+                map.borrow_mut().insert(key, value.clone());
+            }
+            _ => {
+                return Err(self.error(
+                    ErrorKind::TypeError,
+                    "Only lists and maps can be indexed".to_string(),
+                ))
+            }
+        }
+        Ok(value)
+    }
+
+    fn list_index(&mut self, index: &Value, len: usize) -> Result<usize, RuntimeError> {
+        match index {
+            Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && (*n as usize) < len => {
+                Ok(*n as usize)
+            }
+            Value::Number(n) => Err(self.error(
+                ErrorKind::IndexOutOfRange,
+                format!("List index {} out of range", n),
+            )),
+            _ => Err(self.error(
+                ErrorKind::TypeError,
+                "List index must be a number".to_string(),
+            )),
+        }
+    }
+
+    fn map_key(&mut self, key: Value) -> Result<Value, RuntimeError> {
+        if key.is_hashable() {
+            Ok(key)
+        } else {
+            Err(self.error(ErrorKind::TypeError, format!("Unhashable map key: {}", key)))
+        }
+    }
+
+    fn check_arity(&mut self, arity: &Arity, nb_arguments: usize) -> Result<(), RuntimeError> {
+        if arity.accepts(nb_arguments) {
+            return Ok(());
+        }
+        Err(self.error(
+            ErrorKind::ArityMismatch,
+            format!("Expected {} arguments but got {}", arity, nb_arguments),
+        ))
+    }
+
+    fn operand_types_error(&mut self, verb: &str, a: &Value, b: &Value) -> RuntimeError {
+        let msg = format!("Cannot {} {} and {}", verb, a.type_name(), b.type_name());
+        self.error(ErrorKind::TypeError, msg)
+    }
+
+    /// an error no `catch` clause handled, which shows the value thrown if any
+    fn uncaught(&mut self, error: RuntimeError) -> RuntimeError {
+        match (error, self.error_value.take()) {
+            (RuntimeError::Error { kind, msg }, Some(value)) if kind == ErrorKind::Thrown => {
+                RuntimeError::Error {
+                    kind,
+                    msg: format!("Uncaught error: {}{}", value, msg),
+                }
+            }
+            (error, _) => error,
+        }
+    }
+
+    /// error with a trace of the active calls, from the innermost one, like the VM's
+    fn error(&mut self, kind: ErrorKind, msg: String) -> RuntimeError {
+        self.error_value = Some(Value::Str(msg.as_str().into()));
+        let mut trace = vec![msg];
+        for frame in self.frames.iter().rev() {
+            if frame.name.is_empty() {
+                trace.push(format!("[line {}] in script", frame.line));
+            } else {
+                trace.push(format!("[line {}] in {}()", frame.line, frame.name));
+            }
+        }
+        RuntimeError::Error {
+            kind,
+            msg: trace.join("\n"),
+        }
+    }
+}
+
+fn literal_value(literal: Literal) -> Value {
+    match literal {
+        Literal::Number(number) => Value::Number(number),
+        Literal::Str(s) => Value::Str(s.into()),
+        Literal::Char(c) => Value::Char(c),
+        Literal::True => Value::Boolean(true),
+        Literal::False => Value::Boolean(false),
+        Literal::Null => Value::Nil,
+    }
+}
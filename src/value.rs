@@ -1,18 +1,436 @@
-use std::fmt;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
 
-#[derive(Clone, PartialEq)]
+use crate::chunk::Chunk;
+use crate::vm::{RuntimeError, VM};
+
+/// Equality, as used by `==` and `!=`, is structural: values of different types are
+/// never equal, lists, maps, bytes and instances are equal when their contents are, and
+/// functions compare by identity: a function is only equal to itself, a method is equal
+/// to the same function bound to an equal receiver, and a struct to one with the same
+/// name, fields and method functions. Numbers follow IEEE 754, so `NaN` isn't equal to
+/// itself, even though a list holding it is equal to itself.
+///
+/// Comparisons follow IEEE 754 too, `<` and `>` being false when an operand is `NaN`, but
+/// `<=` and `>=` are their negations, and thus true. The `isNan` native detects `NaN`.
+#[derive(Clone)]
 pub enum Value {
     Number(f64),
     Boolean(bool),
-    Str(String),
+    // shared, so that loading a string constant doesn't copy it
+    Str(Rc<str>),
+    Char(char),
+    Nil,
+    List(Rc<List>),
+    Map(Rc<RefCell<HashMap<Value, Value>>>),
+    Bytes(Rc<RefCell<Vec<u8>>>),
+    // shared, so that values stay small and cheap to clone
+    Function(Rc<Function>),
+    NativeFunction(Rc<NativeFunction>),
+    Struct(Rc<Struct>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+}
+
+/// Elements of a list. Once frozen, they can still be read but no longer assigned.
+pub struct List {
+    pub elements: RefCell<Vec<Value>>,
+    pub frozen: Cell<bool>,
+}
+
+impl List {
+    pub fn new(elements: Vec<Value>) -> Self {
+        List {
+            elements: RefCell::new(elements),
+            frozen: Cell::new(false),
+        }
+    }
+}
+
+// a frozen list is equal to a mutable one with the same elements
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        lists_equal(self, other, &mut Vec::new())
+    }
+}
+
+/// A function defined in a script. The script itself is run as a function with an empty name.
+#[derive(Clone)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub chunk: Rc<Chunk>,
+}
+
+// a function is only equal to itself: comparing chunks would be slow, and recurse forever
+// once a function's constants reference it
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.chunk, &other.chunk)
+            && self.name == other.name
+            && self.arity == other.arity
+    }
+}
+
+/// A struct declaration. Calling it builds an instance, taking field values in order.
+/// Fields and methods inherited from a parent struct are copied into the child.
+#[derive(PartialEq)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<String>,
+    pub methods: HashMap<String, Rc<Function>>,
+}
+
+/// Field values are stored in the order of the struct declaration.
+#[derive(Clone)]
+pub struct Instance {
+    pub struct_type: Rc<Struct>,
+    pub values: Vec<Value>,
+}
+
+impl PartialEq for Instance {
+    fn eq(&self, other: &Self) -> bool {
+        instances_equal(self, other, &mut Vec::new())
+    }
+}
+
+impl Instance {
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.struct_type.fields.iter().position(|field| field == name)
+    }
+}
+
+/// A method retrieved from an instance, which becomes `self` when it is called.
+#[derive(PartialEq)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Function>,
+}
+
+/// A function implemented in Rust and exposed to scripts as a global.
+/// It is given the VM running it, to inspect its state.
+#[derive(Clone)]
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: Arity,
+    pub function: fn(&mut VM, &[Value]) -> Result<Value, NativeError>,
+}
+
+/// Why a native function didn't return a value.
+pub enum NativeError {
+    /// reported as a runtime error
+    Error(String),
+    /// stop the script, handing an exit code over to the host
+    Halt(i32),
+    /// raised by a script function called back by the native, see `VM::call`
+    Runtime(RuntimeError),
+}
+
+impl From<String> for NativeError {
+    fn from(msg: String) -> Self {
+        NativeError::Error(msg)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(msg: &str) -> Self {
+        NativeError::Error(msg.to_string())
+    }
+}
+
+/// Number of arguments accepted by a native function.
+#[derive(Clone, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+    /// between the two bounds, both included
+    Range(usize, usize),
+    /// any number of arguments from `min` on
+    Variadic { min: usize },
+}
+
+impl Arity {
+    pub fn accepts(&self, nb_arguments: usize) -> bool {
+        match self {
+            Arity::Exact(arity) => nb_arguments == *arity,
+            Arity::Range(min, max) => (*min..=*max).contains(&nb_arguments),
+            Arity::Variadic { min } => nb_arguments >= *min,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Exact(arity) => write!(f, "{}", arity),
+            Arity::Range(min, max) => write!(f, "{} to {}", min, max),
+            Arity::Variadic { min } => write!(f, "at least {}", min),
+        }
+    }
+}
+
+// natives are registered once under a unique name
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Value {
+    /// Only immutable values can be used as map keys: mutating a key
+    /// after insertion would silently corrupt the map.
+    pub fn is_hashable(&self) -> bool {
+        match self {
+            // NaN isn't equal to itself, so it could be inserted but never found again
+            Value::Number(n) => !n.is_nan(),
+            Value::Boolean(_) | Value::Str(_) | Value::Char(_) | Value::Nil => true,
+            _ => false,
+        }
+    }
+
+    /// `null` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::Boolean(_) => "boolean",
+            Value::Str(_) => "string",
+            Value::Char(_) => "char",
+            Value::Nil => "null",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Bytes(_) => "bytes",
+            Value::Function(_) | Value::NativeFunction(_) | Value::BoundMethod(_) => "function",
+            Value::Struct(_) => "struct",
+            Value::Instance(_) => "instance",
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        equal(self, other, &mut Vec::new())
+    }
+}
+
+// NaN breaks reflexivity, which is why it isn't hashable
+impl Eq for Value {}
+
+/// address of a container, telling it apart from the other ones
+fn address<T>(container: &Rc<T>) -> usize {
+    Rc::as_ptr(container) as *const () as usize
+}
+
+/// `a == b`, where `comparing` holds the pairs of containers whose comparison is under
+/// way. Such a pair, met again further down, is assumed to be equal: values referencing
+/// themselves are then equal when the rest of their contents are, instead of being
+/// compared forever. A container is always equal to itself, even if it holds `NaN`.
+fn equal(a: &Value, b: &Value, comparing: &mut Vec<(usize, usize)>) -> bool {
+    let pair = match (a, b) {
+        (Value::List(a), Value::List(b)) => (address(a), address(b)),
+        (Value::Map(a), Value::Map(b)) => (address(a), address(b)),
+        (Value::Instance(a), Value::Instance(b)) => (address(a), address(b)),
+        (Value::Number(a), Value::Number(b)) => return a == b,
+        (Value::Boolean(a), Value::Boolean(b)) => return a == b,
+        (Value::Str(a), Value::Str(b)) => return a == b,
+        (Value::Char(a), Value::Char(b)) => return a == b,
+        (Value::Nil, Value::Nil) => return true,
+        (Value::Bytes(a), Value::Bytes(b)) => return a == b,
+        (Value::Function(a), Value::Function(b)) => return a == b,
+        (Value::NativeFunction(a), Value::NativeFunction(b)) => return a == b,
+        (Value::Struct(a), Value::Struct(b)) => return a == b,
+        (Value::BoundMethod(a), Value::BoundMethod(b)) => return a == b,
+        _ => return false,
+    };
+    if pair.0 == pair.1 || comparing.contains(&pair) {
+        return true;
+    }
+    comparing.push(pair);
+    let result = match (a, b) {
+        (Value::List(a), Value::List(b)) => lists_equal(a, b, comparing),
+        (Value::Map(a), Value::Map(b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            // keys are never containers, only the values need `comparing`
+            a.len() == b.len()
+                && a.iter().all(|(key, a_value)| match b.get(key) {
+                    Some(b_value) => equal(a_value, b_value, comparing),
+                    None => false,
+                })
+        }
+        (Value::Instance(a), Value::Instance(b)) => {
+            instances_equal(&a.borrow(), &b.borrow(), comparing)
+        }
+        _ => unreachable!("only containers are compared element-wise"),
+    };
+    comparing.pop();
+    result
+}
+
+fn lists_equal(a: &List, b: &List, comparing: &mut Vec<(usize, usize)>) -> bool {
+    let (a, b) = (a.elements.borrow(), b.elements.borrow());
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| equal(a, b, comparing))
+}
+
+fn instances_equal(a: &Instance, b: &Instance, comparing: &mut Vec<(usize, usize)>) -> bool {
+    a.struct_type == b.struct_type
+        && a.values
+            .iter()
+            .zip(&b.values)
+            .all(|(a, b)| equal(a, b, comparing))
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            // 0.0 and -0.0 are equal, so they must hash the same
+            Value::Number(n) if *n == 0.0 => 0u64.hash(state),
+            Value::Number(n) => n.to_bits().hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::Str(s) => s.hash(state),
+            Value::Char(c) => c.hash(state),
+            Value::Nil => {}
+            // unhashable values are rejected by the VM before reaching a map
+            Value::List(_)
+            | Value::Map(_)
+            | Value::Bytes(_)
+            | Value::Function(_)
+            | Value::NativeFunction(_)
+            | Value::Struct(_)
+            | Value::Instance(_)
+            | Value::BoundMethod(_) => {}
+        }
+    }
 }
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format(f, false)
+    }
+}
+
+/// Same as `Display`, except that functions show their arity, as in `<fn add/2>`.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format(f, true)
+    }
+}
+
+impl Value {
+    fn format(&self, f: &mut fmt::Formatter<'_>, debug: bool) -> fmt::Result {
+        let mut out = String::new();
+        self.write(&mut out, debug, f.precision(), &mut Vec::new());
+        f.write_str(&out)
+    }
+
+    /// Append the value to `out`, nested values being written the same way, with the same
+    /// precision. `printing` holds the containers whose contents are being written: met
+    /// again, they are elided as `[...]` or `{...}`, so that a value referencing itself
+    /// can be printed.
+    fn write(
+        &self,
+        out: &mut String,
+        debug: bool,
+        precision: Option<usize>,
+        printing: &mut Vec<usize>,
+    ) {
+        let container = match self {
+            Value::List(list) => Some(address(list)),
+            Value::Map(map) => Some(address(map)),
+            Value::Instance(instance) => Some(address(instance)),
+            _ => None,
+        };
+        if let Some(container) = container {
+            if printing.contains(&container) {
+                match self {
+                    Value::List(_) => out.push_str("[...]"),
+                    Value::Map(_) => out.push_str("{...}"),
+                    Value::Instance(instance) => {
+                        write!(out, "{} {{...}}", instance.borrow().struct_type.name).unwrap()
+                    }
+                    _ => unreachable!("only containers are elided"),
+                }
+                return;
+            }
+            printing.push(container);
+        }
         match self {
-            Value::Number(n) => write!(f, "{}", n),
-            Value::Boolean(b) => write!(f, "{}", b),
-            Value::Str(s) => write!(f, "{}", s),
+            // the precision only rounds the decimals of fractional numbers
+            Value::Number(n) => match precision {
+                Some(precision) if n.fract() != 0.0 => write!(out, "{:.*}", precision, n).unwrap(),
+                // `f64` is never displayed with an exponent: large whole numbers show all
+                // their digits, as they would for an integer
+                _ => write!(out, "{}", n).unwrap(),
+            },
+            Value::Boolean(b) => write!(out, "{}", b).unwrap(),
+            Value::Str(s) => out.push_str(s),
+            Value::Char(c) => out.push(*c),
+            Value::Nil => out.push_str("null"),
+            Value::List(list) => {
+                out.push('[');
+                for (index, element) in list.elements.borrow().iter().enumerate() {
+                    if index > 0 {
+                        out.push_str(", ");
+                    }
+                    element.write(out, debug, precision, printing);
+                }
+                out.push(']');
+            }
+            Value::Map(map) => {
+                // the iteration order of a `HashMap` changes from one run to the next, the
+                // order of the rendered entries doesn't
+                let mut entries: Vec<String> = map
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| {
+                        let mut entry = String::new();
+                        key.write(&mut entry, debug, precision, printing);
+                        entry.push_str(": ");
+                        value.write(&mut entry, debug, precision, printing);
+                        entry
+                    })
+                    .collect();
+                entries.sort();
+                write!(out, "{{{}}}", entries.join(", ")).unwrap();
+            }
+            Value::Bytes(bytes) => write!(out, "<bytes {}>", bytes.borrow().len()).unwrap(),
+            Value::Function(function) if debug => {
+                write!(out, "<fn {}/{}>", function.name, function.arity).unwrap()
+            }
+            Value::Function(function) => write!(out, "<fn {}>", function.name).unwrap(),
+            Value::NativeFunction(native) => write!(out, "<native fn {}>", native.name).unwrap(),
+            Value::Struct(struct_type) => write!(out, "<struct {}>", struct_type.name).unwrap(),
+            Value::BoundMethod(bound) if debug => {
+                write!(out, "<fn {}/{}>", bound.method.name, bound.method.arity).unwrap()
+            }
+            Value::BoundMethod(bound) => write!(out, "<fn {}>", bound.method.name).unwrap(),
+            Value::Instance(instance) => {
+                let instance = instance.borrow();
+                out.push_str(&instance.struct_type.name);
+                if instance.values.is_empty() {
+                    out.push_str(" {}");
+                } else {
+                    out.push_str(" { ");
+                    let fields = instance.struct_type.fields.iter().zip(&instance.values);
+                    for (index, (field, value)) in fields.enumerate() {
+                        if index > 0 {
+                            out.push_str(", ");
+                        }
+                        write!(out, "{}: ", field).unwrap();
+                        value.write(out, debug, precision, printing);
+                    }
+                    out.push_str(" }");
+                }
+            }
+        }
+        if container.is_some() {
+            printing.pop();
         }
     }
 }
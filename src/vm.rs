@@ -1,28 +1,121 @@
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
 
+#[cfg(feature = "profile")]
+use crate::chunk::OPCODE_COUNT;
 use crate::chunk::{Chunk, OpCode};
-use crate::value::Value;
+use crate::gc::Heap;
+use crate::native;
+use crate::value::{
+    Arity, BoundMethod, Function, Instance, List, NativeError, NativeFunction, Struct, Value,
+};
+
+pub(crate) const FRAMES_MAX: usize = 64;
 
 pub struct VM {
-    chunk: Option<Chunk>,
+    // the frame being run is the last one
+    frames: Vec<CallFrame>,
+    // [perf] using stack.len() instead of a pointer to keep track of the top.
+    stack: Vec<Value>,
+    globals: Globals,
+    // maximum number of instructions run by a call to `interpret`, if any
+    step_limit: Option<usize>,
+    steps: usize,
+    // where the script prints, stdout and stderr unless the host says otherwise
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+    // print the stack and each instruction to the error output before running it
+    trace: bool,
+    // number of decimals printed numbers are rounded to, if any
+    precision: Option<usize>,
+    // containers allocated by the script, see `collect_garbage`
+    heap: Heap,
+    // `try` blocks being run, the innermost one last
+    handlers: Vec<Handler>,
+    // value the `catch` clause handling the error being raised is given
+    error_value: Option<Value>,
+    // number of times each opcode was run by the last call to `interpret`
+    #[cfg(feature = "profile")]
+    opcode_counts: [usize; OPCODE_COUNT],
+}
+
+struct CallFrame {
+    function: Rc<Function>,
     // NOTE - [perf] not really an instruction pointer as in the book, but a mere counter
     // This is in order to avoid using unsafe Rust. TODO: benchmark
     ip: usize,
-    // [perf] likewise, using stack.len() instead of a pointer to keep track of the top.
-    stack: Vec<Value>,
-    globals: HashMap<String, Value>,
+    // index of the callee on the stack, its locals come right after
+    slots_start_index: usize,
+}
+
+/// Global variables, each stored in a slot that never changes once defined, so that
+/// chunks can cache the slot of the globals they use instead of hashing their name.
+#[derive(Default)]
+struct Globals {
+    slots: HashMap<Rc<str>, usize>,
+    // name of the global of each slot
+    names: Vec<Rc<str>>,
+    values: Vec<Value>,
+    // whether the global of each slot was declared with `const`
+    constants: Vec<bool>,
+}
+
+impl Globals {
+    fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.slot(name).map(|slot| &self.values[slot])
+    }
+
+    /// define or redefine a global, keeping its slot in the latter case
+    fn insert(&mut self, name: Rc<str>, value: Value, constant: bool) {
+        match self.slot(&name) {
+            Some(slot) => {
+                self.values[slot] = value;
+                self.constants[slot] = constant;
+            }
+            None => {
+                self.slots.insert(name.clone(), self.values.len());
+                self.names.push(name);
+                self.values.push(value);
+                self.constants.push(constant);
+            }
+        }
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Rc<str>> {
+        self.names.iter()
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Value> {
+        self.values.iter()
+    }
+}
+
+/// Where to go when an error is raised in a `try` block.
+struct Handler {
+    // number of frames when the block was entered, the last one running it
+    frames_len: usize,
+    // offset of the `catch` clause in the chunk of that frame
+    ip: usize,
+    // height of the stack when the block was entered, which the clause starts from
+    stack_len: usize,
 }
 
 macro_rules! binary_op {
-    ($self:expr, $op:tt, $valueType:expr) => {{
+    ($self:expr, $op:tt, $valueType:expr, $verb:expr) => {{
         let b = $self.pop();
         let a = $self.pop();
         match (a, b) {
             (Value::Number(x), Value::Number(y)) => {
                 $self.push($valueType(x $op y));
             },
-            _ => {
-                Err($self.runtime_error("Operands must be numbers".to_string()))?;
+            (a, b) => {
+                Err($self.operand_types_error($verb, &a, &b))?;
             }
         }
     }};
@@ -37,7 +130,7 @@ macro_rules! logical_op {
                 $self.push(Value::Boolean(x $op y));
             },
             _ => {
-                Err($self.runtime_error("Operands must be booleans".to_string()))?;
+                Err($self.runtime_error(ErrorKind::TypeError, "Operands must be booleans".to_string()))?;
             }
         }
     }};
@@ -45,44 +138,323 @@ macro_rules! logical_op {
 
 impl VM {
     pub fn new() -> Self {
-        VM {
-            chunk: None,
-            ip: 0,
+        let mut vm = VM {
+            frames: Vec::new(),
             stack: Vec::new(),
-            globals: HashMap::new(),
+            globals: Globals::default(),
+            step_limit: None,
+            steps: 0,
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+            trace: cfg!(feature = "debugTraceExecution"),
+            precision: None,
+            heap: Heap::new(),
+            handlers: Vec::new(),
+            error_value: None,
+            #[cfg(feature = "profile")]
+            opcode_counts: [0; OPCODE_COUNT],
+        };
+        vm.define_native("len", Arity::Exact(1), native::len);
+        vm.define_native("keys", Arity::Exact(1), native::keys);
+        vm.define_native("values", Arity::Exact(1), native::values);
+        vm.define_native("clock", Arity::Exact(0), native::clock);
+        vm.define_native("exit", Arity::Exact(1), native::exit);
+        vm.define_native("write", Arity::Exact(1), native::write);
+        vm.define_native("typeof", Arity::Exact(1), native::type_of);
+        vm.define_native("isNan", Arity::Exact(1), native::is_nan);
+        vm.define_native("clone", Arity::Exact(1), native::clone);
+        vm.define_native("assert", Arity::Range(1, 2), native::assert);
+        vm.define_native("min", Arity::Exact(2), native::min);
+        vm.define_native("max", Arity::Exact(2), native::max);
+        vm.define_native("substring", Arity::Exact(3), native::substring);
+        vm.define_native("indexOf", Arity::Exact(2), native::index_of);
+        vm.define_native("toUpper", Arity::Exact(1), native::to_upper);
+        vm.define_native("toLower", Arity::Exact(1), native::to_lower);
+        vm.define_native("chars", Arity::Exact(1), native::chars);
+        vm.define_native("split", Arity::Exact(2), native::split);
+        vm.define_native("join", Arity::Exact(2), native::join);
+        vm.define_native("repeat", Arity::Exact(2), native::repeat);
+        vm.define_native("format", Arity::Variadic { min: 1 }, native::format);
+        vm.define_native("sum", Arity::Variadic { min: 0 }, native::sum);
+        vm.define_native("bytes", Arity::Exact(1), native::bytes);
+        vm.define_native("byteGet", Arity::Exact(2), native::byte_get);
+        vm.define_native("byteSet", Arity::Exact(3), native::byte_set);
+        vm.define_native("globals", Arity::Exact(0), native::globals);
+        vm.define_native("ord", Arity::Exact(1), native::ord);
+        vm.define_native("chr", Arity::Exact(1), native::chr);
+        vm.define_native("eprint", Arity::Exact(1), native::eprint);
+        vm.define_native("map", Arity::Exact(2), native::map);
+        vm.define_native("filter", Arity::Exact(2), native::filter);
+        vm.define_native("reduce", Arity::Exact(3), native::reduce);
+        vm.define_native("freeze", Arity::Exact(1), native::freeze);
+        vm.define_native("sort", Arity::Range(1, 2), native::sort);
+        vm.define_native("setPrecision", Arity::Exact(1), native::set_precision);
+        vm
+    }
+
+    /// Make `interpret` fail once `step_limit` instructions have been run, so that
+    /// hosts can run untrusted scripts without risking an infinite loop.
+    pub fn with_step_limit(step_limit: usize) -> Self {
+        let mut vm = VM::new();
+        vm.step_limit = Some(step_limit);
+        vm
+    }
+
+    /// Send what the script prints to `output`, and its diagnostics to `error_output`.
+    pub fn with_output(output: Box<dyn Write>, error_output: Box<dyn Write>) -> Self {
+        let mut vm = VM::new();
+        vm.output = output;
+        vm.error_output = error_output;
+        vm
+    }
+
+    /// Trace the execution on the error output: the stack, then the instruction run.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Round the decimals of printed numbers to `precision` digits, or print them in full.
+    pub fn set_precision(&mut self, precision: Option<usize>) {
+        self.precision = precision;
+    }
+
+    /// `value` as printed by the script
+    pub fn show(&self, value: &Value) -> String {
+        match self.precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => value.to_string(),
         }
     }
 
-    pub fn interpret(&mut self, chunk: Chunk) -> Result<(), RuntimeError> {
-        self.chunk = Some(chunk);
-        self.ip = 0;
-        self.run()
+    pub fn write_output(&mut self, text: &str) {
+        self.output
+            .write_all(text.as_bytes())
+            .expect("Something went wrong when writing output");
     }
 
-    fn run(&mut self) -> Result<(), RuntimeError> {
+    /// diagnostics are flushed right away, not to be delayed behind buffered output
+    pub fn write_error_output(&mut self, text: &str) {
+        self.error_output
+            .write_all(text.as_bytes())
+            .and_then(|_| self.error_output.flush())
+            .expect("Something went wrong when writing error output");
+    }
+
+    /// names of the defined globals, natives included, in alphabetical order
+    pub fn global_names(&self) -> Vec<Rc<str>> {
+        let mut names: Vec<Rc<str>> = self.globals.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        self.globals.get(name).cloned()
+    }
+
+    fn define_native(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        function: fn(&mut VM, &[Value]) -> Result<Value, NativeError>,
+    ) {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            function,
+        };
+        self.globals
+            .insert(name.into(), Value::NativeFunction(Rc::new(native)), false);
+    }
+
+    /// Run a chunk, after checking that it is well formed, see `Chunk::validate`.
+    /// Return the value the script returned, if it ran a top-level `return`.
+    pub fn interpret(&mut self, chunk: Chunk) -> Result<Option<Value>, RuntimeError> {
+        if let Err(msg) = chunk.validate() {
+            return Err(RuntimeError::Error {
+                kind: ErrorKind::InvalidBytecode,
+                msg,
+            });
+        }
+        let script = Function {
+            name: String::new(),
+            arity: 0,
+            chunk: Rc::new(chunk),
+        };
+        self.frames.push(CallFrame {
+            function: Rc::new(script),
+            ip: 0,
+            slots_start_index: 0,
+        });
+        self.steps = 0;
+        #[cfg(feature = "profile")]
+        {
+            self.opcode_counts = [0; OPCODE_COUNT];
+        }
+        let result = self.run(0);
+        // the value returned by the script is left on the stack, where it is kept
+        // from being collected
+        self.collect_garbage();
+        #[cfg(feature = "profile")]
+        self.print_profile();
+        result.map(|returned| if returned { self.stack.pop() } else { None })
+    }
+
+    /// Free the containers no longer reachable from the stack or the globals, which are
+    /// only kept alive by reference cycles, and return how many there were.
+    /// Values held by a native function aren't known to the VM: this must not run while
+    /// one is.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap
+            .collect_garbage(self.stack.iter().chain(self.globals.values()))
+    }
+
+    /// collect garbage if enough containers were allocated since the last time, unless a
+    /// native function is running
+    fn maybe_collect_garbage(&mut self, stop_depth: usize) {
+        if stop_depth == 0 && self.heap.collection_due() {
+            self.collect_garbage();
+        }
+    }
+
+    // containers are allocated through the heap, so that they are freed even if they
+    // end up in a cycle
+
+    pub fn new_list(&mut self, elements: Vec<Value>) -> Rc<List> {
+        self.heap.new_list(elements)
+    }
+
+    #[allow(clippy::mutable_key_type)]
+    pub fn new_map(
+        &mut self,
+        entries: HashMap<Value, Value>,
+    ) -> Rc<RefCell<HashMap<Value, Value>>> {
+        self.heap.new_map(entries)
+    }
+
+    pub fn new_instance(&mut self, instance: Instance) -> Rc<RefCell<Instance>> {
+        self.heap.new_instance(instance)
+    }
+
+    /// table of the opcodes run, the most frequent first, on the error output
+    #[cfg(feature = "profile")]
+    fn print_profile(&mut self) {
+        let mut counts: Vec<(OpCode, usize)> = self
+            .opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(byte, count)| (OpCode::new(byte as u8), *count))
+            .collect();
+        counts.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let mut table = String::from("== profile ==\n");
+        for (opcode, count) in counts {
+            table.push_str(&format!("{:<16} {:>10}\n", format!("{:?}", opcode), count));
+        }
+        self.write_error_output(&table);
+    }
+
+    /// Run until the number of frames drops to `stop_depth`, or the script ends.
+    /// An error raised in a `try` block entered since `stop_depth` runs its `catch` clause,
+    /// others are for the caller to handle: the native function calling back into the VM,
+    /// or the host.
+    /// Return whether the last frame run returned, rather than reaching the end of the script.
+    fn run(&mut self, stop_depth: usize) -> Result<bool, RuntimeError> {
+        loop {
+            match self.run_instructions(stop_depth) {
+                Err(RuntimeError::Error { kind, mut msg }) if !self.catch(kind, stop_depth) => {
+                    if stop_depth == 0 {
+                        self.reset_stack();
+                        self.reset_frames();
+                        msg = self.uncaught_message(kind, msg);
+                    }
+                    return Err(RuntimeError::Error { kind, msg });
+                }
+                Err(RuntimeError::Error { .. }) => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Unwind to the innermost `try` block entered since `stop_depth`, if any, and jump to
+    /// its `catch` clause with the error value on top of the stack. Return whether there
+    /// was one.
+    fn catch(&mut self, kind: ErrorKind, stop_depth: usize) -> bool {
+        if !kind.is_catchable() {
+            return false;
+        }
+        match self.handlers.last() {
+            Some(handler) if handler.frames_len > stop_depth => {}
+            _ => return false,
+        }
+        let handler = self.handlers.pop().expect("Expected handler to be set");
+        self.frames.truncate(handler.frames_len);
+        self.stack.truncate(handler.stack_len);
+        let value = self.error_value.take().unwrap_or(Value::Nil);
+        self.push(value);
+        self.unwrap_frame_mut().ip = handler.ip;
+        true
+    }
+
+    /// A thrown value is only shown once no `catch` clause is left to handle it, its
+    /// trace being all the error has until then.
+    fn uncaught_message(&mut self, kind: ErrorKind, trace: String) -> String {
+        match self.error_value.take() {
+            Some(value) if kind == ErrorKind::Thrown => {
+                format!("Uncaught error: {}{}", value, trace)
+            }
+            _ => trace,
+        }
+    }
+
+    fn run_instructions(&mut self, stop_depth: usize) -> Result<bool, RuntimeError> {
         loop {
-            #[cfg(feature = "debugTraceExecution")]
+            if self.trace {
+                self.trace_instruction();
+            }
+            let byte = self.read_byte();
+            // chunks are validated before being run, but a native may call a function
+            // that wasn't
+            let Ok(instruction) = OpCode::try_from(byte) else {
+                return Err(self.runtime_error(
+                    ErrorKind::InvalidBytecode,
+                    format!("Invalid opcode: {:#04x}.", byte),
+                ));
+            };
+            #[cfg(feature = "profile")]
             {
-                print!("          ");
-                for value in &self.stack {
-                    print!("[ ");
-                    print!("{}", *value);
-                    print!(" ]");
+                self.opcode_counts[byte as usize] += 1;
+            }
+            if let Some(step_limit) = self.step_limit {
+                if self.steps == step_limit {
+                    Err(self.runtime_error(
+                        ErrorKind::InstructionLimit,
+                        "Instruction limit exceeded".to_string(),
+                    ))?;
                 }
-                println!("");
-                self.unwrap_chunk().disassemble_instruction(self.ip);
+                self.steps += 1;
             }
-            let instruction = OpCode::new(self.read_byte());
             match instruction {
                 OpCode::OpConstant => {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
+                OpCode::OpConstantLong => {
+                    let address = self.read_long();
+                    let constant = self.unwrap_chunk().read_constant(address as usize);
+                    self.push(constant);
+                }
+                OpCode::OpImmediate => {
+                    let number = self.read_byte() as i8;
+                    self.push(Value::Number(number as f64));
+                }
                 OpCode::OpNegate => {
                     let value = self.pop();
                     match value {
                         Value::Number(number) => self.push(Value::Number(-number)),
-                        _ => Err(self.runtime_error("Operand must be a number".to_string()))?,
+                        _ => Err(self.runtime_error(
+                            ErrorKind::TypeError,
+                            "Operand must be a number".to_string(),
+                        ))?,
                     }
                 }
                 OpCode::OpAdd => {
@@ -93,69 +465,79 @@ impl VM {
                             self.push(Value::Number(x + y));
                         }
                         (Value::Str(x), Value::Str(y)) => {
-                            self.push(Value::Str(format!("{}{}", x, y)));
-                        }
-                        _ => {
-                            Err(self.runtime_error(
-                                "Operands must be two numbers or two strings".to_string(),
-                            ))?;
+                            self.push(Value::Str(format!("{}{}", x, y).into()));
                         }
+                        (a, b) => Err(self.operand_types_error("add", &a, &b))?,
                     }
                 }
-                OpCode::OpSubtract => binary_op!(self, -, Value::Number),
-                OpCode::OpMultiply => binary_op!(self, *, Value::Number),
-                OpCode::OpDivide => binary_op!(self, /, Value::Number),
-                OpCode::OpEqualEqual => {
+                OpCode::OpSubtract => binary_op!(self, -, Value::Number, "subtract"),
+                OpCode::OpMultiply => binary_op!(self, *, Value::Number, "multiply"),
+                OpCode::OpDivide => binary_op!(self, /, Value::Number, "divide"),
+                OpCode::OpFloorDivide => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(a == b));
+                    match (a, b) {
+                        (Value::Number(_), Value::Number(0.0)) => Err(self.runtime_error(
+                            ErrorKind::DivisionByZero,
+                            "Division by zero".to_string(),
+                        ))?,
+                        (Value::Number(x), Value::Number(y)) => {
+                            self.push(Value::Number((x / y).floor()));
+                        }
+                        (a, b) => Err(self.operand_types_error("divide", &a, &b))?,
+                    }
                 }
-                OpCode::OpBangEqual => {
+                OpCode::OpEqualEqual => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Boolean(a != b));
+                    self.push(Value::Boolean(a == b));
                 }
-                OpCode::OpLess => binary_op!(self, <, Value::Boolean),
-                OpCode::OpLessEqual => binary_op!(self, <=, Value::Boolean),
-                OpCode::OpGreater => binary_op!(self, >, Value::Boolean),
-                OpCode::OpGreaterEqual => binary_op!(self, >=, Value::Boolean),
+                OpCode::OpLess => binary_op!(self, <, Value::Boolean, "compare"),
+                OpCode::OpGreater => binary_op!(self, >, Value::Boolean, "compare"),
                 OpCode::OpReturn => {
-                    return Ok(());
+                    if self.return_from_frame(stop_depth) {
+                        return Ok(true);
+                    }
                 }
                 OpCode::OpTrue => self.push(Value::Boolean(true)),
                 OpCode::OpFalse => self.push(Value::Boolean(false)),
                 OpCode::OpNot => {
                     let value = self.pop();
-                    match value {
-                        Value::Boolean(b) => self.push(Value::Boolean(!b)),
-                        _ => Err(self.runtime_error("Operand must be a boolean".to_string()))?,
-                    }
+                    self.push(Value::Boolean(value.is_falsey()));
                 }
                 OpCode::OpAnd => logical_op!(self, &&),
                 OpCode::OpOr => logical_op!(self, ||),
                 OpCode::OpPrint => {
-                    println!("{}", self.pop());
+                    let value = self.pop();
+                    let printed = self.show(&value);
+                    self.write_output(&format!("{}\n", printed));
+                }
+                OpCode::OpPrintList => {
+                    let nb_values = self.read_byte() as usize;
+                    let values: Vec<String> = self
+                        .stack
+                        .split_off(self.stack.len() - nb_values)
+                        .iter()
+                        .map(|value| self.show(value))
+                        .collect();
+                    self.write_output(&format!("{}\n", values.join(" ")));
                 }
-                OpCode::OpDefineGlobal => {
+                OpCode::OpDefineGlobal | OpCode::OpDefineConstant => {
                     let value = self.pop();
                     let constant = self.read_constant();
                     if let Value::Str(constant) = constant {
-                        self.globals.insert(constant, value);
+                        let is_const = matches!(instruction, OpCode::OpDefineConstant);
+                        self.globals.insert(constant, value, is_const);
                     } else {
-                        Err(self.runtime_error("Expected string constant".to_string()))?;
+                        Err(self.runtime_error(
+                            ErrorKind::InvalidBytecode,
+                            "Expected string constant".to_string(),
+                        ))?;
                     }
                 }
                 OpCode::OpGetGlobal => {
-                    let constant = self.read_constant();
-                    if let Value::Str(constant) = constant {
-                        if let Some(value) = self.globals.get(&constant) {
-                            self.push(value.clone());
-                        } else {
-                            Err(self.runtime_error(format!("Undefined variable '{}'", constant)))?;
-                        }
-                    } else {
-                        Err(self.runtime_error("Expected string constant".to_string()))?;
-                    }
+                    let slot = self.global_slot()?;
+                    self.push(self.globals.values[slot].clone());
                 }
                 OpCode::OpPop => {
                     self.pop();
@@ -166,31 +548,316 @@ impl VM {
                 }
                 OpCode::OpGetLocal => {
                     let local_index = self.read_byte();
-                    let local_value = self.get_local(local_index);
+                    let local_value = self.get_local(local_index as usize);
+                    self.push(local_value);
+                }
+                OpCode::OpGetLocalLong => {
+                    let local_index = self.read_long();
+                    let local_value = self.get_local(local_index as usize);
                     self.push(local_value);
                 }
+                OpCode::OpNil => self.push(Value::Nil),
+                OpCode::OpBuildList => {
+                    let nb_elements = self.read_byte();
+                    let start = self.stack.len() - <u8 as Into<usize>>::into(nb_elements);
+                    let elements = self.stack.split_off(start);
+                    let list = self.new_list(elements);
+                    self.push(Value::List(list));
+                    self.maybe_collect_garbage(stop_depth);
+                }
+                OpCode::OpBuildMap => {
+                    let nb_entries = self.read_byte();
+                    let start = self.stack.len() - 2 * <u8 as Into<usize>>::into(nb_entries);
+                    // keys are checked with `map_key`, so they can't be mutated through a `RefCell`
+                    #[allow(clippy::mutable_key_type)]
+                    let mut map = HashMap::new();
+                    let entries = self.stack.split_off(start);
+                    for entry in entries.chunks(2) {
+                        let key = self.map_key(entry[0].clone())?;
+                        map.insert(key, entry[1].clone());
+                    }
+                    let map = self.new_map(map);
+                    self.push(Value::Map(map));
+                    self.maybe_collect_garbage(stop_depth);
+                }
+                OpCode::OpIndexGet => {
+                    let index = self.pop();
+                    let object = self.pop();
+                    let value = match object {
+                        Value::List(list) => {
+                            let list = list.elements.borrow();
+                            let index = self.list_index(&index, list.len())?;
+                            list[index].clone()
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(index)?;
+                            match map.borrow().get(&key) {
+                                Some(value) => value.clone(),
+                                None => Err(self.runtime_error(
+                                    ErrorKind::UndefinedKey,
+                                    format!("Undefined key '{}'", key),
+                                ))?,
+                            }
+                        }
+                        _ => Err(self.runtime_error(
+                            ErrorKind::TypeError,
+                            "Only lists and maps can be indexed".to_string(),
+                        ))?,
+                    };
+                    self.push(value);
+                }
+                OpCode::OpIndexSet => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let object = self.pop();
+                    match object {
+                        Value::List(list) => {
+                            if list.frozen.get() {
+                                Err(self.runtime_error(
+                                    ErrorKind::TypeError,
+                                    "Cannot mutate a frozen list.".to_string(),
+                                ))?
+                            }
+                            let mut list = list.elements.borrow_mut();
+                            let index = self.list_index(&index, list.len())?;
+                            list[index] = value.clone();
+                        }
+                        Value::Map(map) => {
+                            let key = self.map_key(index)?;
+                            map.borrow_mut().insert(key, value.clone());
+                        }
+                        _ => Err(self.runtime_error(
+                            ErrorKind::TypeError,
+                            "Only lists and maps can be indexed".to_string(),
+                        ))?,
+                    }
+                    // an assignment is an expression, it evaluates to the assigned value
+                    self.push(value);
+                }
+                OpCode::OpGetField => {
+                    let name = self.read_constant();
+                    let object = self.pop();
+                    let value = self.get_field(object, name)?;
+                    self.push(value);
+                }
+                OpCode::OpSetField => {
+                    let name = self.read_constant();
+                    let value = self.pop();
+                    let instance = self.pop();
+                    let (instance, index) = self.field(instance, name)?;
+                    instance.borrow_mut().values[index] = value.clone();
+                    // an assignment is an expression, it evaluates to the assigned value
+                    self.push(value);
+                }
+                OpCode::OpInherit => {
+                    let child = self.pop();
+                    let parent = self.pop();
+                    let (Value::Struct(parent), Value::Struct(child)) = (parent, child) else {
+                        return Err(self.runtime_error(
+                            ErrorKind::TypeError,
+                            "Parent must be a struct".to_string(),
+                        ));
+                    };
+                    if let Some(field) = child.fields.iter().find(|f| parent.fields.contains(f)) {
+                        return Err(self.runtime_error(
+                            ErrorKind::DuplicateField,
+                            format!("Field '{}' is already declared by {}", field, parent.name),
+                        ));
+                    }
+                    // copy down: the child doesn't need to walk up to its parent for lookups
+                    let mut fields = parent.fields.clone();
+                    fields.extend(child.fields.iter().cloned());
+                    let mut methods = parent.methods.clone();
+                    methods.extend(child.methods.clone());
+                    self.push(Value::Struct(Rc::new(Struct {
+                        name: child.name.clone(),
+                        fields,
+                        methods,
+                    })));
+                }
+                OpCode::OpCall => {
+                    let nb_arguments = self.read_byte();
+                    self.call_value(nb_arguments.into())?;
+                    self.maybe_collect_garbage(stop_depth);
+                }
+                OpCode::OpInvoke => {
+                    let name = self.read_constant();
+                    let nb_arguments: usize = self.read_byte().into();
+                    self.invoke(name, nb_arguments)?;
+                }
+                OpCode::OpTailCall => {
+                    let nb_arguments: usize = self.read_byte().into();
+                    let callee_index = self.stack.len() - 1 - nb_arguments;
+                    let function = match self.stack[callee_index].clone() {
+                        Value::Function(function) => Some(function),
+                        Value::BoundMethod(bound) => {
+                            self.stack[callee_index] = bound.receiver.clone();
+                            Some(bound.method.clone())
+                        }
+                        _ => None,
+                    };
+                    if let Some(function) = function {
+                        self.check_arity(&Arity::Exact(function.arity), nb_arguments)?;
+                        // the callee takes over the current frame: no need to push a new one
+                        let frame = self.unwrap_frame_mut();
+                        let slots_start_index = frame.slots_start_index;
+                        frame.function = function;
+                        frame.ip = 0;
+                        self.stack.drain(slots_start_index..callee_index);
+                    } else {
+                        // natives and structs don't run in a frame, return their result right away
+                        self.call_value(nb_arguments)?;
+                        if self.return_from_frame(stop_depth) {
+                            return Ok(true);
+                        }
+                    }
+                }
+                OpCode::OpSetGlobal => {
+                    let slot = self.global_slot()?;
+                    // the compiler only knows about the constants declared before the assignment
+                    if self.globals.constants[slot] {
+                        let name = self.globals.names[slot].clone();
+                        Err(self.runtime_error(
+                            ErrorKind::ConstantAssignment,
+                            format!("Cannot assign to constant '{}'.", name),
+                        ))?;
+                    }
+                    // assignment is an expression, so the value stays on the stack
+                    self.globals.values[slot] = self.peek(0).clone();
+                }
+                OpCode::OpSetLocal => {
+                    let local_index = self.read_byte();
+                    self.set_local(local_index as usize);
+                }
+                OpCode::OpSetLocalLong => {
+                    let local_index = self.read_long();
+                    self.set_local(local_index as usize);
+                }
+                OpCode::OpJump => {
+                    let offset = self.read_short();
+                    self.unwrap_frame_mut().ip += offset as usize;
+                }
+                OpCode::OpJumpIfFalsePop => {
+                    let offset = self.read_short();
+                    if self.pop().is_falsey() {
+                        self.unwrap_frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::OpLoop => {
+                    let offset = self.read_short();
+                    self.unwrap_frame_mut().ip -= offset as usize;
+                }
+                OpCode::OpTry => {
+                    let offset = self.read_short();
+                    self.handlers.push(Handler {
+                        frames_len: self.frames.len(),
+                        ip: self.unwrap_frame().ip + offset as usize,
+                        stack_len: self.stack.len(),
+                    });
+                }
+                OpCode::OpEndTry => {
+                    self.handlers.pop();
+                }
+                OpCode::OpThrow => {
+                    let value = self.pop();
+                    // the message is built if the error turns out to be uncaught
+                    let error = self.runtime_error(ErrorKind::Thrown, String::new());
+                    self.error_value = Some(value);
+                    return Err(error);
+                }
                 OpCode::OpEof => {
-                    return Ok(());
+                    // the VM can be reused to run another chunk, as in the REPL
+                    self.frames.pop();
+                    return Ok(false);
                 }
             }
         }
     }
 
-    /// helper to avoid dealing with Option. This should be safe to call within
+    fn trace_instruction(&mut self) {
+        let mut trace = String::from("          ");
+        for value in &self.stack {
+            trace.push_str(&format!("[ {} ]", value));
+        }
+        trace.push('\n');
+        // prefixed by the function running it, to tell which one a call entered
+        let frame = self.unwrap_frame();
+        if frame.function.name.is_empty() {
+            trace.push_str("script:: ");
+        } else {
+            trace.push_str(&format!("{}:: ", frame.function.name));
+        }
+        frame.function.chunk.write_instruction(&mut trace, frame.ip);
+        self.write_error_output(&trace);
+    }
+
+    /// helpers to avoid dealing with Option. These should be safe to call within
     /// the context of an interpret run.
+    fn unwrap_frame(&self) -> &CallFrame {
+        self.frames.last().expect("Expected frame to be set")
+    }
+
+    fn unwrap_frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Expected frame to be set")
+    }
+
     fn unwrap_chunk(&self) -> &Chunk {
-        self.chunk.as_ref().expect("Expected chunk to be set")
+        &self.unwrap_frame().function.chunk
     }
 
     fn read_byte(&mut self) -> u8 {
-        let result = self.unwrap_chunk().read_byte(self.ip);
-        self.ip += 1;
+        let frame = self.unwrap_frame_mut();
+        let result = frame.function.chunk.read_byte(frame.ip);
+        frame.ip += 1;
+        result
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let frame = self.unwrap_frame_mut();
+        let result = frame.function.chunk.read_short(frame.ip);
+        frame.ip += 2;
+        result
+    }
+
+    fn read_long(&mut self) -> u32 {
+        let frame = self.unwrap_frame_mut();
+        let result = frame.function.chunk.read_long(frame.ip);
+        frame.ip += 3;
         result
     }
 
     fn read_constant(&mut self) -> Value {
         let byte = self.read_byte();
-        self.unwrap_chunk().read_constant(byte)
+        self.unwrap_chunk().read_constant(byte as usize)
+    }
+
+    /// Read the constant naming a global, and return the slot of that global.
+    /// The slot is looked up by name the first time only, then cached in the chunk. A
+    /// chunk could be run by several VMs though, so the name of the cached slot is checked.
+    fn global_slot(&mut self) -> Result<usize, RuntimeError> {
+        let address = self.read_byte();
+        let chunk = &self.unwrap_frame().function.chunk;
+        let Value::Str(name) = chunk.read_constant(address as usize) else {
+            return Err(self.runtime_error(
+                ErrorKind::InvalidBytecode,
+                "Expected string constant".to_string(),
+            ));
+        };
+        if let Some(slot) = chunk.cached_global_slot(address) {
+            if self.globals.names.get(slot) == Some(&name) {
+                return Ok(slot);
+            }
+        }
+        match self.globals.slot(&name) {
+            Some(slot) => {
+                self.unwrap_chunk().cache_global_slot(address, slot);
+                Ok(slot)
+            }
+            None => Err(self.runtime_error(
+                ErrorKind::UndefinedVariable,
+                format!("Undefined variable '{}'", name),
+            )),
+        }
     }
 
     fn push(&mut self, value: Value) {
@@ -201,29 +868,1045 @@ impl VM {
         self.stack.pop().expect("Tried to pop on empty stack")
     }
 
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
+    }
+
     fn pop_n(&mut self, nb_elem_to_pop: u8) {
         let new_len = self.stack.len() - <u8 as Into<usize>>::into(nb_elem_to_pop);
         self.stack.truncate(new_len);
     }
 
-    fn get_local(&self, index: u8) -> Value {
-        let usize_index: usize = index.try_into().unwrap();
-        self.stack[usize_index].clone()
+    fn get_local(&self, index: usize) -> Value {
+        self.stack[self.unwrap_frame().slots_start_index + index].clone()
+    }
+
+    /// store the value on top of the stack in a local, leaving it on the stack
+    fn set_local(&mut self, index: usize) {
+        let slot = self.unwrap_frame().slots_start_index + index;
+        self.stack[slot] = self.peek(0).clone();
+    }
+
+    /// the callee sits on the stack right below its arguments, both get replaced
+    /// by the call's result.
+    fn call_value(&mut self, nb_arguments: usize) -> Result<(), RuntimeError> {
+        let callee_index = self.stack.len() - 1 - nb_arguments;
+        match self.stack[callee_index].clone() {
+            Value::NativeFunction(native) => {
+                self.check_arity(&native.arity, nb_arguments)?;
+                let arguments = self.stack.split_off(callee_index + 1);
+                match (native.function)(self, &arguments) {
+                    Ok(result) => {
+                        self.pop(); // discard callee
+                        self.push(result);
+                        Ok(())
+                    }
+                    Err(NativeError::Error(msg)) => Err(self.runtime_error(ErrorKind::Native, msg)),
+                    // raised by a function the native called, already reported
+                    Err(NativeError::Runtime(err)) => Err(err),
+                    Err(NativeError::Halt(code)) => {
+                        self.reset_stack();
+                        self.reset_frames();
+                        Err(RuntimeError::Halt(code))
+                    }
+                }
+            }
+            Value::Function(function) => self.call_function(function, callee_index, nb_arguments),
+            Value::BoundMethod(bound) => {
+                // the receiver takes the place of the callee, where `self` is looked up
+                self.stack[callee_index] = bound.receiver.clone();
+                self.call_function(bound.method.clone(), callee_index, nb_arguments)
+            }
+            Value::Struct(struct_type) => {
+                self.check_arity(&Arity::Exact(struct_type.fields.len()), nb_arguments)?;
+                let values = self.stack.split_off(callee_index + 1);
+                self.pop(); // discard callee
+                let instance = Instance {
+                    struct_type,
+                    values,
+                };
+                let instance = self.new_instance(instance);
+                self.push(Value::Instance(instance));
+                Ok(())
+            }
+            _ => {
+                Err(self.runtime_error(ErrorKind::TypeError, "Can only call functions".to_string()))
+            }
+        }
+    }
+
+    fn call_function(
+        &mut self,
+        function: Rc<Function>,
+        callee_index: usize,
+        nb_arguments: usize,
+    ) -> Result<(), RuntimeError> {
+        self.check_arity(&Arity::Exact(function.arity), nb_arguments)?;
+        if self.frames.len() == FRAMES_MAX {
+            Err(self.runtime_error(ErrorKind::StackOverflow, "Stack overflow".to_string()))?;
+        }
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots_start_index: callee_index,
+        });
+        Ok(())
+    }
+
+    /// call the method `name` of the receiver sitting below the arguments, sparing the
+    /// bound method `get_field` would build. Fields holding functions are called as well.
+    fn invoke(&mut self, name: Value, nb_arguments: usize) -> Result<(), RuntimeError> {
+        let receiver_index = self.stack.len() - 1 - nb_arguments;
+        let receiver = self.stack[receiver_index].clone();
+        if let (Value::Instance(instance), Value::Str(name)) = (&receiver, &name) {
+            let instance = instance.borrow();
+            // fields shadow methods
+            let method = match instance.field_index(name) {
+                Some(_) => None,
+                None => instance.struct_type.methods.get(&**name).cloned(),
+            };
+            drop(instance);
+            if let Some(method) = method {
+                // the receiver already sits in the callee slot, where `self` is looked up
+                return self.call_function(method, receiver_index, nb_arguments);
+            }
+        }
+        let callee = self.get_field(receiver, name)?;
+        self.stack[receiver_index] = callee;
+        self.call_value(nb_arguments)
+    }
+
+    /// value of the field `name` of `object`, or its method of that name bound to it.
+    /// Fields shadow methods.
+    fn get_field(&mut self, object: Value, name: Value) -> Result<Value, RuntimeError> {
+        if let (Value::Instance(instance), Value::Str(name)) = (&object, &name) {
+            let instance = instance.borrow();
+            if instance.field_index(name).is_none() {
+                if let Some(method) = instance.struct_type.methods.get(&**name) {
+                    return Ok(Value::BoundMethod(Rc::new(BoundMethod {
+                        receiver: object.clone(),
+                        method: method.clone(),
+                    })));
+                }
+            }
+        }
+        let (instance, index) = self.field(object, name)?;
+        let value = instance.borrow().values[index].clone();
+        Ok(value)
+    }
+
+    /// resolve the field `name` of `instance` to its position in the instance values
+    fn field(
+        &mut self,
+        instance: Value,
+        name: Value,
+    ) -> Result<(Rc<RefCell<Instance>>, usize), RuntimeError> {
+        let Value::Instance(instance) = instance else {
+            return Err(self.runtime_error(
+                ErrorKind::TypeError,
+                "Only instances have fields".to_string(),
+            ));
+        };
+        let Value::Str(name) = name else {
+            return Err(self.runtime_error(
+                ErrorKind::InvalidBytecode,
+                "Expected string constant".to_string(),
+            ));
+        };
+        let index = instance.borrow().field_index(&name);
+        match index {
+            Some(index) => Ok((instance, index)),
+            None => {
+                let struct_name = instance.borrow().struct_type.name.clone();
+                Err(self.runtime_error(
+                    ErrorKind::UndefinedField,
+                    format!("Undefined field '{}' on {}", name, struct_name),
+                ))
+            }
+        }
+    }
+
+    fn check_arity(&mut self, arity: &Arity, nb_arguments: usize) -> Result<(), RuntimeError> {
+        if !arity.accepts(nb_arguments) {
+            Err(self.runtime_error(
+                ErrorKind::ArityMismatch,
+                format!("Expected {} arguments but got {}", arity, nb_arguments),
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// pop the current frame, replacing its slots with the value on top of the stack.
+    /// The slots include the locals of blocks left early by a `return`.
+    /// Return whether the number of frames dropped to `stop_depth`.
+    fn return_from_frame(&mut self, stop_depth: usize) -> bool {
+        let result = self.pop();
+        let frame = self.frames.pop().expect("Expected frame to be set");
+        // the `try` blocks `return` leaves
+        let frames_len = self.frames.len();
+        while matches!(self.handlers.last(), Some(handler) if handler.frames_len > frames_len) {
+            self.handlers.pop();
+        }
+        self.stack.truncate(frame.slots_start_index);
+        self.push(result);
+        self.frames.len() == stop_depth
+    }
+
+    /// Call `callee` from a native function, running it to completion.
+    pub fn call(&mut self, callee: Value, arguments: &[Value]) -> Result<Value, NativeError> {
+        let stop_depth = self.frames.len();
+        self.push(callee);
+        for argument in arguments {
+            self.push(argument.clone());
+        }
+        self.call_value(arguments.len())
+            .map_err(NativeError::Runtime)?;
+        // natives and structs are done already, functions have a frame to run
+        if self.frames.len() > stop_depth {
+            self.run(stop_depth).map_err(NativeError::Runtime)?;
+        }
+        Ok(self.pop())
+    }
+
+    /// check that `index` is a valid position in a list of length `len`
+    fn list_index(&mut self, index: &Value, len: usize) -> Result<usize, RuntimeError> {
+        match index {
+            Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && (*n as usize) < len => {
+                Ok(*n as usize)
+            }
+            Value::Number(n) => Err(self.runtime_error(
+                ErrorKind::IndexOutOfRange,
+                format!("List index {} out of range", n),
+            )),
+            _ => Err(self.runtime_error(
+                ErrorKind::TypeError,
+                "List index must be a number".to_string(),
+            )),
+        }
+    }
+
+    fn map_key(&mut self, key: Value) -> Result<Value, RuntimeError> {
+        if key.is_hashable() {
+            Ok(key)
+        } else {
+            Err(self.runtime_error(ErrorKind::TypeError, format!("Unhashable map key: {}", key)))
+        }
     }
 
     fn reset_stack(&mut self) {
         self.stack.clear();
     }
 
-    fn runtime_error(&mut self, msg: String) -> RuntimeError {
-        let lineno = self.unwrap_chunk().get_lineno(self.ip - 1);
-        self.reset_stack();
-        RuntimeError {
-            msg: format!("{}\n[line {}] in script", msg, lineno),
+    fn reset_frames(&mut self) {
+        self.frames.clear();
+        self.handlers.clear();
+    }
+
+    /// error of a binary operator applied to operands of the wrong types, like
+    /// "Cannot add null and number"
+    fn operand_types_error(&mut self, verb: &str, a: &Value, b: &Value) -> RuntimeError {
+        let msg = format!("Cannot {} {} and {}", verb, a.type_name(), b.type_name());
+        self.runtime_error(ErrorKind::TypeError, msg)
+    }
+
+    /// Error raised by the instruction being run. The stack and frames are left as they
+    /// are, for `run` to unwind them.
+    fn runtime_error(&mut self, kind: ErrorKind, msg: String) -> RuntimeError {
+        // a `catch` clause only gets the message
+        self.error_value = Some(Value::Str(msg.as_str().into()));
+        // trace of the active calls, from the innermost one
+        let mut trace = vec![msg];
+        for frame in self.frames.iter().rev() {
+            let chunk = &frame.function.chunk;
+            let location = match chunk.get_file(frame.ip - 1) {
+                Some(file) => format!("line {} of {}", chunk.get_lineno(frame.ip - 1), file),
+                None => format!("line {}", chunk.get_lineno(frame.ip - 1)),
+            };
+            if frame.function.name.is_empty() {
+                trace.push(format!("[{}] in script", location));
+            } else {
+                trace.push(format!("[{}] in {}()", location, frame.function.name));
+            }
+        }
+        RuntimeError::Error {
+            kind,
+            msg: trace.join("\n"),
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub enum RuntimeError {
+    /// the message comes with a trace of the calls active when the error occured
+    Error { kind: ErrorKind, msg: String },
+    /// the script called `exit`, the host decides what to do with the code
+    Halt(i32),
+}
+
+/// What went wrong, for hosts to handle errors without parsing their message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// an operand or callee of the wrong type
+    TypeError,
+    UndefinedVariable,
+    UndefinedField,
+    UndefinedKey,
+    /// assigning to a global declared with `const`
+    ConstantAssignment,
+    IndexOutOfRange,
+    ArityMismatch,
+    StackOverflow,
+    DuplicateField,
+    /// `~/` by zero, unlike `/` which follows IEEE 754
+    DivisionByZero,
+    /// the budget given to `VM::with_step_limit` was exhausted
+    InstructionLimit,
+    /// reported by a native function
+    Native,
+    /// the chunk wasn't produced by the compiler
+    InvalidBytecode,
+    /// raised by a `throw` statement
+    Thrown,
+}
+
+impl ErrorKind {
+    /// Whether a `catch` clause can handle the error. A script can't escape the
+    /// instruction limit, nor run a corrupt chunk any further.
+    pub fn is_catchable(self) -> bool {
+        !matches!(
+            self,
+            ErrorKind::InstructionLimit | ErrorKind::InvalidBytecode
+        )
+    }
+}
+
+#[cfg(test)]
+mod vm_tests {
+    use super::*;
+    use crate::ast::parser::Parser;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+
+    fn compile(source: &str) -> Chunk {
+        let tokens = Scanner::new(source.to_string()).scan_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let mut chunk = Chunk::new();
+        Compiler::new(&mut chunk).run(program).unwrap();
+        chunk
+    }
+
+    /// output sink whose content can still be read once handed to the VM
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn content(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    fn interpret(source: &str) -> (VM, Result<Option<Value>, RuntimeError>) {
+        let mut vm = VM::new();
+        let result = vm.interpret(compile(source));
+        (vm, result)
+    }
+
+    #[test]
+    fn debug_format_shows_function_arity() {
+        let (vm, result) = interpret("fun add(a, b) { return a + b; }");
+        assert!(result.is_ok());
+        let add = vm.globals.get("add").unwrap();
+        assert!(format!("{:?}", add).contains("/2"));
+        assert_eq!(add.to_string(), "<fn add>");
+    }
+
+    #[test]
+    fn script_hands_its_return_value_over() {
+        let (vm, result) = interpret("let x = 40;\n{\n  let y = 2;\n  return x + y;\n}\nx = 0;");
+        assert!(matches!(result, Ok(Some(Value::Number(n))) if n == 42.0));
+        assert!(vm.globals.get("x") == Some(&Value::Number(40.0)));
+        assert!(vm.stack.is_empty());
+        assert!(matches!(interpret("return 42;").1, Ok(Some(Value::Number(n))) if n == 42.0));
+        assert!(matches!(interpret("let x = 42;").1, Ok(None)));
+        // not collected, even though it is only kept alive by a cycle
+        let result = interpret("let l = [null];\nl[0] = l;\nreturn l;").1;
+        assert!(matches!(result, Ok(Some(Value::List(l))) if l.elements.borrow().len() == 1));
+    }
+
+    #[test]
+    fn values_referencing_themselves_can_be_printed() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(output.clone()), Box::new(io::sink()));
+        let source = "let l = [1, null];\nl[1] = l;\nlet m = {};\nm[1] = [m, l];\nstruct Node { next }\nlet n = Node(null);\nn.next = [n];\nprint l, m, n;";
+        assert!(vm.interpret(compile(source)).is_ok());
+        assert_eq!(
+            output.content(),
+            "[1, [...]] {1: [{...}, [1, [...]]]} Node { next: [Node {...}] }\n"
+        );
+    }
+
+    #[test]
+    fn values_referencing_themselves_can_be_compared() {
+        let source = "let a = [null];\na[0] = a;\nlet b = [null];\nb[0] = b;\nlet m = {};\nm[1] = m;\nlet n = {};\nn[1] = n;\nlet lists = a == b;\nlet maps = m == n;\nlet different = a == [a, 1];";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("lists") == Some(&Value::Boolean(true)));
+        assert!(vm.globals.get("maps") == Some(&Value::Boolean(true)));
+        assert!(vm.globals.get("different") == Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn numbers_are_displayed_without_exponent() {
+        assert_eq!(Value::Number(1e21).to_string(), "1000000000000000000000");
+        assert_eq!(Value::Number(1000000.0).to_string(), "1000000");
+        assert_eq!(Value::Number(0.0001).to_string(), "0.0001");
+        assert_eq!(Value::Number(-1e21).to_string(), "-1000000000000000000000");
+    }
+
+    #[test]
+    fn step_limit_stops_infinite_loop() {
+        let mut vm = VM::with_step_limit(1000);
+        match vm.interpret(compile("while (true) {}")) {
+            Err(RuntimeError::Error { msg, .. }) => {
+                assert!(msg.starts_with("Instruction limit exceeded"))
+            }
+            _ => panic!("Expected the instruction limit to be hit"),
+        }
+    }
+
+    #[test]
+    fn step_limit_cant_be_caught() {
+        let mut vm = VM::with_step_limit(1000);
+        let source = "while (true) {\n  try {\n    while (true) {}\n  } catch (e) {}\n}";
+        match vm.interpret(compile(source)) {
+            Err(RuntimeError::Error { kind, .. }) => assert_eq!(kind, ErrorKind::InstructionLimit),
+            _ => panic!("Expected the instruction limit to be hit"),
+        }
+    }
+
+    #[test]
+    fn caught_error_unwinds_calls_and_temporaries() {
+        let source = "fun f(n) {\n  if (n == 0) return 1 ~/ 0;\n  return 1 + f(n - 1);\n}\nlet before = 1;\nlet result = 0;\ntry {\n  let inside = 2;\n  result = [inside, 3 + f(10)];\n} catch (e) {\n  result = e;\n}\nlet after = before;";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("result") == Some(&Value::Str("Division by zero".into())));
+        assert!(vm.globals.get("after") == Some(&Value::Number(1.0)));
+        assert!(vm.stack.is_empty());
+        assert!(vm.handlers.is_empty());
+    }
+
+    #[test]
+    fn thrown_value_is_caught_as_is() {
+        let source = "let thrown = [1];\nlet caught = null;\ntry {\n  throw thrown;\n} catch (e) {\n  caught = e;\n}";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        let (Some(Value::List(thrown)), Some(Value::List(caught))) =
+            (vm.globals.get("thrown"), vm.globals.get("caught"))
+        else {
+            panic!("Expected the thrown list to be caught");
+        };
+        assert!(Rc::ptr_eq(thrown, caught));
+    }
+
+    #[test]
+    fn uncaught_throw_is_a_runtime_error() {
+        match interpret("fun f() {\n  throw \"oops\";\n}\nf();").1 {
+            Err(RuntimeError::Error { kind, msg }) => {
+                assert_eq!(kind, ErrorKind::Thrown);
+                assert_eq!(
+                    msg,
+                    "Uncaught error: oops\n[line 2] in f()\n[line 4] in script"
+                );
+            }
+            _ => panic!("Expected an uncaught throw to fail"),
+        }
+    }
+
+    #[test]
+    fn thrown_value_is_shown_once_uncaught() {
+        let source = "let l = [null];\nl[0] = l;\ntry {\n  throw l;\n} catch (e) {}\nlet caught = null;\ntry {\n  map([1], fun(x) { throw \"inner\"; });\n} catch (e) {\n  caught = e;\n}\nthrow l;";
+        let (vm, result) = interpret(source);
+        assert!(vm.globals.get("caught") == Some(&Value::Str("inner".into())));
+        match result {
+            Err(RuntimeError::Error { msg, .. }) => {
+                assert_eq!(msg, "Uncaught error: [[...]]\n[line 12] in script")
+            }
+            _ => panic!("Expected an uncaught throw to fail"),
+        }
+    }
+
+    #[test]
+    fn returning_from_a_try_block_drops_its_handler() {
+        let source = "fun f() {\n  try {\n    return 1;\n  } catch (e) {\n    return 2;\n  }\n}\nlet a = f();\nprint 1 + true;";
+        let (vm, result) = interpret(source);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::Error {
+                kind: ErrorKind::TypeError,
+                ..
+            })
+        ));
+        assert!(vm.globals.get("a") == Some(&Value::Number(1.0)));
+        assert!(vm.handlers.is_empty());
+    }
+
+    #[test]
+    fn errors_have_a_kind() {
+        let cases = [
+            ("1 + true;", ErrorKind::TypeError),
+            ("null();", ErrorKind::TypeError),
+            ("print x;", ErrorKind::UndefinedVariable),
+            (
+                "fun f() {\n  return 1 + f();\n}\nf();",
+                ErrorKind::StackOverflow,
+            ),
+            ("fun f(a) {}\nf();", ErrorKind::ArityMismatch),
+            ("[1][3];", ErrorKind::IndexOutOfRange),
+            ("let m = {1: 2};\nm[3];", ErrorKind::UndefinedKey),
+            ("let m = {};\nm[0 / 0] = 1;", ErrorKind::TypeError),
+            (
+                "fun f() {\n  x = 2;\n}\nconst x = 1;\nf();",
+                ErrorKind::ConstantAssignment,
+            ),
+            ("struct A { x }\nA(1).y;", ErrorKind::UndefinedField),
+            ("len(1);", ErrorKind::Native),
+            ("let a = 1;\na ~/ 0;", ErrorKind::DivisionByZero),
+            ("throw 1;", ErrorKind::Thrown),
+        ];
+        for (source, expected) in cases {
+            match interpret(source).1 {
+                Err(RuntimeError::Error { kind, .. }) => assert_eq!(kind, expected, "{}", source),
+                _ => panic!("Expected a runtime error for {}", source),
+            }
+        }
+    }
+
+    #[test]
+    fn frozen_list_can_be_read_but_not_assigned() {
+        let (vm, result) = interpret("let l = freeze([1, 2]);\nlet x = l[0];\nl[1] = 3;");
+        match result {
+            Err(RuntimeError::Error { kind, msg }) => {
+                assert_eq!(kind, ErrorKind::TypeError);
+                assert!(msg.starts_with("Cannot mutate a frozen list."));
+            }
+            _ => panic!("Expected assigning to a frozen list to fail"),
+        }
+        assert!(vm.globals.get("x") == Some(&Value::Number(1.0)));
+    }
+
+    // the profile is written to the error output as well
+    #[cfg(not(feature = "profile"))]
+    #[test]
+    fn eprint_writes_to_error_output() {
+        let output = SharedBuffer::default();
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(output.clone()), Box::new(error_output.clone()));
+        // the trace compiled in by debugTraceExecution goes to the error output too
+        vm.set_trace(false);
+        let source = "print 1;\neprint(\"warning\");\nwrite(2);";
+        assert!(vm.interpret(compile(source)).is_ok());
+        assert_eq!(output.content(), "1\n2");
+        assert_eq!(error_output.content(), "warning\n");
+    }
+
+    #[cfg(feature = "debugTraceExecution")]
+    #[test]
+    fn trace_shows_the_called_function() {
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(error_output.clone()));
+        let source = "fun add(a, b) {\n  return a + b;\n}\nprint add(1, 2);";
+        assert!(vm.interpret(compile(source)).is_ok());
+        let trace = error_output.content();
+        assert!(trace.contains("script:: 0000"), "{}", trace);
+        assert!(trace.contains("add:: 0004    | OP_ADD"), "{}", trace);
+    }
+
+    #[test]
+    fn trace_shows_values_referencing_themselves() {
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(error_output.clone()));
+        vm.set_trace(true);
+        let source = "let l = [null];\nl[0] = l;\nprint len(l);";
+        assert!(vm.interpret(compile(source)).is_ok());
+        let trace = error_output.content();
+        assert!(trace.contains("[ [[...]] ]"), "{}", trace);
+    }
+
+    #[test]
+    fn set_precision_rounds_printed_numbers() {
+        let output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(output.clone()), Box::new(io::sink()));
+        let source = "setPrecision(2);\nprint 1/3;\nprint 2, [2/3, \"1.234\"];\nwrite(format(\"{}\n\", 1/3));\nsetPrecision(null);\nprint 1/3;";
+        assert!(vm.interpret(compile(source)).is_ok());
+        assert_eq!(
+            output.content(),
+            "0.33\n2 [0.67, 1.234]\n0.33\n0.3333333333333333\n"
+        );
+    }
+
+    // the profile is written to the error output as well
+    #[cfg(not(feature = "profile"))]
+    #[test]
+    fn set_precision_rounds_eprinted_numbers() {
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(error_output.clone()));
+        // the trace compiled in by debugTraceExecution goes to the error output too
+        vm.set_trace(false);
+        let source = "setPrecision(2);\neprint(1/3);";
+        assert!(vm.interpret(compile(source)).is_ok());
+        assert_eq!(error_output.content(), "0.33\n");
+    }
+
+    #[test]
+    fn map_calls_back_into_the_vm() {
+        let (vm, result) = interpret("let doubled = map([1, 2, 3], fun(x) { return x * 2; });");
+        assert!(result.is_ok());
+        let expected = [2.0, 4.0, 6.0].map(Value::Number).to_vec();
+        assert!(vm.globals.get("doubled") == Some(&Value::List(Rc::new(List::new(expected)))));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn exit_halts_with_code() {
+        let (vm, result) = interpret("let a = 1;\nexit(3);\nlet b = 2;");
+        assert!(matches!(result, Err(RuntimeError::Halt(3))));
+        assert!(vm.globals.get("a").is_some());
+        assert!(vm.globals.get("b").is_none());
+    }
+
+    #[test]
+    fn exit_from_a_function() {
+        let (_, result) = interpret("fun stop() {\n  exit(0);\n}\nstop();");
+        assert!(matches!(result, Err(RuntimeError::Halt(0))));
+    }
+
+    #[test]
+    fn exit_expects_an_integer() {
+        let (_, result) = interpret("exit(1.5);");
+        match result {
+            Err(RuntimeError::Error { msg, .. }) => {
+                assert_eq!(msg, "exit() expects an integer code\n[line 1] in script")
+            }
+            _ => panic!("Expected a runtime error"),
         }
     }
+
+    #[test]
+    fn return_from_nested_block_leaves_balanced_stack() {
+        let source = "fun f() {\n  let a = 1;\n  {\n    let b = 2;\n    {\n      let c = 3;\n      return a + b + c;\n    }\n  }\n}\nlet r = 0;\nwhile (r < 60) {\n  r = r + f();\n}";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("r") == Some(&Value::Number(60.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn tail_call_from_nested_block_leaves_balanced_stack() {
+        let source = "fun two() {\n  return 2;\n}\nfun f() {\n  {\n    let a = 1;\n    return two();\n  }\n}\nlet r = f();";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("r") == Some(&Value::Number(2.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn string_constants_are_unchanged_by_loads() {
+        let source = "let s = \"\";\nlet i = 0;\nwhile (i < 3) {\n  s = s + \"ab\";\n  i = i + 1;\n}\nlet t = toUpper(s);";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("s") == Some(&Value::Str("ababab".into())));
+        assert!(vm.globals.get("t") == Some(&Value::Str("ABABAB".into())));
+    }
+
+    #[test]
+    fn nested_conditions_leave_balanced_stack() {
+        let source = "fun count(n) {
+  let hits = 0;
+  let i = 0;
+  while (i < n and not (i == 1000)) {
+    let j = i;
+    if (j < 2 or j == 3) {
+      hits = hits + 1;
+    } else if (j == 4 and true) {
+      do {
+        if (false or j == 4) hits = hits + 10;
+        j = j + 1;
+      } while (j < 7 and j > 4);
+    }
+    i = i + 1;
+  }
+  return hits;
 }
+let total = 0;
+let k = 0;
+while (k < 100 or false) {
+  if (k == 0 or k == 1) total = total + count(10); else total = total + 0;
+  k = k + 1;
+}";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        // 3 hits below 4, plus 10 when j is 4, in each of the two calls
+        assert!(vm.globals.get("total") == Some(&Value::Number(26.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn let_declares_several_variables() {
+        let source = "let a = 1, b = 2, c = a + b;\nlet d, e = 5, f;\n{\n  let x = 1, y = x + 1;\n  d = y;\n}";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("a") == Some(&Value::Number(1.0)));
+        assert!(vm.globals.get("b") == Some(&Value::Number(2.0)));
+        assert!(vm.globals.get("c") == Some(&Value::Number(3.0)));
+        assert!(vm.globals.get("d") == Some(&Value::Number(2.0)));
+        assert!(vm.globals.get("e") == Some(&Value::Number(5.0)));
+        assert!(vm.globals.get("f") == Some(&Value::Nil));
+        assert!(vm.stack.is_empty());
+    }
+
+    // a tag and the widest variants, a string or bytes behind a fat pointer
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn values_stay_small() {
+        assert_eq!(std::mem::size_of::<Value>(), 24);
+    }
+
+    #[test]
+    fn functions_behave_the_same_behind_rc() {
+        let source = "fun add(a, b) { return a + b; }\nlet f = add;\nlet same = f == add;\nlet r = f(1, 2);\nlet l = len;\nlet n = l([1]);";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("same") == Some(&Value::Boolean(true)));
+        assert!(vm.globals.get("r") == Some(&Value::Number(3.0)));
+        assert!(vm.globals.get("n") == Some(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn variadic_native_gets_all_its_arguments() {
+        let (vm, result) = interpret("let none = sum();\nlet one = sum(4);\nlet many = sum(1, 2, 3, 4.5);");
+        assert!(result.is_ok());
+        assert!(vm.globals.get("none") == Some(&Value::Number(0.0)));
+        assert!(vm.globals.get("one") == Some(&Value::Number(4.0)));
+        assert!(vm.globals.get("many") == Some(&Value::Number(10.5)));
+        assert!(vm.stack.is_empty());
+        match interpret("sum(1, \"2\");").1 {
+            Err(RuntimeError::Error { kind, .. }) => assert_eq!(kind, ErrorKind::Native),
+            _ => panic!("Expected sum to reject a string"),
+        }
+    }
+
+    #[test]
+    fn bang_and_not_both_negate() {
+        let (vm, result) = interpret("let a = !true;\nlet b = not true;\nlet c = !not false;");
+        assert!(result.is_ok());
+        assert!(vm.globals.get("a") == Some(&Value::Boolean(false)));
+        assert!(vm.globals.get("b") == Some(&Value::Boolean(false)));
+        assert!(vm.globals.get("c") == Some(&Value::Boolean(false)));
+    }
+
+    #[test]
+    fn global_slots_are_cached() {
+        let source = "let total = 0;\nlet step = 2;\nfun run(n) {\n  for (i in 0..n) total = total + step;\n}\nrun(1000000);\nlet step = 3;\nrun(1);";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("total") == Some(&Value::Number(2000003.0)));
+        let Some(Value::Function(run)) = vm.globals.get("run") else {
+            panic!("Expected run to be a function");
+        };
+        let step = (0..=255)
+            .find(|address| run.chunk.read_constant(*address as usize) == Value::Str("step".into()))
+            .unwrap();
+        assert_eq!(run.chunk.cached_global_slot(step), vm.globals.slot("step"));
+    }
+
+    #[test]
+    fn cached_global_slots_are_checked_by_name() {
+        let (mut first, result) = interpret("let a = 1;\nfun f() { return a; }");
+        assert!(result.is_ok());
+        let f = first.globals.get("f").unwrap().clone();
+        assert!(matches!(first.call(f.clone(), &[]), Ok(Value::Number(n)) if n == 1.0));
+        // the slot cached by the first VM holds another global in the second one
+        let mut second = VM::new();
+        second.globals.insert("padding".into(), Value::Nil, false);
+        second.globals.insert("a".into(), Value::Number(2.0), false);
+        assert!(matches!(second.call(f, &[]), Ok(Value::Number(n)) if n == 2.0));
+    }
+
+    #[test]
+    fn malformed_chunk_is_not_run() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::OpTrue as u8, 1);
+        chunk.write(OpCode::OpJump as u8, 1);
+        let mut vm = VM::new();
+        match vm.interpret(chunk) {
+            Err(RuntimeError::Error { kind, msg }) => {
+                assert_eq!(kind, ErrorKind::InvalidBytecode);
+                assert_eq!(msg, "Truncated OpJump at offset 1");
+            }
+            _ => panic!("Expected a malformed chunk to be rejected"),
+        }
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn invalid_opcode_is_a_runtime_error() {
+        let mut body = Chunk::new();
+        body.write(OpCode::OpNil as u8, 1);
+        body.write(0xff, 2);
+        let function = Function {
+            name: "corrupt".to_string(),
+            arity: 0,
+            chunk: Rc::new(body),
+        };
+        // called like a native would, as `interpret` would reject the chunk
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(error_output.clone()));
+        // tracing disassembles the instruction before it is run
+        vm.set_trace(true);
+        match vm.call(Value::Function(Rc::new(function)), &[]) {
+            Err(NativeError::Runtime(RuntimeError::Error { kind, msg })) => {
+                assert_eq!(kind, ErrorKind::InvalidBytecode);
+                assert_eq!(msg, "Invalid opcode: 0xff.\n[line 2] in corrupt()");
+            }
+            _ => panic!("Expected an invalid opcode to be reported"),
+        }
+        let trace = error_output.content();
+        assert!(trace.ends_with("Invalid opcode 0xff\n"), "{}", trace);
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn profile_counts_opcodes() {
+        let error_output = SharedBuffer::default();
+        let mut vm = VM::with_output(Box::new(io::sink()), Box::new(error_output.clone()));
+        let result = vm.interpret(compile("let i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}"));
+        assert!(result.is_ok());
+        assert_eq!(vm.opcode_counts[OpCode::OpLess as usize], 4);
+        assert_eq!(vm.opcode_counts[OpCode::OpAdd as usize], 3);
+        assert_eq!(vm.opcode_counts[OpCode::OpLoop as usize], 3);
+        assert_eq!(vm.opcode_counts[OpCode::OpDefineGlobal as usize], 1);
+        assert_eq!(vm.opcode_counts[OpCode::OpEof as usize], 1);
+        assert!(error_output.content().contains("OpLess"));
+    }
+
+    #[test]
+    fn instances_share_their_fields() {
+        let source = "struct Box { content }\nfun get(b) { return b.content; }\nlet a = Box(1);\nlet b = a;\nb.content = 2;\nlet c = get(a);";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("c") == Some(&Value::Number(2.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn globals_lists_defined_names() {
+        let (vm, result) = interpret("let a = 1;\nlet b = 2;\nlet names = globals();");
+        assert!(result.is_ok());
+        let Some(Value::List(names)) = vm.globals.get("names") else {
+            panic!("Expected globals() to return a list");
+        };
+        let names = names.elements.borrow();
+        assert!(names.contains(&Value::Str("a".into())));
+        assert!(names.contains(&Value::Str("b".into())));
+        assert!(names.contains(&Value::Str("globals".into())));
+    }
+
+    #[test]
+    fn loops_leave_balanced_stack_whatever_their_exit() {
+        let sources = [
+            // exiting through the condition, many times over
+            "let n = 0;\nwhile (n < 10000) {\n  let a = n;\n  n = a + 1;\n}",
+            "let n = 0;\ndo {\n  n = n + 1;\n} while (n < 10000);",
+            "let n = 0;\nfor (x in 0..10000) {\n  n = n + x;\n}",
+            // exiting through a return, with the condition value and locals on the stack
+            "fun find(limit) {\n  let i = 0;\n  while (true) {\n    let j = i;\n    if (j == limit) return j;\n    i = i + 1;\n  }\n}\nlet n = 0;\nwhile (n < 1000) {\n  n = n + find(3);\n}",
+            "fun first() {\n  for (x in 0..10) {\n    return x;\n  }\n}\nlet n = 0;\nwhile (n < 1000) {\n  n = n + first() + 1;\n}",
+        ];
+        for source in sources {
+            let (vm, result) = interpret(source);
+            assert!(result.is_ok(), "{}", source);
+            assert!(vm.stack.is_empty(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn invoked_methods() {
+        let source = "struct A { f\n  fun add(x, y) { return x + y + self.f(); }\n}\nstruct B < A {\n  fun twice(x) { return self.add(x, x); }\n}\nlet b = B(fun() { return 100; });\nlet sum = b.add(1, 2);\nlet twice = b.twice(3);\nlet field = b.f();";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("sum") == Some(&Value::Number(103.0)));
+        assert!(vm.globals.get("twice") == Some(&Value::Number(106.0)));
+        assert!(vm.globals.get("field") == Some(&Value::Number(100.0)));
+        assert!(vm.stack.is_empty());
+
+        let (_, result) = interpret("struct A {}\nA().missing();");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn binary_operator_errors_name_operand_types() {
+        let (_, result) = interpret("null + 1;");
+        let Err(RuntimeError::Error { kind, msg }) = result else {
+            panic!("Expected a runtime error");
+        };
+        assert!(matches!(kind, ErrorKind::TypeError));
+        assert!(msg.starts_with("Cannot add null and number\n"));
+
+        let (_, result) = interpret("\"a\" < [];");
+        let Err(RuntimeError::Error { msg, .. }) = result else {
+            panic!("Expected a runtime error");
+        };
+        assert!(msg.starts_with("Cannot compare string and list\n"));
+    }
+
+    #[test]
+    fn instances_in_cycles_are_freed() {
+        let source = "struct Node { next }\nfor (i in 0..3000) {\n  let a = Node(null);\n  let b = Node(a);\n  a.next = [b];\n}\nlet kept = Node(null);\nkept.next = kept;";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        // only `kept` is still alive, and still references itself
+        assert_eq!(vm.heap.len(), 1);
+        let Some(Value::Instance(kept)) = vm.globals.get("kept") else {
+            panic!("Expected a reachable instance to be kept");
+        };
+        assert!(kept.borrow().values[0] == Value::Instance(kept.clone()));
+    }
+
+    #[test]
+    fn lists_and_maps_in_cycles_are_freed() {
+        let source = "for (i in 0..3000) {\n  let l = [null];\n  l[0] = l;\n  let m = {\"self\": null};\n  m[\"self\"] = [m];\n}\nlet kept = [1, {}];\nkept[0] = kept;";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        // `kept` and its map
+        assert_eq!(vm.heap.len(), 2);
+    }
+
+    fn heap_size(vm: &mut VM, _args: &[Value]) -> Result<Value, NativeError> {
+        Ok(Value::Number(vm.heap.len() as f64))
+    }
+
+    #[test]
+    fn garbage_is_collected_while_running() {
+        let mut vm = VM::new();
+        vm.define_native("heapSize", Arity::Exact(0), heap_size);
+        let source = "let largest = 0;\nfor (i in 0..100000) {\n  let l = [null];\n  l[0] = l;\n  largest = max(largest, heapSize());\n}";
+        assert!(vm.interpret(compile(source)).is_ok());
+        let Some(Value::Number(largest)) = vm.globals.get("largest") else {
+            panic!("Expected largest to be a number");
+        };
+        assert!(*largest < 10000.0, "{} containers alive at once", largest);
+    }
 
-pub struct RuntimeError {
-    pub msg: String,
+    #[test]
+    fn instances_held_by_natives_are_not_collected() {
+        let source = "struct Node { next }\nlet numbers = {};\nfor (i in 0..2000) numbers[i] = i;\nlet nodes = map(keys(numbers), fun(x) {\n  let node = Node(null);\n  node.next = node;\n  return node;\n});\nlet linked = len(filter(nodes, fun(node) { return node.next == node; }));";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("linked") == Some(&Value::Number(2000.0)));
+    }
+
+    #[test]
+    fn chars_splits_on_characters() {
+        let (vm, result) =
+            interpret("let c = chars(\"héllo\");\nlet n = len(c);\nlet second = c[1];");
+        assert!(result.is_ok());
+        assert!(vm.globals.get("n") == Some(&Value::Number(5.0)));
+        assert!(vm.globals.get("second") == Some(&Value::Char('é')));
+    }
+
+    #[test]
+    fn split_and_join() {
+        let source = "let parts = split(\"a,b,c\", \",\");\nlet n = len(parts);\nlet joined = join(parts, \"-\");\nlet empty = split(\"\", \",\");";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("n") == Some(&Value::Number(3.0)));
+        assert!(vm.globals.get("joined") == Some(&Value::Str("a-b-c".into())));
+        let Some(Value::List(empty)) = vm.globals.get("empty") else {
+            panic!("Expected split() to return a list");
+        };
+        assert!(*empty.elements.borrow() == vec![Value::Str("".into())]);
+
+        let (_, result) = interpret("join([\"a\", 1], \",\");");
+        let Err(RuntimeError::Error { msg, .. }) = result else {
+            panic!("Expected a runtime error");
+        };
+        assert!(msg.starts_with("join() expects a list of strings, got a number\n"));
+    }
+
+    #[test]
+    fn continue_leaves_balanced_stack() {
+        let source = "let n = 0;\nfor (let i = 0; i < 5; i = i + 1) {\n  let a = i;\n  {\n    let b = a;\n    if (b == 2) continue;\n  }\n  n = n + 1;\n}";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("n") == Some(&Value::Number(4.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn equality_across_values() {
+        let cases = [
+            ("null", "null", true),
+            ("null", "false", false),
+            ("null", "0", false),
+            ("false", "false", true),
+            ("true", "false", false),
+            ("\"\"", "false", false),
+            ("1", "1.0", true),
+            ("0", "-0", true),
+            ("1", "2", false),
+            ("0 / 0", "0 / 0", false),
+            ("\"a\"", "\"a\"", true),
+            ("\"a\"", "\"b\"", false),
+            ("\"1\"", "1", false),
+            ("[1, 2]", "[1, 2]", true),
+            ("[1]", "[2]", false),
+            ("[]", "{}", false),
+            ("{\"a\": 1}", "{\"a\": 1}", true),
+            ("{\"a\": 1}", "{\"a\": 2}", false),
+            ("bytes(2)", "bytes(2)", true),
+            ("bytes(1)", "bytes(2)", false),
+            ("len", "len", true),
+            ("len", "keys", false),
+            ("f", "f", true),
+            ("f", "g", false),
+            ("f", "len", false),
+        ];
+        let mut source = "fun f() {}\nfun g() {}\n".to_string();
+        for (index, (a, b, _)) in cases.iter().enumerate() {
+            source += &format!("let eq{} = {} == {};\n", index, a, b);
+            source += &format!("let ne{} = {} != {};\n", index, a, b);
+        }
+        let (vm, result) = interpret(&source);
+        assert!(result.is_ok());
+        for (index, (a, b, expected)) in cases.iter().enumerate() {
+            let eq = vm.globals.get(format!("eq{}", index).as_str());
+            let ne = vm.globals.get(format!("ne{}", index).as_str());
+            assert!(eq == Some(&Value::Boolean(*expected)), "{} == {}", a, b);
+            assert!(ne == Some(&Value::Boolean(!expected)), "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn recursive_function_is_equal_to_itself() {
+        let source = "fun f(n) {\n  if (n > 0) return f(n - 1);\n  return f;\n}\nlet same = f(3) == f;\nlet other = f == fun(n) { return n; };";
+        let (vm, result) = interpret(source);
+        assert!(result.is_ok());
+        assert!(vm.globals.get("same") == Some(&Value::Boolean(true)));
+        assert!(vm.globals.get("other") == Some(&Value::Boolean(false)));
+    }
 }
@@ -0,0 +1,46 @@
+use std::process::Command;
+
+fn script(name: &str) -> String {
+    format!(
+        "{}/scripts/test_data/in/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    )
+}
+
+// the code disassembled by debugPrintCode goes to stdout too
+#[cfg(not(feature = "debugPrintCode"))]
+#[test]
+fn trace_dumps_stack_and_instructions_on_stderr() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rox"))
+        .args(["--trace", &script("hello_world")])
+        .output()
+        .expect("Couldn't run rox");
+    let stdout = String::from_utf8(output.stdout).expect("Expected UTF-8 output");
+    let stderr = String::from_utf8(output.stderr).expect("Expected UTF-8 output");
+    assert_eq!(stdout, "hello world!\n");
+    assert!(stderr.contains("OP_PRINT"), "{}", stderr);
+    assert!(stderr.contains("[ hello world! ]"), "{}", stderr);
+}
+
+#[cfg(not(feature = "debugPrintCode"))]
+#[test]
+fn check_compiles_without_running() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rox"))
+        .args(["--check", &script("hello_world")])
+        .output()
+        .expect("Couldn't run rox");
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn check_reports_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rox"))
+        .args(["--check", &script("parse_error")])
+        .output()
+        .expect("Couldn't run rox");
+    let stdout = String::from_utf8(output.stdout).expect("Expected UTF-8 output");
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(stdout, "[line 2] Error at end: Expect ';' after value.\n");
+}
@@ -0,0 +1,51 @@
+//! Helpers shared by the integration tests.
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use rox::ast::parser::Parser;
+use rox::ast::Program;
+use rox::chunk::Chunk;
+use rox::compiler::Compiler;
+use rox::scanner::Scanner;
+
+/// scan and parse `src`, reporting errors the way the binary does
+pub fn parse(src: &str) -> Result<Program, String> {
+    let tokens = Scanner::new(src.to_string())
+        .scan_tokens()
+        .map_err(|errors| {
+            let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+            errors.join("\n")
+        })?;
+    Parser::new(tokens).parse().map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+        errors.join("\n")
+    })
+}
+
+pub fn compile(src: &str) -> Result<Chunk, String> {
+    let program = parse(src)?;
+    let mut chunk = Chunk::new();
+    Compiler::new(&mut chunk).run(program)?;
+    Ok(chunk)
+}
+
+/// output sink whose content can still be read once handed to the VM
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    pub fn content(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
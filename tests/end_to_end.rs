@@ -0,0 +1,138 @@
+mod common;
+
+use std::io;
+
+use common::{compile, SharedBuffer};
+use rox::vm::{RuntimeError, VM};
+
+/// what the script printed, followed by the error it stopped on if any, as the binary would
+fn run(src: &str) -> String {
+    let output = SharedBuffer::default();
+    let mut vm = VM::with_output(Box::new(output.clone()), Box::new(io::sink()));
+    let result = match compile(src) {
+        Ok(chunk) => vm.interpret(chunk),
+        Err(err) => return format!("{}\n", err),
+    };
+    let mut printed = output.content();
+    match result {
+        Ok(_) => {}
+        Err(RuntimeError::Error { msg, .. }) => printed.push_str(&format!("{}\n", msg)),
+        Err(RuntimeError::Halt(code)) => printed.push_str(&format!("exit {}\n", code)),
+    }
+    printed
+}
+
+#[test]
+fn arithmetic_follows_precedence() {
+    assert_eq!(run("print 1 + 2 * 3 - 4 / 2;"), "5\n");
+    assert_eq!(run("print -(1 + 2) * 3;"), "-9\n");
+}
+
+#[test]
+fn strings_concatenate() {
+    assert_eq!(run("let s = \"foo\";\nprint s + \"bar\";"), "foobar\n");
+}
+
+#[test]
+fn comparisons_and_logic() {
+    assert_eq!(
+        run("print 1 < 2, 2 <= 1, 1 == 1, \"a\" != \"b\";"),
+        "true false true true\n"
+    );
+    assert_eq!(
+        run("print true and not false, false or false;"),
+        "true false\n"
+    );
+}
+
+#[test]
+fn if_else_picks_a_branch() {
+    let src = "let x = 3;\nif (x > 2) print \"big\"; else print \"small\";";
+    assert_eq!(run(src), "big\n");
+}
+
+#[test]
+fn while_loop_runs_until_its_condition_fails() {
+    let src = "let i = 0;\nwhile (i < 3) {\n  print i;\n  i = i + 1;\n}";
+    assert_eq!(run(src), "0\n1\n2\n");
+}
+
+#[test]
+fn for_loop_with_continue() {
+    let src = "for (let i = 0; i < 5; i = i + 1) {\n  if (i == 2) continue;\n  print i;\n}";
+    assert_eq!(run(src), "0\n1\n3\n4\n");
+}
+
+#[test]
+fn recursive_function() {
+    let src = "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(15);";
+    assert_eq!(run(src), "610\n");
+}
+
+#[test]
+fn functions_are_values() {
+    let src =
+        "let twice = fun(f, x) { return f(f(x)); };\nprint twice(fun(x) { return x * 3; }, 2);";
+    assert_eq!(run(src), "18\n");
+}
+
+#[test]
+fn lists_and_maps() {
+    let src = "let l = [1, 2, 3];\nl[0] = 4;\nlet m = {\"a\": l};\nprint m[\"a\"], len(l);";
+    assert_eq!(run(src), "[4, 2, 3] 3\n");
+}
+
+#[test]
+fn structs_with_methods() {
+    let src = "struct Point {\n  x, y\n  fun sum() { return self.x + self.y; }\n}\nlet p = Point(1, 2);\np.x = 10;\nprint p, p.sum();";
+    assert_eq!(run(src), "Point { x: 10, y: 2 } 12\n");
+}
+
+#[test]
+fn output_before_an_error_is_kept() {
+    let src = "print \"before\";\nprint 1 + true;";
+    assert_eq!(
+        run(src),
+        "before\nCannot add number and boolean\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn runtime_error_has_a_trace() {
+    // not a tail call, which would replace the frame of outer
+    let src =
+        "fun inner() {\n  return missing;\n}\nfun outer() {\n  return inner() + 1;\n}\nouter();";
+    assert_eq!(
+        run(src),
+        "Undefined variable 'missing'\n[line 2] in inner()\n[line 5] in outer()\n[line 7] in script\n"
+    );
+}
+
+#[test]
+fn list_index_out_of_range() {
+    assert_eq!(
+        run("let l = [1];\nprint l[1];"),
+        "List index 1 out of range\n[line 2] in script\n"
+    );
+}
+
+#[test]
+fn compile_error_prevents_running() {
+    assert_eq!(
+        run("print \"not printed\";\ncontinue;"),
+        "Compilation error: Can't use 'continue' outside of a loop.\nat line 2\n"
+    );
+}
+
+#[test]
+fn parse_error_is_reported() {
+    assert_eq!(
+        run("print 1 +;"),
+        "[line 1] Error at ';': Expect expression\n"
+    );
+}
+
+#[test]
+fn exit_halts_with_its_code() {
+    assert_eq!(run("print 1;\nexit(3);\nprint 2;"), "1\nexit 3\n");
+}
@@ -0,0 +1,70 @@
+//! The REPL's output is compared as a whole, which the code dumped on stdout by
+//! debugPrintCode would be mixed in.
+#![cfg(not(feature = "debugPrintCode"))]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// feed `input` to the REPL, returning what it printed
+fn repl(input: &str) -> String {
+    repl_with_args(&[], input)
+}
+
+fn repl_with_args(args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Couldn't start the REPL");
+    child
+        .stdin
+        .take()
+        .expect("Expected stdin to be piped")
+        .write_all(input.as_bytes())
+        .expect("Couldn't write to the REPL");
+    let output = child.wait_with_output().expect("Couldn't run the REPL");
+    String::from_utf8(output.stdout).expect("Expected UTF-8 output")
+}
+
+#[test]
+fn expression_is_echoed() {
+    assert_eq!(repl("1 + 2\n\n"), "> 3\n> ");
+}
+
+#[test]
+fn declaration_is_not_echoed() {
+    assert_eq!(repl("let x = 1;\n\n"), "> > ");
+}
+
+#[test]
+fn globals_are_kept_between_lines() {
+    assert_eq!(repl("let x = 1;\nx + 1\n\n"), "> > 2\n> ");
+}
+
+#[test]
+fn constants_are_kept_between_lines() {
+    assert_eq!(
+        repl("const x = 1;\nx = 2;\nx\n\n"),
+        "> > Cannot assign to constant 'x'.\n[line 1] in script\n> 1\n> "
+    );
+}
+
+#[test]
+fn piped_input_is_read_plainly_with_history_enabled() {
+    let history_file = std::env::temp_dir().join("rox_piped_repl_history");
+    let history_file = history_file.to_str().unwrap();
+    let output = repl_with_args(&["--repl-history", history_file], "let x = 1;\nx + 1\n\n");
+    assert_eq!(output, "> > 2\n> ");
+}
+
+#[test]
+fn input_can_end_without_an_empty_line() {
+    assert_eq!(repl("1 + 2\n"), "> 3\n> ");
+}
+
+#[test]
+fn incomplete_input_goes_on_over_the_next_lines() {
+    let input = "fun add(a, b) {\n  return a + b;\n}\nadd(1, 2)\n\n";
+    assert_eq!(repl(input), "> ... ... > 3\n> ");
+}
@@ -0,0 +1,148 @@
+//! Differential tests: programs are run by the VM and the tree-walking interpreter,
+//! which must print the same output and stop on the same error.
+#![cfg(feature = "treewalk")]
+
+mod common;
+
+use std::io;
+
+use common::{compile, parse, SharedBuffer};
+use rox::treewalk;
+use rox::value::Value;
+use rox::vm::{RuntimeError, VM};
+
+/// what the program printed, followed by the value it returned or the error it stopped on if any
+fn report(output: SharedBuffer, result: Result<Option<Value>, RuntimeError>) -> String {
+    let mut printed = output.content();
+    match result {
+        Ok(None) => {}
+        Ok(Some(value)) => printed.push_str(&format!("return {}\n", value)),
+        Err(RuntimeError::Error { kind, msg }) => {
+            printed.push_str(&format!("{:?}: {}\n", kind, msg))
+        }
+        Err(RuntimeError::Halt(code)) => printed.push_str(&format!("exit {}\n", code)),
+    }
+    printed
+}
+
+fn run_vm(src: &str) -> String {
+    let chunk = compile(src).unwrap();
+    let output = SharedBuffer::default();
+    let mut vm = VM::with_output(Box::new(output.clone()), Box::new(io::sink()));
+    let result = vm.interpret(chunk);
+    report(output, result)
+}
+
+fn run_treewalk(src: &str) -> String {
+    let output = SharedBuffer::default();
+    let result = treewalk::interpret(parse(src).unwrap(), Box::new(output.clone()));
+    report(output, result)
+}
+
+/// run `src` with both engines, and return what they agreed on
+fn run(src: &str) -> String {
+    let printed = run_vm(src);
+    assert_eq!(run_treewalk(src), printed, "engines disagree on:\n{}", src);
+    printed
+}
+
+#[test]
+fn arithmetic_and_comparisons() {
+    let src = "print 1 + 2 * 3 - 4 / 2, -(1 + 2);\nprint 1 < 2, 2 <= 1, 1 >= 1, 1 != 2;\nprint true and not false, false or false;";
+    assert_eq!(run(src), "5 -3\ntrue false true true\ntrue false\n");
+    assert_eq!(run("let a = 7;\nprint a ~/ 2, -a ~/ 2;"), "3 -4\n");
+    assert_eq!(
+        run("let nan = 0 / 0;\nprint nan < 1, nan >= 1;"),
+        "false true\n"
+    );
+}
+
+#[test]
+fn strings_and_natives() {
+    let src =
+        "let s = \"a,b\" + \",c\";\nprint s, len(s), split(s, \",\"), join(chars(\"xyz\"), \"-\");";
+    run(src);
+}
+
+#[test]
+fn variables_and_scopes() {
+    let src = "let a = 1, b = 2;\n{\n  let a = 10;\n  b = a + b;\n  print a, b;\n}\nprint a, b;";
+    assert_eq!(run(src), "10 12\n1 12\n");
+}
+
+#[test]
+fn loops() {
+    let src = "let i = 0;\nwhile (i < 3) { print i; i = i + 1; }\nfor (let j = 0; j < 5; j = j + 1) {\n  if (j == 2) continue;\n  print j;\n}\nfor (k in 0..3) print k;\ndo { print i; i = i - 1; } while (i > 0);";
+    run(src);
+}
+
+#[test]
+fn functions() {
+    let src = "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 1) + fib(n - 2);\n}\nprint fib(15);\nlet twice = fun(f, x) { return f(f(x)); };\nprint twice(fun(x) { return x * 3; }, 2);\nfun nothing() {}\nprint nothing();";
+    assert_eq!(run(src), "610\n18\nnull\n");
+}
+
+#[test]
+fn lists_and_maps() {
+    let src = "let l = [1, 2, 3];\nl[0] = 4;\nlet m = {\"a\": l, 1: true};\nm[\"b\"] = [];\nprint m[\"a\"], m[1], len(l), l == [4, 2, 3];";
+    run(src);
+}
+
+#[test]
+fn structs() {
+    let src = "struct Point {\n  x, y\n  fun sum() { return self.x + self.y; }\n}\nstruct Point3 < Point {\n  z\n  fun sum3() { return self.sum() + self.z; }\n}\nlet p = Point3(1, 2, 3);\np.x = 10;\nlet sum = p.sum;\nprint p, p.sum3(), sum();";
+    run(src);
+}
+
+#[test]
+fn runtime_errors() {
+    run("print \"before\";\nprint 1 + true;");
+    run("print -\"a\";");
+    run("let zero = 0;\nprint 1 ~/ zero;");
+    run("print 1 ~/ \"a\";");
+    run("print 1 < null;");
+    run("print true and 1;");
+    run("let l = [1];\nprint l[1];");
+    run("let m = {};\nprint m[\"a\"];");
+    run("print {}[[]];");
+    run("print 1[0];");
+    run("fun f(a) {}\nf();");
+    run("1();");
+    run("struct A { x }\nA(1).y;");
+    run("struct A { x }\nstruct B < A { x }");
+    run("let A = 1;\nstruct B < A {}");
+    run("print 1.x;");
+    run("print len(1);");
+    run("print 1;\nexit(3);\nprint 2;");
+}
+
+#[test]
+fn try_catch() {
+    let src = "fun fail(n) {\n  if (n == 0) return 1 ~/ 0;\n  return fail(n - 1) + 1;\n}\nlet x = 1;\ntry {\n  let y = 2;\n  print fail(3);\n} catch (e) {\n  print e, x;\n}\nfor (i in 0..3) {\n  try {\n    if (i == 1) continue;\n    assert(i != 2, \"two\");\n    print i;\n  } catch (e) {\n    print i, e;\n  }\n}\ntry {\n  null();\n} catch (e) {\n  print 1 + e;\n}";
+    run(src);
+    run("try {\n  throw {\"a\": [1]};\n} catch (e) {\n  print e;\n}\nthrow \"oops\";");
+    let src = "let l = [null];\nl[0] = l;\ntry {\n  throw l;\n} catch (e) {\n  print len(e);\n}\nthrow l;";
+    assert_eq!(
+        run(src),
+        "1\nThrown: Uncaught error: [[...]]\n[line 8] in script\n"
+    );
+}
+
+#[test]
+fn errors_are_traced_through_calls() {
+    // not a tail call, which would replace the frame of outer
+    let src =
+        "fun inner() {\n  return missing;\n}\nfun outer() {\n  return inner() + 1;\n}\nouter();";
+    run(src);
+    run("fun f(n) {\n  return f(n + 1) + 1;\n}\nf(0);");
+}
+
+#[test]
+fn set_precision() {
+    run("setPrecision(2);\nprint 1 / 3, [2 / 3], 4;\nsetPrecision(null);\nprint 1 / 3;");
+}
+
+#[test]
+fn script_return() {
+    run("fun f(x) {\n  return [x];\n}\nprint 1;\nif (true) {\n  let a = 2;\n  return f(a);\n}\nprint 2;");
+}